@@ -0,0 +1,100 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use rodio::{OutputStream, Sink, Source};
+
+use crate::interface_adapter::Tone;
+
+const SAMPLE_RATE: u32 = 44100;
+
+// square waves are loud, full volume stays well below clipping
+const MAX_AMPLITUDE: f32 = 0.25;
+
+// the tone is packed into one atomic so the audio thread never waits on the emulator
+fn pack(tone: Tone) -> u32 {
+    return (tone.freq as u32) | ((tone.gate as u32) << 16);
+}
+
+fn unpack(packed: u32) -> Tone {
+    return Tone { freq: (packed & 0xffff) as u16, gate: (packed >> 16) as u8 };
+}
+
+struct SquareWave {
+    tone:  Arc<AtomicU32>,
+    phase: f32
+}
+
+impl Iterator for SquareWave {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let tone = unpack(self.tone.load(Ordering::Relaxed));
+        if !tone.playing() {
+            self.phase = 0.0;
+            return Some(0.0);
+        }
+
+        self.phase = (self.phase + tone.freq as f32 / SAMPLE_RATE as f32).fract();
+
+        let amplitude = MAX_AMPLITUDE * tone.volume() as f32 / 15.0;
+        return Some(if self.phase < 0.5 { amplitude } else { -amplitude });
+    }
+}
+
+impl Source for SquareWave {
+    fn current_frame_len(&self) -> Option<usize> {
+        return None;
+    }
+
+    fn channels(&self) -> u16 {
+        return 1;
+    }
+
+    fn sample_rate(&self) -> u32 {
+        return SAMPLE_RATE;
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        return None;
+    }
+}
+
+// plays the adapter's tone generator on the default output device
+pub struct ToneOutput {
+    tone: Arc<AtomicU32>,
+
+    // dropping either stops playback
+    _sink:   Sink,
+    _stream: OutputStream
+}
+
+impl ToneOutput {
+    // without an output device the emulator just runs silently
+    pub fn open() -> Option<Self> {
+        let (stream, handle) = match OutputStream::try_default() {
+            Ok(output) => output,
+            Err(err) => {
+                println!("Couldn't open audio output: {}", err);
+                return None;
+            }
+        };
+
+        let sink = match Sink::try_new(&handle) {
+            Ok(sink) => sink,
+            Err(err) => {
+                println!("Couldn't open audio output: {}", err);
+                return None;
+            }
+        };
+
+        let tone = Arc::new(AtomicU32::new(0));
+        sink.append(SquareWave { tone: Arc::clone(&tone), phase: 0.0 });
+
+        return Some(ToneOutput { tone, _sink: sink, _stream: stream });
+    }
+
+    pub fn update(&self, tone: Tone) {
+        self.tone.store(pack(tone), Ordering::Relaxed);
+    }
+}
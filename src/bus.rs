@@ -0,0 +1,142 @@
+use crate::error::BusFault;
+#[cfg(test)]
+use crate::mapper::{BusAccess, BusEvent};
+#[cfg(test)]
+use std::cell::RefCell;
+
+// everything the CPU needs from the memory map, so it can run
+// against something other than the computer's own layout
+pub trait Bus {
+    fn read_byte(&self, address: u16) -> u8;
+
+    // a fault means the value was dropped, the CPU decides what to do about it
+    fn write_byte(&mut self, value: u8, address: u16) -> Result<(), BusFault>;
+
+    fn read_word(&self, address: u16) -> u16 {
+        return (self.read_byte(address) as u16) | ((self.read_byte(address.wrapping_add(1)) as u16) << 8);
+    }
+
+    // reads without side effects, for debugging views
+    fn peek_byte(&self, address: u16) -> u8 {
+        return self.read_byte(address);
+    }
+
+    fn irq_asserted(&self) -> bool {
+        return false;
+    }
+
+    // lets the bus know where the current instruction is, for diagnostics
+    fn set_cpu_pc(&mut self, _pc: u16) {}
+
+    // cycles the CPU was held up by the last instruction's accesses
+    fn take_stall_cycles(&mut self) -> u8 {
+        return 0;
+    }
+}
+
+// 64K of flat RAM and nothing else, for running the CPU in unit tests
+#[cfg(test)]
+pub struct FlatBus {
+    pub memory: [u8; 0x10000]
+}
+
+#[cfg(test)]
+impl FlatBus {
+    pub fn new() -> Self {
+        return FlatBus { memory: [0; 0x10000] };
+    }
+
+    // copies the program to `origin` and points the reset vector at it
+    pub fn with_program(origin: u16, program: &[u8]) -> Self {
+        let mut bus = FlatBus::new();
+        bus.memory[origin as usize .. origin as usize + program.len()].copy_from_slice(program);
+        bus.memory[0xfffc] = origin as u8;
+        bus.memory[0xfffd] = (origin >> 8) as u8;
+
+        return bus;
+    }
+}
+
+#[cfg(test)]
+impl Bus for FlatBus {
+    fn read_byte(&self, address: u16) -> u8 {
+        return self.memory[address as usize];
+    }
+
+    fn write_byte(&mut self, value: u8, address: u16) -> Result<(), BusFault> {
+        self.memory[address as usize] = value;
+        return Ok(());
+    }
+}
+
+// a flat bus that logs every read and write in order, peeks aren't logged.
+// for checking the exact access sequence of an instruction
+#[cfg(test)]
+pub struct RecordingBus {
+    pub inner: FlatBus,
+    events: RefCell<Vec<BusEvent>>
+}
+
+#[cfg(test)]
+impl RecordingBus {
+    pub fn new(inner: FlatBus) -> Self {
+        return RecordingBus { inner, events: RefCell::new(Vec::new()) };
+    }
+
+    pub fn take_events(&self) -> Vec<BusEvent> {
+        return self.events.take();
+    }
+}
+
+#[cfg(test)]
+impl Bus for RecordingBus {
+    fn read_byte(&self, address: u16) -> u8 {
+        let value = self.inner.read_byte(address);
+        self.events.borrow_mut().push(BusEvent { access: BusAccess::Read, address, value });
+        return value;
+    }
+
+    fn write_byte(&mut self, value: u8, address: u16) -> Result<(), BusFault> {
+        self.events.borrow_mut().push(BusEvent { access: BusAccess::Write, address, value });
+        return self.inner.write_byte(value, address);
+    }
+
+    fn peek_byte(&self, address: u16) -> u8 {
+        return self.inner.read_byte(address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flat_bus_reads_back_writes() {
+        let mut bus = FlatBus::new();
+        bus.write_byte(0x34, 0xfffe).unwrap();
+        bus.write_byte(0x12, 0xffff).unwrap();
+
+        assert_eq!(bus.read_word(0xfffe), 0x1234);
+    }
+
+    #[test]
+    fn with_program_sets_reset_vector() {
+        let bus = FlatBus::with_program(0x0200, &[0xa9, 0x42]);
+
+        assert_eq!(bus.read_word(0xfffc), 0x0200);
+        assert_eq!(bus.read_byte(0x0201), 0x42);
+    }
+
+    #[test]
+    fn recording_bus_logs_reads_and_writes_but_not_peeks() {
+        let mut bus = RecordingBus::new(FlatBus::new());
+        bus.write_byte(0x42, 0x0010).unwrap();
+        bus.peek_byte(0x0010);
+
+        assert_eq!(bus.read_byte(0x0010), 0x42);
+        assert_eq!(bus.take_events(), [
+            BusEvent { access: BusAccess::Write, address: 0x0010, value: 0x42 },
+            BusEvent { access: BusAccess::Read,  address: 0x0010, value: 0x42 }
+        ]);
+    }
+}
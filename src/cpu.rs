@@ -1,9 +1,13 @@
 #![allow(arithmetic_overflow)]
 
-use crate::mapper;
+use crate::bus::Bus;
+use crate::disasm;
+use crate::error::{BusFault, StepError};
 use crate::opcodes;
 
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Write;
 use std::rc::Rc;
 
 pub const CARRY_FLAG      : u8 = 1;
@@ -14,16 +18,57 @@ pub const BREAK_FLAG      : u8 = 16;
 pub const OVERFLOW_FLAG   : u8 = 64;
 pub const NEGATIVE_FLAG   : u8 = 128;
 
+const RESERVED_FLAG: u8 = 32;
+
 const INV_CARRY_FLAG      : u8 = !CARRY_FLAG;
 const INV_IRQ_DISABLE_FLAG: u8 = !IRQ_DISABLE_FLAG;
 const INV_DEC_MODE_FLAG   : u8 = !DEC_MODE_FLAG;
 const INV_OVERFLOW_FLAG   : u8 = !OVERFLOW_FLAG;
 
-const NMI_VECTOR      : u16 = 0xfffa;
-const INTERRUPT_VECTOR: u16 = 0xfffe;
-const RESET_VECTOR    : u16 = 0xfffc;
+const NMI_VECTOR          : u16 = 0xfffa;
+pub const INTERRUPT_VECTOR: u16 = 0xfffe;
+const RESET_VECTOR        : u16 = 0xfffc;
+
+// reset runs three dummy pushes with writes disabled, leaving SP at 0xfd
+const SP_START_POS: u8 = 0xfd;
+
+// pushing the return frame and fetching the vector
+pub const INTERRUPT_CYCLES: u8 = 7;
+
+#[allow(unused)]
+#[derive(Clone, Copy, Debug)]
+pub struct CpuState {
+    pub pc: u16,
+    pub sp: u8,
+
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+
+    pub flags: u8
+}
+
+// WAI sleeps until an interrupt is asserted, STP and the NMOS KIL opcodes until reset
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunState {
+    Running,
+    WaitingForInterrupt,
+    Stopped
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct BranchStats {
+    pub taken:     u64,
+    pub not_taken: u64
+}
+
+pub type InstructionCallback = Box<dyn FnMut(&CpuState)>;
 
-const SP_START_POS: u8 = 0xff;
+// gets a line per instruction, written before it runs
+pub type InstructionTrace = Box<dyn Write>;
+
+// handler for an opcode the core doesn't decode, PC already points past the opcode
+pub type CustomOpcode = fn(&mut CPU, &Rc<RefCell<dyn Bus>>);
 
 pub struct CPU {
     pub pc: u16,
@@ -35,26 +80,62 @@ pub struct CPU {
 
     flags: u8,
 
-    mapper: Rc<RefCell<mapper::Map>>
+    // total cycles run since the CPU was created
+    pub cycles: u64,
+
+    pub nmi_pending: bool,
+
+    pub run_state: RunState,
+
+    // per branch instruction address, only collected when enabled
+    pub branch_stats: Option<HashMap<u16, BranchStats>>,
+
+    page_crossed: bool,
+    extra_cycles: u8,
+
+    // invoked after every instruction with the resulting state
+    pub on_instruction: Option<InstructionCallback>,
+    pub trace: Option<InstructionTrace>,
+
+    // dropped writes end the step with an error instead of just being logged
+    pub trap_bus_faults: bool,
+    bus_fault: Option<BusFault>,
+
+    custom_opcodes: HashMap<u8, CustomOpcode>,
+
+    mapper: Rc<RefCell<dyn Bus>>
 }
 
 impl CPU {
-    pub fn new(mapper: Rc<RefCell<mapper::Map>>) -> Self {
+    pub fn new(mapper: Rc<RefCell<dyn Bus>>) -> Self {
         return CPU {
             pc: 0, sp: 0, a: 0, x: 0, y: 0, flags: 0, 
-            mapper 
+            cycles: 0, nmi_pending: false, run_state: RunState::Running, branch_stats: None, page_crossed: false, extra_cycles: 0,
+            on_instruction: None, trace: None, trap_bus_faults: false, bus_fault: None, custom_opcodes: HashMap::new(), mapper 
         }
     }
 
+    // only opcodes that aren't already implemented can be patched in
+    #[allow(unused)]
+    pub fn register_opcode(&mut self, opcode: u8, handler: CustomOpcode) {
+        self.custom_opcodes.insert(opcode, handler);
+    }
+
     pub fn reset(&mut self) {
         self.pc = (*self.mapper.borrow_mut()).read_word(RESET_VECTOR);
         self.sp = SP_START_POS;
+        self.run_state = RunState::Running;
 
         self.a = 0;
         self.x = 0;
         self.y = 0;
 
-        self.flags = 0b00110100;
+        // only I is forced by the hardware, decimal mode is cleared
+        // as on the 65C02 so programs start from a known state
+        self.flags = RESERVED_FLAG | IRQ_DISABLE_FLAG;
+
+        self.cycles      = 0;
+        self.nmi_pending = false;
     }
 
     fn set_flag_if(&mut self, cond: bool, flag: u8) {
@@ -69,6 +150,22 @@ impl CPU {
         return self.flags & flag != 0;
     }
 
+    // the reserved bit can't be changed from outside
+    pub fn set_flag(&mut self, flag: u8, value: bool) {
+        self.set_flag_if(value, flag & !RESERVED_FLAG);
+    }
+
+    #[allow(unused)]
+    pub fn get_flags(&self) -> u8 {
+        return self.flags;
+    }
+
+    // B doesn't exist in the register and the reserved bit is always set
+    #[allow(unused)]
+    pub fn set_flags(&mut self, flags: u8) {
+        self.flags = (flags & !BREAK_FLAG) | RESERVED_FLAG;
+    }
+
     fn fetch_word(&mut self) -> u16 {
         let mut val: u16 = (*self.mapper.borrow()).read_byte(self.pc) as u16;
         self.pc += 1;
@@ -84,81 +181,222 @@ impl CPU {
     }
 
     fn get_indirect_address_x(&mut self) -> u16 {
-        let addr = (*self.mapper.borrow()).read_word(((*self.mapper.borrow()).read_byte(self.pc) + self.x) as u16);
+        let ptr  = (*self.mapper.borrow()).read_byte(self.pc).wrapping_add(self.x);
+        let addr = self.read_zero_page_word(ptr);
         self.pc += 1;
 
         return addr;
     }
 
+    // pointers stored in the zero page wrap around within it,
+    // so a pointer at 0xff takes its high byte from 0x00
+    fn read_zero_page_word(&self, ptr: u8) -> u16 {
+        let lo = (*self.mapper.borrow()).read_byte(ptr as u16);
+        let hi = (*self.mapper.borrow()).read_byte(ptr.wrapping_add(1) as u16);
+
+        return (lo as u16) | ((hi as u16) << 8);
+    }
+
     fn get_indirect_address_y(&mut self) -> u16 {
-        let addr = (*self.mapper.borrow()).read_word(((*self.mapper.borrow()).read_byte(self.pc)) as u16) + self.y as u16;
+        let ptr  = (*self.mapper.borrow()).read_byte(self.pc);
+        let base = self.read_zero_page_word(ptr);
         self.pc += 1;
 
+        return self.index_address(base, self.y);
+    }
+
+    // crossing a page costs the 6502 a cycle to fix up the high byte,
+    // during which it reads from the address without the carry
+    fn index_address(&mut self, base: u16, index: u8) -> u16 {
+        let addr = base.wrapping_add(index as u16);
+        self.page_crossed = (base & 0xff00) != (addr & 0xff00);
+
+        if self.page_crossed {
+            (*self.mapper.borrow()).read_byte((base & 0xff00) | (addr & 0x00ff));
+        }
+
         return addr;
     }
 
+    fn get_absolute_address_indexed(&mut self, index: u8) -> u16 {
+        let base = self.fetch_word();
+        return self.index_address(base, index);
+    }
+
+    fn get_absolute_address_x(&mut self) -> u16 {
+        return self.get_absolute_address_indexed(self.x);
+    }
+
+    fn get_absolute_address_y(&mut self) -> u16 {
+        return self.get_absolute_address_indexed(self.y);
+    }
+
+    // a taken branch costs one extra cycle, two if it lands on another page
+    fn branch(&mut self, cond: bool) {
+        if let Some(stats) = self.branch_stats.as_mut() {
+            let site = stats.entry(self.pc - 1).or_default();
+            if cond {
+                site.taken += 1;
+            } else {
+                site.not_taken += 1;
+            }
+        }
+
+        let offset = (*self.mapper.borrow()).read_byte(self.pc) as i8;
+        self.pc += 1;
+
+        if cond {
+            let target = self.pc.wrapping_add(offset as u16);
+            self.extra_cycles += if (target & 0xff00) != (self.pc & 0xff00) { 2 } else { 1 };
+            self.pc = target;
+        }
+    }
+
     fn get_sp_addr(&self) -> u16 {
         return 0x100 | (self.sp as u16);
     }
 
+    fn store(&mut self, value: u8, address: u16) {
+        if let Err(fault) = (*self.mapper.borrow_mut()).write_byte(value, address) {
+            if self.trap_bus_faults {
+                // the first fault of the instruction is the one reported
+                self.bus_fault.get_or_insert(fault);
+            } else {
+                println!("{}", fault);
+            }
+        }
+    }
+
+    // like the real 6502, SP points to the next free slot:
+    // pushes write then decrement, pops increment then read
     fn push_byte(&mut self, value: u8) {
-        self.sp -= 1;
-        (*self.mapper.borrow_mut()).write_byte(value, self.get_sp_addr());
+        self.store(value, self.get_sp_addr());
+        self.sp = self.sp.wrapping_sub(1);
     }
 
     fn push_word(&mut self, value: u16) {
-        self.sp -= 2;
-        (*self.mapper.borrow_mut()).write_word(value, self.get_sp_addr());
+        self.push_byte((value >> 8) as u8);
+        self.push_byte((value & 0xff) as u8);
     }
 
     fn pop_byte(&mut self) -> u8 {
-        let value = (*self.mapper.borrow()).read_byte(self.get_sp_addr());
-        self.sp += 1;
-
-        return value;
+        self.sp = self.sp.wrapping_add(1);
+        return (*self.mapper.borrow()).read_byte(self.get_sp_addr());
     }
 
     fn pop_word(&mut self) -> u16 {
-        let value = (*self.mapper.borrow()).read_word(self.get_sp_addr()) as u16;
-        self.sp += 2;
+        let lo = self.pop_byte() as u16;
+        let hi = self.pop_byte() as u16;
 
-        return value;
+        return lo | (hi << 8);
     }
 
-    fn adc(&mut self, op: u8) {
-        let sign_eq = ((self.a ^ op) & NEGATIVE_FLAG) == 0;
-        let sum = (op as u16) + (self.a as u16) + (self.get_flag(CARRY_FLAG) as u16);
-        self.a = sum as u8;
+    // decodes the topmost interrupt frame on the stack,
+    // as pushed by an IRQ, NMI or BRK: (flags, return address)
+    pub fn interrupt_frame(&self) -> (u8, u16) {
+        let map = self.mapper.borrow();
+
+        let flags = map.peek_byte(0x100 | (self.sp.wrapping_add(1) as u16));
+        let lo    = map.peek_byte(0x100 | (self.sp.wrapping_add(2) as u16));
+        let hi    = map.peek_byte(0x100 | (self.sp.wrapping_add(3) as u16));
+
+        return (flags, (lo as u16) | ((hi as u16) << 8));
+    }
+
+    // V is set when both inputs have the same sign and the result doesn't
+    fn adc_binary(&mut self, op: u8) {
+        let sum    = (op as u16) + (self.a as u16) + (self.get_flag(CARRY_FLAG) as u16);
+        let result = sum as u8;
+
+        self.set_flag_if((self.a ^ result) & (op ^ result) & NEGATIVE_FLAG != 0, OVERFLOW_FLAG);
+        self.a = result;
 
         self.update_flags_registers(self.a);
         self.set_flag_if(sum > 0xff, CARRY_FLAG);
-        self.set_flag_if(
-            sign_eq && ((self.a ^ op) & NEGATIVE_FLAG) != 0,
-            OVERFLOW_FLAG
-        );
+    }
+
+    fn adc(&mut self, op: u8) {
+        if !self.get_flag(DEC_MODE_FLAG) {
+            self.adc_binary(op);
+            return;
+        }
+
+        // NMOS decimal mode, following the hardware algorithm so that
+        // invalid BCD nibbles (A-F) produce the same results as the chip.
+        // Z comes from the binary sum, N and V from the half-adjusted sum
+        let a     = self.a as i16;
+        let b     = op as i16;
+        let carry = self.get_flag(CARRY_FLAG) as i16;
+
+        let binary = (a + b + carry) as u8;
+
+        let mut low = (a & 0x0f) + (b & 0x0f) + carry;
+        if low >= 0x0a {
+            low = ((low + 0x06) & 0x0f) + 0x10;
+        }
+
+        let mut sum = (a & 0xf0) + (b & 0xf0) + low;
+        let signed  = ((a & 0xf0) as u8 as i8) as i16 + ((b & 0xf0) as u8 as i8) as i16 + low;
+
+        self.set_flag_if(binary == 0, ZERO_FLAG);
+        self.set_flag_if(sum & NEGATIVE_FLAG as i16 != 0, NEGATIVE_FLAG);
+        self.set_flag_if(signed < -128 || signed > 127, OVERFLOW_FLAG);
+
+        if sum >= 0xa0 {
+            sum += 0x60;
+        }
+
+        self.set_flag_if(sum >= 0x100, CARRY_FLAG);
+        self.a = sum as u8;
     }
 
     fn sbc(&mut self, op: u8) {
-        self.adc(!op);
+        if !self.get_flag(DEC_MODE_FLAG) {
+            self.adc_binary(!op);
+            return;
+        }
+
+        // on the NMOS 6502 all flags behave as in binary mode,
+        // only the accumulator gets the decimal correction
+        let a      = self.a as i16;
+        let b      = op as i16;
+        let borrow = 1 - self.get_flag(CARRY_FLAG) as i16;
+
+        self.adc_binary(!op);
+
+        let mut low = (a & 0x0f) - (b & 0x0f) - borrow;
+        if low < 0 {
+            low = ((low - 0x06) & 0x0f) - 0x10;
+        }
+
+        let mut diff = (a & 0xf0) - (b & 0xf0) + low;
+        if diff < 0 {
+            diff -= 0x60;
+        }
+
+        self.a = diff as u8;
     }
 
     fn asl(&mut self, op: u8) -> u8 {
         self.set_flag_if(op & NEGATIVE_FLAG != 0, CARRY_FLAG);
-        let result = op >> 1;
+        let result = op << 1;
         self.update_flags_registers(result);
         return result;
     }
 
+    // bit 0 goes to carry and bit 7 is always shifted in as 0, so N ends up clear
     fn lsr(&mut self, op: u8) -> u8 {
-        self.set_flag_if(op & CARRY_FLAG != 0, CARRY_FLAG);
+        self.set_flag_if(op & 0x01 != 0, CARRY_FLAG);
         let result = op >> 1;
-        self.update_flags_registers(result);
+        self.set_flag_if(result == 0, ZERO_FLAG);
+        self.flags &= !NEGATIVE_FLAG;
         return result;
     }
 
+    // the old carry is rotated in, the bit shifted out becomes the new one
     fn rol(&mut self, op: u8) -> u8 {
         let carry = self.get_flag(CARRY_FLAG) as u8;
-        self.set_flag_if(op & NEGATIVE_FLAG != 0, CARRY_FLAG);
+        self.set_flag_if(op & 0x80 != 0, CARRY_FLAG);
         let result = (op << 1) | carry;
         self.update_flags_registers(result);
         return result;
@@ -166,45 +404,136 @@ impl CPU {
 
     fn ror(&mut self, op: u8) -> u8 {
         let carry = (self.get_flag(CARRY_FLAG) as u8) << 7;
-        self.set_flag_if(op & CARRY_FLAG != 0, CARRY_FLAG);
+        self.set_flag_if(op & 0x01 != 0, CARRY_FLAG);
         let result = (op >> 1) | carry;
         self.update_flags_registers(result);
         return result;
     }
 
     fn cmp(&mut self, reg: u8, op: u8) {
-        self.update_flags_registers(reg - op);
+        self.update_flags_registers(reg.wrapping_sub(op));
         self.set_flag_if(reg >= op, CARRY_FLAG);
     }
 
-    fn push_flags(&mut self) {
-        // the 6502 always sets bits 4 and 5 high when 
-	    // pushing processor status...
-        self.push_byte(self.flags | 0b00011000);
+    fn push_flags(&mut self, brk: bool) {
+        // the 6502 always sets bit 5 high when pushing processor status,
+        // bit 4 (B) is only set when pushed by BRK or PHP and clear
+        // for IRQ and NMI, which is how handlers tell them apart...
+        let b = if brk { BREAK_FLAG } else { 0 };
+        self.push_byte((self.flags & !BREAK_FLAG) | RESERVED_FLAG | b);
     }
 
     fn pop_flags(&mut self) {
-        // ... those same flags get cleared on the way back
-        self.flags = self.pop_byte() & 0b11100111;
+        // ... B doesn't exist in the register itself, so it's dropped on the way back
+        self.flags = (self.pop_byte() & !BREAK_FLAG) | RESERVED_FLAG;
     }
 
+    // wakes a WAI even with interrupts disabled, execution then just carries on after it
     pub fn interrupt_request(&mut self) {
+        self.wake();
+
         if !self.get_flag(IRQ_DISABLE_FLAG) {
             self.push_word(self.pc);
-            self.push_flags();
+            self.push_flags(false);
             self.pc = (*self.mapper.borrow()).read_word(INTERRUPT_VECTOR);
             self.flags |= IRQ_DISABLE_FLAG;
         }
     }
 
-    #[allow(unused)]
-    pub fn non_maskable_interrupt(&mut self) {
+    // latched, the NMI is serviced before the next instruction
+    pub fn non_maskable_interrupt_request(&mut self) {
+        self.wake();
+        self.nmi_pending = true;
+    }
+
+    fn wake(&mut self) {
+        if self.run_state == RunState::WaitingForInterrupt {
+            self.run_state = RunState::Running;
+        }
+    }
+
+    fn non_maskable_interrupt(&mut self) {
         self.push_word(self.pc);
-        self.push_flags();
+        self.push_flags(false);
         self.pc = (*self.mapper.borrow()).read_word(NMI_VECTOR);
+        self.flags |= IRQ_DISABLE_FLAG;
+    }
+
+    fn interrupt_cycles(&mut self) -> u8 {
+        self.cycles += INTERRUPT_CYCLES as u64;
+        return INTERRUPT_CYCLES;
+    }
+
+    // same layout as the traces of most 6502 test harnesses:
+    // PC, instruction bytes, disassembly, registers, then the cycles run so far
+    fn trace_instruction(&mut self) {
+        let (text, len) = disasm::disassemble(&*self.mapper.borrow(), self.pc);
+        let bytes: Vec<String> = (0 .. len as u16)
+            .map(|i| format!("{:02X}", (*self.mapper.borrow()).peek_byte(self.pc.wrapping_add(i))))
+            .collect();
+
+        let line = format!(
+            "{:04X}  {:<8}  {:<16}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+            self.pc, bytes.join(" "), text, self.a, self.x, self.y, self.flags, self.sp, self.cycles
+        );
+
+        if let Some(trace) = self.trace.as_mut() {
+            if let Err(err) = writeln!(trace, "{}", line) {
+                println!("Couldn't write the instruction trace, it's turned off: {}", err);
+                self.trace = None;
+            }
+        }
     }
 
-    pub fn tick(&mut self) {
+    // steps until the predicate holds or the budget runs out, returning the steps taken
+    pub fn run_until(&mut self, predicate: impl Fn(&CPU) -> bool, max_steps: usize) -> Result<usize, StepError> {
+        let mut steps = 0;
+        while steps < max_steps && !predicate(self) {
+            self.tick()?;
+            steps += 1;
+        }
+
+        return Ok(steps);
+    }
+
+    // runs one instruction or interrupt sequence, returning how many cycles it took.
+    // on an illegal opcode PC is left pointing at it
+    pub fn tick(&mut self) -> Result<u8, StepError> {
+        match self.run_state {
+            RunState::Stopped => return Ok(0),
+            // the clock keeps running while waiting, so timers still count down
+            RunState::WaitingForInterrupt => {
+                if !self.nmi_pending && !(*self.mapper.borrow()).irq_asserted() {
+                    self.cycles += 1;
+                    return Ok(1);
+                }
+
+                self.run_state = RunState::Running;
+            }
+            RunState::Running => {}
+        }
+
+        self.page_crossed = false;
+        self.extra_cycles = 0;
+
+        // the interrupt sequence takes a step of its own, so PC is at the
+        // handler's first instruction afterwards. NMI wins over IRQ, and
+        // the IRQ line stays asserted until the program reads the interrupt id
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            self.non_maskable_interrupt();
+            return Ok(self.interrupt_cycles());
+        } else if (*self.mapper.borrow()).irq_asserted() && !self.get_flag(IRQ_DISABLE_FLAG) {
+            self.interrupt_request();
+            return Ok(self.interrupt_cycles());
+        }
+
+        (*self.mapper.borrow_mut()).set_cpu_pc(self.pc);
+
+        if self.trace.is_some() {
+            self.trace_instruction();
+        }
+
         let instruction = (*self.mapper.borrow()).read_byte(self.pc);
         self.pc += 1;
 
@@ -222,7 +551,7 @@ impl CPU {
                 self.update_flags_registers(self.a);
             }
             opcodes::LDA_ZERO_PAGE_X => {
-                self.a = (*self.mapper.borrow()).read_byte(((*self.mapper.borrow()).read_byte(self.pc) + self.x) as u16);
+                self.a = (*self.mapper.borrow()).read_byte((*self.mapper.borrow()).read_byte(self.pc).wrapping_add(self.x) as u16);
                 self.pc += 1;
 
                 self.update_flags_registers(self.a);
@@ -233,12 +562,12 @@ impl CPU {
                 self.update_flags_registers(self.a);
             }
             opcodes::LDA_ABSOLUTE_X => {
-                let addr = self.fetch_word() + self.x as u16;
+                let addr = self.get_absolute_address_x();
                 self.a = (*self.mapper.borrow()).read_byte(addr);
                 self.update_flags_registers(self.a);
             }
             opcodes::LDA_ABSOLUTE_Y => {
-                let addr = self.fetch_word() + self.y as u16;
+                let addr = self.get_absolute_address_y();
                 self.a = (*self.mapper.borrow()).read_byte(addr);
                 self.update_flags_registers(self.a);
             }
@@ -256,7 +585,8 @@ impl CPU {
 
             opcodes::LDX_IMMEDIATE => {
                 self.x = (*self.mapper.borrow()).read_byte(self.pc);
-                self.pc += 1
+                self.pc += 1;
+                self.update_flags_registers(self.x);
             }
             opcodes::LDX_ZERO_PAGE => {
                 self.x = (*self.mapper.borrow()).read_byte((*self.mapper.borrow()).read_byte(self.pc) as u16);
@@ -264,7 +594,7 @@ impl CPU {
                 self.update_flags_registers(self.x);
             }
             opcodes::LDX_ZERO_PAGE_Y => {
-                self.x = (*self.mapper.borrow()).read_byte((*self.mapper.borrow()).read_byte(self.pc) as u16 + self.y as u16);
+                self.x = (*self.mapper.borrow()).read_byte((*self.mapper.borrow()).read_byte(self.pc).wrapping_add(self.y) as u16);
                 self.pc += 1;
                 self.update_flags_registers(self.x);
             }
@@ -274,7 +604,7 @@ impl CPU {
                 self.update_flags_registers(self.x);
             }
             opcodes::LDX_ABSOLUTE_Y => {
-                let addr = self.fetch_word() + self.y as u16;
+                let addr = self.get_absolute_address_y();
                 self.x = (*self.mapper.borrow()).read_byte(addr);
                 self.update_flags_registers(self.x);
             }
@@ -283,6 +613,7 @@ impl CPU {
             opcodes::LDY_IMMEDIATE => {
                 self.y = (*self.mapper.borrow()).read_byte(self.pc);
                 self.pc += 1;
+                self.update_flags_registers(self.y);
             }
             opcodes::LDY_ZERO_PAGE => {
                 self.y = (*self.mapper.borrow()).read_byte((*self.mapper.borrow()).read_byte(self.pc) as u16);
@@ -290,7 +621,7 @@ impl CPU {
                 self.update_flags_registers(self.y);
             }
             opcodes::LDY_ZERO_PAGE_X => {
-                self.y = (*self.mapper.borrow()).read_byte((*self.mapper.borrow()).read_byte(self.pc) as u16 + self.y as u16);
+                self.y = (*self.mapper.borrow()).read_byte((*self.mapper.borrow()).read_byte(self.pc).wrapping_add(self.x) as u16);
                 self.pc += 1;
                 self.update_flags_registers(self.y);
             }
@@ -300,7 +631,7 @@ impl CPU {
                 self.update_flags_registers(self.y);
             }
             opcodes::LDY_ABSOLUTE_X => {
-                let addr = self.fetch_word() + self.x as u16;
+                let addr = self.get_absolute_address_x();
                 self.y = (*self.mapper.borrow()).read_byte(addr);
                 self.update_flags_registers(self.y);
             }
@@ -308,65 +639,85 @@ impl CPU {
 
             opcodes::STA_ZERO_PAGE => {
                 let addr = (*self.mapper.borrow()).read_byte(self.pc) as u16;
-                (*self.mapper.borrow_mut()).write_byte(self.a, addr);
+                self.store(self.a, addr);
                 self.pc += 1;
             }
             opcodes::STA_ZERO_PAGE_X => {
-                let addr = (*self.mapper.borrow()).read_byte(self.pc) as u16 + self.x as u16;
-                (*self.mapper.borrow_mut()).write_byte(self.a, addr);
+                let addr = (*self.mapper.borrow()).read_byte(self.pc).wrapping_add(self.x) as u16;
+                self.store(self.a, addr);
                 self.pc += 1;
             }
             opcodes::STA_ABSOLUTE => {
                 let addr = self.fetch_word();
-                (*self.mapper.borrow_mut()).write_byte(self.a, addr);
+                self.store(self.a, addr);
             }
             opcodes::STA_ABSOLUTE_X => {
-                let addr = self.fetch_word() + self.x as u16;
-                (*self.mapper.borrow_mut()).write_byte(self.a, addr);
+                let addr = self.get_absolute_address_x();
+                self.store(self.a, addr);
             }
             opcodes::STA_ABSOLUTE_Y => {
-                let addr = self.fetch_word() + self.y as u16;
-                (*self.mapper.borrow_mut()).write_byte(self.a, addr);
+                let addr = self.get_absolute_address_y();
+                self.store(self.a, addr);
             }
             opcodes::STA_INDIRECT_X => {
                 let addr = self.get_indirect_address_x();
-                (*self.mapper.borrow_mut()).write_byte(self.a, addr);
+                self.store(self.a, addr);
             }
             opcodes::STA_INDIRECT_Y => {
                 let addr = self.get_indirect_address_y();
-                (*self.mapper.borrow_mut()).write_byte(self.a, addr);
+                self.store(self.a, addr);
             }
 
 
             opcodes::STX_ZERO_PAGE => {
                 let addr = (*self.mapper.borrow()).read_byte(self.pc) as u16;
-                (*self.mapper.borrow_mut()).write_byte(self.x, addr);
+                self.store(self.x, addr);
                 self.pc += 1;
             }
             opcodes::STX_ZERO_PAGE_Y => {
-                let addr = (*self.mapper.borrow()).read_byte(self.pc) as u16 + self.y as u16;
-                (*self.mapper.borrow_mut()).write_byte(self.x, addr);
+                let addr = (*self.mapper.borrow()).read_byte(self.pc).wrapping_add(self.y) as u16;
+                self.store(self.x, addr);
                 self.pc += 1;
             }
             opcodes::STX_ABSOLUTE => {
                 let addr = self.fetch_word();
-                (*self.mapper.borrow_mut()).write_byte(self.x, addr);
+                self.store(self.x, addr);
             }
 
 
             opcodes::STY_ZERO_PAGE => {
                 let addr = (*self.mapper.borrow()).read_byte(self.pc) as u16;
-                (*self.mapper.borrow_mut()).write_byte(self.y, addr);
+                self.store(self.y, addr);
                 self.pc += 1;
             }
             opcodes::STY_ZERO_PAGE_X => {
-                let addr = (*self.mapper.borrow()).read_byte(self.pc) as u16 + self.x as u16;
-                (*self.mapper.borrow_mut()).write_byte(self.y, addr);
+                let addr = (*self.mapper.borrow()).read_byte(self.pc).wrapping_add(self.x) as u16;
+                self.store(self.y, addr);
                 self.pc += 1;
             }
             opcodes::STY_ABSOLUTE => {
                 let addr = self.fetch_word();
-                (*self.mapper.borrow_mut()).write_byte(self.y, addr);
+                self.store(self.y, addr);
+            }
+
+
+            opcodes::STZ_ZERO_PAGE => {
+                let addr = (*self.mapper.borrow()).read_byte(self.pc) as u16;
+                self.store(0, addr);
+                self.pc += 1;
+            }
+            opcodes::STZ_ZERO_PAGE_X => {
+                let addr = (*self.mapper.borrow()).read_byte(self.pc).wrapping_add(self.x) as u16;
+                self.store(0, addr);
+                self.pc += 1;
+            }
+            opcodes::STZ_ABSOLUTE => {
+                let addr = self.fetch_word();
+                self.store(0, addr);
+            }
+            opcodes::STZ_ABSOLUTE_X => {
+                let addr = self.get_absolute_address_x();
+                self.store(0, addr);
             }
 
             
@@ -380,11 +731,12 @@ impl CPU {
 
 
             opcodes::JSR => {
+                // the pushed address is the last byte of the operand, RTS adds one back
                 let addr = self.fetch_word();
-                self.push_word(self.pc);
+                self.push_word(self.pc.wrapping_sub(1));
                 self.pc = addr;
             }
-            opcodes::RTS => self.pc = self.pop_word(),
+            opcodes::RTS => self.pc = self.pop_word().wrapping_add(1),
 
 
             opcodes::TSX => {
@@ -414,25 +766,21 @@ impl CPU {
                 let result = self.x as u16 + 1;
                 self.x = result as u8;
                 self.update_flags_registers(self.x);
-                self.set_flag_if(result > 0xff, CARRY_FLAG);
             }
             opcodes::INY => {
                 let result = self.y as u16 + 1;
                 self.y = result as u8;
                 self.update_flags_registers(self.y);
-                self.set_flag_if(result > 0xff, CARRY_FLAG);
             }
             opcodes::DEX => {
                 let result = self.x as i16 - 1;
                 self.x = result as u8;
                 self.update_flags_registers(self.x);
-                self.set_flag_if(result < 0, CARRY_FLAG);
             }
             opcodes::DEY => {
                 let result = self.y as i16 - 1;
                 self.y = result as u8;
                 self.update_flags_registers(self.y);
-                self.set_flag_if(result < 0, CARRY_FLAG);
             }
 
 
@@ -441,72 +789,78 @@ impl CPU {
                 self.pc += 1;
 
                 let value = (*self.mapper.borrow()).read_byte(addr) as u16 + 1;
-                (*self.mapper.borrow_mut()).write_byte(value as u8, addr);
+                self.store(value as u8, addr);
                 self.update_flags_registers(value as u8);
-                self.set_flag_if(value > 0xff, CARRY_FLAG);
             }
             opcodes::INC_ZERO_PAGE_X => {
-                let addr = (*self.mapper.borrow()).read_byte(self.pc) as u16 + self.x as u16;
+                let addr = (*self.mapper.borrow()).read_byte(self.pc).wrapping_add(self.x) as u16;
                 self.pc += 1;
 
                 let value = (*self.mapper.borrow()).read_byte(addr) as u16 + 1;
-                (*self.mapper.borrow_mut()).write_byte(value as u8, addr);
+                self.store(value as u8, addr);
                 self.update_flags_registers(value as u8);
-                self.set_flag_if(value > 0xff, CARRY_FLAG);
             }
             opcodes::INC_ABSOLUTE => {
                 let addr = self.fetch_word();
                 let value = (*self.mapper.borrow()).read_byte(addr) as u16 + 1;
-                (*self.mapper.borrow_mut()).write_byte(value as u8, addr);
+                self.store(value as u8, addr);
                 self.update_flags_registers(value as u8);
-                self.set_flag_if(value > 0xff, CARRY_FLAG);
             }
             opcodes::INC_ABSOLUTE_X => {
-                let addr = self.fetch_word() + self.x as u16;
+                let addr = self.get_absolute_address_x();
                 let value = (*self.mapper.borrow()).read_byte(addr) as u16 + 1;
-                (*self.mapper.borrow_mut()).write_byte(value as u8, addr);
+                self.store(value as u8, addr);
                 self.update_flags_registers(value as u8);
-                self.set_flag_if(value > 0xff, CARRY_FLAG);
             }
             opcodes::DEC_ZERO_PAGE => {
                 let addr = (*self.mapper.borrow()).read_byte(self.pc) as u16;
                 self.pc += 1;
 
                 let value = (*self.mapper.borrow()).read_byte(addr) as i16 - 1;
-                (*self.mapper.borrow_mut()).write_byte(value as u8, addr);
+                self.store(value as u8, addr);
                 self.update_flags_registers(value as u8);
-                self.set_flag_if(value < 0, CARRY_FLAG);
             }
             opcodes::DEC_ZERO_PAGE_X => {
-                let addr = (*self.mapper.borrow()).read_byte(self.pc) as u16 + self.x as u16;
+                let addr = (*self.mapper.borrow()).read_byte(self.pc).wrapping_add(self.x) as u16;
                 self.pc += 1;
 
                 let value = (*self.mapper.borrow()).read_byte(addr) as i16 - 1;
-                (*self.mapper.borrow_mut()).write_byte(value as u8, addr);
+                self.store(value as u8, addr);
                 self.update_flags_registers(value as u8);
-                self.set_flag_if(value < 0, CARRY_FLAG);
             }
             opcodes::DEC_ABSOLUTE => {
                 let addr = self.fetch_word();
                 let value = (*self.mapper.borrow()).read_byte(addr) as i16 - 1;
-                (*self.mapper.borrow_mut()).write_byte(value as u8, addr);
+                self.store(value as u8, addr);
                 self.update_flags_registers(value as u8);
-                self.set_flag_if(value < 0, CARRY_FLAG);
             }
             opcodes::DEC_ABSOLUTE_X => {
-                let addr = self.fetch_word() + self.x as u16;
+                let addr = self.get_absolute_address_x();
                 let value = (*self.mapper.borrow()).read_byte(addr) as i16 - 1;
-                (*self.mapper.borrow_mut()).write_byte(value as u8, addr);
+                self.store(value as u8, addr);
                 self.update_flags_registers(value as u8);
-                self.set_flag_if(value < 0, CARRY_FLAG);
             }
 
 
             opcodes::PHA => self.push_byte(self.a),
-            opcodes::PHP => self.push_flags(),
-            opcodes::PLA => self.a = self.pop_byte(),
+            opcodes::PHP => self.push_flags(true),
+            opcodes::PLA => {
+                self.a = self.pop_byte();
+                self.update_flags_registers(self.a);
+            }
             opcodes::PLP => self.pop_flags(),
 
+            opcodes::PHX => self.push_byte(self.x),
+            opcodes::PHY => self.push_byte(self.y),
+            opcodes::PLX => {
+                self.x = self.pop_byte();
+                self.update_flags_registers(self.x);
+            }
+            opcodes::PLY => {
+                self.y = self.pop_byte();
+                self.update_flags_registers(self.y);
+            }
+
 
             opcodes::AND_IMMEDIATE => {
                 self.a  &= (*self.mapper.borrow()).read_byte(self.pc);
@@ -521,7 +875,7 @@ impl CPU {
                 self.update_flags_registers(self.a);
             }
             opcodes::AND_ZERO_PAGE_X => {
-                self.a &= (*self.mapper.borrow()).read_byte((*self.mapper.borrow()).read_byte(self.pc) as u16 + self.x as u16);
+                self.a &= (*self.mapper.borrow()).read_byte((*self.mapper.borrow()).read_byte(self.pc).wrapping_add(self.x) as u16);
                 self.pc += 1;
 
                 self.update_flags_registers(self.a);
@@ -532,12 +886,12 @@ impl CPU {
                 self.update_flags_registers(self.a);
             }
             opcodes::AND_ABSOLUTE_X => {
-                let addr = self.fetch_word() + self.x as u16;
+                let addr = self.get_absolute_address_x();
                 self.a &= (*self.mapper.borrow()).read_byte(addr);
                 self.update_flags_registers(self.a);
             }
             opcodes::AND_ABSOLUTE_Y => {
-                let addr = self.fetch_word() + self.y as u16;
+                let addr = self.get_absolute_address_y();
                 self.a &= (*self.mapper.borrow()).read_byte(addr);
                 self.update_flags_registers(self.a);
             }
@@ -566,7 +920,7 @@ impl CPU {
                 self.update_flags_registers(self.a);
             }
             opcodes::ORA_ZERO_PAGE_X => {
-                self.a |= (*self.mapper.borrow()).read_byte((*self.mapper.borrow()).read_byte(self.pc) as u16 + self.x as u16);
+                self.a |= (*self.mapper.borrow()).read_byte((*self.mapper.borrow()).read_byte(self.pc).wrapping_add(self.x) as u16);
                 self.pc += 1;
 
                 self.update_flags_registers(self.a);
@@ -577,12 +931,12 @@ impl CPU {
                 self.update_flags_registers(self.a);
             }
             opcodes::ORA_ABSOLUTE_X => {
-                let addr = self.fetch_word() + self.x as u16;
+                let addr = self.get_absolute_address_x();
                 self.a |= (*self.mapper.borrow()).read_byte(addr);
                 self.update_flags_registers(self.a);
             }
             opcodes::ORA_ABSOLUTE_Y => {
-                let addr = self.fetch_word() + self.y as u16;
+                let addr = self.get_absolute_address_y();
                 self.a |= (*self.mapper.borrow()).read_byte(addr);
                 self.update_flags_registers(self.a);
             }
@@ -611,7 +965,7 @@ impl CPU {
                 self.update_flags_registers(self.a);
             }
             opcodes::EOR_ZERO_PAGE_X => {
-                self.a ^= (*self.mapper.borrow()).read_byte((*self.mapper.borrow()).read_byte(self.pc) as u16 + self.x as u16);
+                self.a ^= (*self.mapper.borrow()).read_byte((*self.mapper.borrow()).read_byte(self.pc).wrapping_add(self.x) as u16);
                 self.pc += 1;
 
                 self.update_flags_registers(self.a);
@@ -622,12 +976,12 @@ impl CPU {
                 self.update_flags_registers(self.a);
             }
             opcodes::EOR_ABSOLUTE_X => {
-                let addr = self.fetch_word() + self.x as u16;
+                let addr = self.get_absolute_address_x();
                 self.a ^= (*self.mapper.borrow()).read_byte(addr);
                 self.update_flags_registers(self.a);
             }
             opcodes::EOR_ABSOLUTE_Y => {
-                let addr = self.fetch_word() + self.y as u16;
+                let addr = self.get_absolute_address_y();
                 self.a ^= (*self.mapper.borrow()).read_byte(addr);
                 self.update_flags_registers(self.a);
             }
@@ -654,7 +1008,6 @@ impl CPU {
             opcodes::BIT_ABSOLUTE => {
                 let addr = self.fetch_word();
                 let value = (*self.mapper.borrow()).read_byte(addr);
-                self.pc += 1;
 
                 self.set_flag_if(self.a & value == 0, ZERO_FLAG);
                 self.set_flag_if(value & OVERFLOW_FLAG != 0, OVERFLOW_FLAG);
@@ -662,62 +1015,15 @@ impl CPU {
             }
 
 
-            opcodes::BEQ => {
-                if self.get_flag(ZERO_FLAG) {
-                    self.pc = (self.pc as i64 + 1 + ((*self.mapper.borrow()).read_byte(self.pc) as i8) as i64) as u16;
-                } else {
-                    self.pc += 1;
-                }
-            }
-            opcodes::BNE => {
-                if self.get_flag(ZERO_FLAG) {
-                    self.pc += 1;
-                } else {
-                    self.pc = (self.pc as i64 + 1 + ((*self.mapper.borrow()).read_byte(self.pc) as i8) as i64) as u16;
-                }
-            }
-            opcodes::BCS => {
-                if self.get_flag(CARRY_FLAG) {
-                    self.pc = (self.pc as i64 + 1 + ((*self.mapper.borrow()).read_byte(self.pc) as i8) as i64) as u16;
-                } else {
-                    self.pc += 1;
-                }
-            }
-            opcodes::BCC => {
-                if self.get_flag(CARRY_FLAG) {
-                    self.pc += 1;
-                } else {
-                    self.pc = (self.pc as i64 + 1 + ((*self.mapper.borrow()).read_byte(self.pc) as i8) as i64) as u16;
-                }
-            }
-            opcodes::BMI => {
-                if self.get_flag(NEGATIVE_FLAG) {
-                    self.pc = (self.pc as i64 + 1 +  ((*self.mapper.borrow()).read_byte(self.pc) as i8) as i64) as u16;
-                } else {
-                    self.pc += 1;
-                }
-            }
-            opcodes::BPL => {
-                if self.get_flag(NEGATIVE_FLAG) {
-                    self.pc += 1;
-                } else {
-                    self.pc = (self.pc as i64 + 1 + ((*self.mapper.borrow()).read_byte(self.pc) as i8) as i64) as u16;
-                }
-            }
-            opcodes::BVS => {
-                if self.get_flag(OVERFLOW_FLAG) {
-                    self.pc = (self.pc as i64 + 1 + ((*self.mapper.borrow()).read_byte(self.pc) as i8) as i64) as u16;
-                } else {
-                    self.pc += 1;
-                }
-            }
-            opcodes::BVC => {
-                if self.get_flag(OVERFLOW_FLAG) {
-                    self.pc += 1;
-                } else {
-                    self.pc = (self.pc as i64 + 1 + ((*self.mapper.borrow()).read_byte(self.pc) as i8) as i64) as u16;
-                }
-            }
+            opcodes::BEQ => self.branch(self.get_flag(ZERO_FLAG)),
+            opcodes::BNE => self.branch(!self.get_flag(ZERO_FLAG)),
+            opcodes::BCS => self.branch(self.get_flag(CARRY_FLAG)),
+            opcodes::BCC => self.branch(!self.get_flag(CARRY_FLAG)),
+            opcodes::BMI => self.branch(self.get_flag(NEGATIVE_FLAG)),
+            opcodes::BPL => self.branch(!self.get_flag(NEGATIVE_FLAG)),
+            opcodes::BVS => self.branch(self.get_flag(OVERFLOW_FLAG)),
+            opcodes::BVC => self.branch(!self.get_flag(OVERFLOW_FLAG)),
+            opcodes::BRA => self.branch(true),
 
 
             opcodes::CLC => self.flags &= INV_CARRY_FLAG,
@@ -743,7 +1049,7 @@ impl CPU {
             }
             opcodes::ADC_ZERO_PAGE_X => {
                 let value = (*self.mapper.borrow()).read_byte(
-                    (*self.mapper.borrow()).read_byte(self.pc) as u16 + self.x as u16
+                    (*self.mapper.borrow()).read_byte(self.pc).wrapping_add(self.x) as u16
                 );
                 self.adc(value);
                 self.pc += 1;
@@ -754,12 +1060,12 @@ impl CPU {
                 self.adc(value);
             }
             opcodes::ADC_ABSOLUTE_X => {
-                let addr = self.fetch_word() + self.x as u16;
+                let addr = self.get_absolute_address_x();
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 self.adc(value);
             }
             opcodes::ADC_ABSOLUTE_Y => {
-                let addr = self.fetch_word() + self.y as u16;
+                let addr = self.get_absolute_address_y();
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 self.adc(value);
             }
@@ -789,7 +1095,7 @@ impl CPU {
             }
             opcodes::SBC_ZERO_PAGE_X => {
                 let value = (*self.mapper.borrow()).read_byte(
-                    (*self.mapper.borrow()).read_byte(self.pc) as u16 + self.x as u16
+                    (*self.mapper.borrow()).read_byte(self.pc).wrapping_add(self.x) as u16
                 );
                 self.sbc(value);
                 self.pc += 1;
@@ -800,12 +1106,12 @@ impl CPU {
                 self.sbc(value);
             }
             opcodes::SBC_ABSOLUTE_X => {
-                let addr = self.fetch_word() + self.x as u16;
+                let addr = self.get_absolute_address_x();
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 self.sbc(value);
             }
             opcodes::SBC_ABSOLUTE_Y => {
-                let addr = self.fetch_word() + self.y as u16;
+                let addr = self.get_absolute_address_y();
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 self.sbc(value);
             }
@@ -834,8 +1140,8 @@ impl CPU {
                 self.pc += 1;
             }
             opcodes::CMP_ZERO_PAGE_X => {
-                let value = (*self.mapper.borrow()).read_byte((
-                    *self.mapper.borrow()).read_byte(self.pc) as u16 + self.x as u16
+                let value = (*self.mapper.borrow()).read_byte(
+                    (*self.mapper.borrow()).read_byte(self.pc).wrapping_add(self.x) as u16
                 );
                 self.cmp(self.a, value);
                 self.pc += 1;
@@ -846,12 +1152,12 @@ impl CPU {
                 self.cmp(self.a, value);
             }
             opcodes::CMP_ABSOLUTE_X => {
-                let addr = self.fetch_word() + self.x as u16;
+                let addr = self.get_absolute_address_x();
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 self.cmp(self.a, value);
             }
             opcodes::CMP_ABSOLUTE_Y => {
-                let addr = self.fetch_word() + self.y as u16;
+                let addr = self.get_absolute_address_y();
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 self.cmp(self.a, value);
             }
@@ -911,26 +1217,26 @@ impl CPU {
                 self.pc += 1;
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 let result = self.asl(value);
-                (*self.mapper.borrow_mut()).write_byte(result, addr);
+                self.store(result, addr);
             }
             opcodes::ASL_ZERO_PAGE_X => {
-                let addr = (*self.mapper.borrow()).read_byte(self.pc) as u16 + self.x as u16;
+                let addr = (*self.mapper.borrow()).read_byte(self.pc).wrapping_add(self.x) as u16;
                 self.pc += 1;
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 let result = self.asl(value);
-                (*self.mapper.borrow_mut()).write_byte(result, addr);
+                self.store(result, addr);
             }
             opcodes::ASL_ABSOLUTE => {
                 let addr = self.fetch_word();
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 let result = self.asl(value);
-                (*self.mapper.borrow_mut()).write_byte(result, addr);
+                self.store(result, addr);
             }
             opcodes::ASL_ABSOLUTE_X => {
-                let addr = self.fetch_word() + self.x as u16;
+                let addr = self.get_absolute_address_x();
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 let result = self.asl(value);
-                (*self.mapper.borrow_mut()).write_byte(result, addr);
+                self.store(result, addr);
             }
 
 
@@ -940,26 +1246,26 @@ impl CPU {
                 self.pc += 1;
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 let result = self.lsr(value);
-                (*self.mapper.borrow_mut()).write_byte(result, addr);
+                self.store(result, addr);
             }
             opcodes::LSR_ZERO_PAGE_X => {
-                let addr = (*self.mapper.borrow()).read_byte(self.pc) as u16 + self.x as u16;
+                let addr = (*self.mapper.borrow()).read_byte(self.pc).wrapping_add(self.x) as u16;
                 self.pc += 1;
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 let result = self.lsr(value);
-                (*self.mapper.borrow_mut()).write_byte(result, addr);
+                self.store(result, addr);
             }
             opcodes::LSR_ABSOLUTE => {
                 let addr = self.fetch_word();
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 let result = self.lsr(value);
-                (*self.mapper.borrow_mut()).write_byte(result, addr);
+                self.store(result, addr);
             }
             opcodes::LSR_ABSOLUTE_X => {
-                let addr = self.fetch_word() + self.x as u16;
+                let addr = self.get_absolute_address_x();
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 let result = self.lsr(value);
-                (*self.mapper.borrow_mut()).write_byte(result, addr);
+                self.store(result, addr);
             }
 
 
@@ -969,26 +1275,26 @@ impl CPU {
                 self.pc += 1;
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 let result = self.rol(value);
-                (*self.mapper.borrow_mut()).write_byte(result, addr);
+                self.store(result, addr);
             }
             opcodes::ROL_ZERO_PAGE_X => {
-                let addr = (*self.mapper.borrow()).read_byte(self.pc) as u16 + self.x as u16;
+                let addr = (*self.mapper.borrow()).read_byte(self.pc).wrapping_add(self.x) as u16;
                 self.pc += 1;
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 let result = self.rol(value);
-                (*self.mapper.borrow_mut()).write_byte(result, addr);
+                self.store(result, addr);
             }
             opcodes::ROL_ABSOLUTE => {
                 let addr = self.fetch_word();
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 let result = self.rol(value);
-                (*self.mapper.borrow_mut()).write_byte(result, addr);
+                self.store(result, addr);
             }
             opcodes::ROL_ABSOLUTE_X => {
-                let addr = self.fetch_word() + self.x as u16;
+                let addr = self.get_absolute_address_x();
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 let result = self.rol(value);
-                (*self.mapper.borrow_mut()).write_byte(result, addr);
+                self.store(result, addr);
             }
 
 
@@ -998,38 +1304,36 @@ impl CPU {
                 self.pc += 1;
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 let result = self.ror(value);
-                (*self.mapper.borrow_mut()).write_byte(result, addr);
+                self.store(result, addr);
             }
             opcodes::ROR_ZERO_PAGE_X => {
-                let addr = (*self.mapper.borrow()).read_byte(self.pc) as u16 + self.x as u16;
+                let addr = (*self.mapper.borrow()).read_byte(self.pc).wrapping_add(self.x) as u16;
                 self.pc += 1;
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 let result = self.ror(value);
-                (*self.mapper.borrow_mut()).write_byte(result, addr);
+                self.store(result, addr);
             }
             opcodes::ROR_ABSOLUTE => {
                 let addr = self.fetch_word();
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 let result = self.ror(value);
-                (*self.mapper.borrow_mut()).write_byte(result, addr);
+                self.store(result, addr);
             }
             opcodes::ROR_ABSOLUTE_X => {
-                let addr = self.fetch_word() + self.x as u16;
+                let addr = self.get_absolute_address_x();
                 let value = (*self.mapper.borrow()).read_byte(addr);
                 let result = self.ror(value);
-                (*self.mapper.borrow_mut()).write_byte(result, addr);
+                self.store(result, addr);
             }
 
 
             opcodes::BRK => {
-                if !self.get_flag(IRQ_DISABLE_FLAG) {
-                    // the 6502 skips 1 byte ahead when using brk 
-				    // for some reason, so we imitate that behaviour
-                    self.push_word(self.pc + 1);
-                    self.push_flags();
-                    self.pc = (*self.mapper.borrow()).read_word(INTERRUPT_VECTOR);
-                    self.flags |= IRQ_DISABLE_FLAG | BREAK_FLAG;
-                }
+                // BRK isn't masked by I. the 6502 skips 1 byte ahead
+                // when using brk for some reason, so we imitate that behaviour
+                self.push_word(self.pc + 1);
+                self.push_flags(true);
+                self.pc = (*self.mapper.borrow()).read_word(INTERRUPT_VECTOR);
+                self.flags |= IRQ_DISABLE_FLAG;
             }
             opcodes::RTI => {
                 self.pop_flags();
@@ -1038,7 +1342,843 @@ impl CPU {
 
 
             opcodes::NOP => {},
-            _ => println!("Invalid instruction: {:02X}", instruction)
+            opcodes::WAI => self.run_state = RunState::WaitingForInterrupt,
+            opcodes::STP => self.run_state = RunState::Stopped,
+            op if opcodes::is_jam(op) => {
+                println!("CPU jammed by opcode {:02X} at {:04X}", op, self.pc - 1);
+                self.pc -= 1;
+                self.run_state = RunState::Stopped;
+            }
+
+            _ => match self.custom_opcodes.get(&instruction).copied() {
+                Some(handler) => {
+                    let mapper = Rc::clone(&self.mapper);
+                    handler(self, &mapper);
+                }
+                None => {
+                    self.pc -= 1;
+                    return Err(StepError::IllegalOpcode(instruction, self.pc));
+                }
+            }
+        }
+
+        if self.page_crossed && opcodes::page_penalty(instruction) {
+            self.extra_cycles += 1;
+        }
+
+        let stall  = (*self.mapper.borrow_mut()).take_stall_cycles();
+        let cycles = opcodes::CYCLES[instruction as usize] + self.extra_cycles + stall;
+        self.cycles += cycles as u64;
+
+        if let Some(callback) = self.on_instruction.as_mut() {
+            callback(&CpuState {
+                pc: self.pc, sp: self.sp, a: self.a, x: self.x, y: self.y, flags: self.flags
+            });
+        }
+
+        if let Some(fault) = self.bus_fault.take() {
+            return Err(StepError::BusFault(fault, cycles));
+        }
+
+        return Ok(cycles);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{FlatBus, RecordingBus};
+    use crate::mapper::{BusAccess, BusEvent};
+
+    const ORIGIN: u16 = 0x0200;
+
+    // a CPU reset into `program` at ORIGIN on a flat RAM bus
+    fn setup(program: &[u8]) -> (CPU, Rc<RefCell<FlatBus>>) {
+        let bus = Rc::new(RefCell::new(FlatBus::with_program(ORIGIN, program)));
+        let mut cpu = CPU::new(bus.clone());
+        cpu.reset();
+
+        return (cpu, bus);
+    }
+
+    fn run(cpu: &mut CPU, instructions: usize) {
+        for _ in 0 .. instructions {
+            cpu.tick().unwrap();
+        }
+    }
+
+    #[test]
+    fn runs_on_a_flat_bus() {
+        // LDA #$42; STA $1234
+        let (mut cpu, bus) = setup(&[0xa9, 0x42, 0x8d, 0x34, 0x12]);
+        run(&mut cpu, 2);
+
+        assert_eq!(cpu.pc, ORIGIN + 5);
+        assert_eq!(bus.borrow().memory[0x1234], 0x42);
+    }
+
+    #[test]
+    fn pushed_b_flag_tells_brk_from_irq() {
+        // CLI; NOP, with the handler at $0300
+        let (mut cpu, bus) = setup(&[0x58, 0xea]);
+        bus.borrow_mut().memory[0xfffe] = 0x00;
+        bus.borrow_mut().memory[0xffff] = 0x03;
+        run(&mut cpu, 1);
+
+        cpu.interrupt_request();
+        let (irq_flags, irq_return) = cpu.interrupt_frame();
+        assert_eq!(irq_flags & BREAK_FLAG, 0);
+        assert_eq!(irq_return, ORIGIN + 1);
+
+        // SEI; BRK, which still vectors with I set
+        let (mut cpu, bus) = setup(&[0x78, 0x00]);
+        bus.borrow_mut().memory[0xfffe] = 0x00;
+        bus.borrow_mut().memory[0xffff] = 0x03;
+        run(&mut cpu, 2);
+
+        let (brk_flags, brk_return) = cpu.interrupt_frame();
+        assert_ne!(brk_flags & BREAK_FLAG, 0);
+        assert_eq!(brk_return, ORIGIN + 3);
+        assert_eq!(cpu.pc, 0x0300);
+        assert!(!cpu.get_flag(BREAK_FLAG));
+    }
+
+    fn read(address: u16, value: u8) -> BusEvent {
+        return BusEvent { access: BusAccess::Read, address, value };
+    }
+
+    #[test]
+    fn indexed_read_across_a_page_does_a_dummy_read() {
+        // LDX #$D0; LDA $1234,X
+        let mut flat = FlatBus::with_program(ORIGIN, &[0xa2, 0xd0, 0xbd, 0x34, 0x12]);
+        flat.memory[0x1204] = 0x11;
+        flat.memory[0x1304] = 0x22;
+
+        let bus = Rc::new(RefCell::new(RecordingBus::new(flat)));
+        let mut cpu = CPU::new(bus.clone());
+        cpu.reset();
+        cpu.tick().unwrap();
+        bus.borrow().take_events();
+
+        assert_eq!(cpu.tick().unwrap(), 5);
+        assert_eq!(cpu.a, 0x22);
+        assert_eq!(bus.borrow().take_events(), [
+            read(ORIGIN + 2, 0xbd), read(ORIGIN + 3, 0x34), read(ORIGIN + 4, 0x12),
+            read(0x1204, 0x11), read(0x1304, 0x22)
+        ]);
+    }
+
+    #[test]
+    fn indexed_read_within_a_page_reads_once() {
+        // LDX #$01; LDA $1234,X
+        let bus = Rc::new(RefCell::new(RecordingBus::new(FlatBus::with_program(ORIGIN, &[0xa2, 0x01, 0xbd, 0x34, 0x12]))));
+        let mut cpu = CPU::new(bus.clone());
+        cpu.reset();
+        cpu.tick().unwrap();
+        bus.borrow().take_events();
+
+        assert_eq!(cpu.tick().unwrap(), 4);
+        assert_eq!(bus.borrow().take_events(), [
+            read(ORIGIN + 2, 0xbd), read(ORIGIN + 3, 0x34), read(ORIGIN + 4, 0x12), read(0x1235, 0x00)
+        ]);
+    }
+
+    // SED, sets the carry, then LDA #a; ADC #b (or SBC #b), giving (A, carry)
+    fn decimal(a: u8, b: u8, carry: bool, subtract: bool) -> (u8, bool) {
+        let op = if subtract { 0xe9 } else { 0x69 };
+        let (mut cpu, _) = setup(&[0xf8, if carry { 0x38 } else { 0x18 }, 0xa9, a, op, b]);
+        run(&mut cpu, 4);
+
+        return (cpu.a, cpu.get_flag(CARRY_FLAG));
+    }
+
+    #[test]
+    fn decimal_adc_of_invalid_bcd_nibbles() {
+        assert_eq!(decimal(0x0a, 0x01, false, false), (0x11, false));
+        assert_eq!(decimal(0x0f, 0x0f, false, false), (0x14, false));
+        assert_eq!(decimal(0x9a, 0x00, false, false), (0x00, true));
+        assert_eq!(decimal(0xff, 0xff, true, false), (0x55, true));
+    }
+
+    #[test]
+    fn decimal_sbc_of_invalid_bcd_nibbles() {
+        assert_eq!(decimal(0x0a, 0x01, true, true), (0x09, true));
+        assert_eq!(decimal(0x00, 0x0f, true, true), (0x9b, false));
+    }
+
+    #[test]
+    fn interrupt_frame_reads_back_the_irq_push() {
+        // CLI; SEC; NOP
+        let (mut cpu, _bus) = setup(&[0x58, 0x38, 0xea]);
+        run(&mut cpu, 2);
+
+        cpu.interrupt_request();
+        let (flags, return_address) = cpu.interrupt_frame();
+
+        assert_eq!(cpu.sp, SP_START_POS.wrapping_sub(3));
+        assert_eq!(flags, RESERVED_FLAG | CARRY_FLAG);
+        assert_eq!(return_address, ORIGIN + 2);
+    }
+
+
+    #[test]
+    fn asl_shifts_left_into_the_carry() {
+        // LDA #$81; ASL A
+        let (mut cpu, _) = setup(&[0xa9, 0x81, 0x0a]);
+        run(&mut cpu, 2);
+
+        assert_eq!(cpu.a, 0x02);
+        assert!(cpu.get_flag(CARRY_FLAG));
+
+        // LDA #$40; ASL $10
+        let (mut cpu, bus) = setup(&[0xa9, 0x40, 0x85, 0x10, 0x06, 0x10]);
+        run(&mut cpu, 3);
+
+        assert_eq!(bus.borrow().memory[0x10], 0x80);
+        assert!(!cpu.get_flag(CARRY_FLAG));
+        assert!(cpu.get_flag(NEGATIVE_FLAG));
+    }
+
+
+    #[test]
+    fn instruction_callback_fires_once_per_instruction() {
+        // LDA #$01; NOP; JMP $0200
+        let (mut cpu, _) = setup(&[0xa9, 0x01, 0xea, 0x4c, 0x00, 0x02]);
+        let seen = Rc::new(RefCell::new(Vec::new()));
+
+        let log = seen.clone();
+        cpu.on_instruction = Some(Box::new(move |state: &CpuState| log.borrow_mut().push(state.pc)));
+        run(&mut cpu, 4);
+
+        assert_eq!(*seen.borrow(), [ORIGIN + 2, ORIGIN + 3, ORIGIN, ORIGIN + 2]);
+    }
+
+
+    #[test]
+    fn ldx_and_ldy_immediate_set_the_flags() {
+        // LDX #$00
+        let (mut cpu, _) = setup(&[0xa2, 0x00]);
+        run(&mut cpu, 1);
+        assert!(cpu.get_flag(ZERO_FLAG));
+        assert!(!cpu.get_flag(NEGATIVE_FLAG));
+
+        // LDY #$80
+        let (mut cpu, _) = setup(&[0xa0, 0x80]);
+        run(&mut cpu, 1);
+        assert!(!cpu.get_flag(ZERO_FLAG));
+        assert!(cpu.get_flag(NEGATIVE_FLAG));
+    }
+
+
+    #[test]
+    fn indirect_y_pointer_at_ff_wraps_in_the_zero_page() {
+        // LDY #$10; LDA ($FF),Y; LDY #$D0; LDA ($FF),Y
+        let (mut cpu, bus) = setup(&[0xa0, 0x10, 0xb1, 0xff, 0xa0, 0xd0, 0xb1, 0xff]);
+        {
+            let mut bus = bus.borrow_mut();
+            bus.memory[0x00ff] = 0x34;
+            bus.memory[0x0000] = 0x12;
+            bus.memory[0x0100] = 0x56;
+            bus.memory[0x1244] = 0x42;
+            bus.memory[0x1304] = 0x43;
+        }
+
+        run(&mut cpu, 1);
+        assert_eq!(cpu.tick().unwrap(), 5);
+        assert_eq!(cpu.a, 0x42);
+
+        // crossing into the next page costs a cycle
+        run(&mut cpu, 1);
+        assert_eq!(cpu.tick().unwrap(), 6);
+        assert_eq!(cpu.a, 0x43);
+    }
+
+
+    #[test]
+    fn ldy_and_sty_zero_page_x_index_with_x() {
+        // LDX #$04; LDY #$07; LDY $10,X; STY $20,X
+        let (mut cpu, bus) = setup(&[0xa2, 0x04, 0xa0, 0x07, 0xb4, 0x10, 0x94, 0x20]);
+        bus.borrow_mut().memory[0x14] = 0x5a;
+        bus.borrow_mut().memory[0x17] = 0xee;
+        run(&mut cpu, 4);
+
+        assert_eq!(cpu.y, 0x5a);
+        assert_eq!(bus.borrow().memory[0x24], 0x5a);
+    }
+
+
+    fn swap_a_x(cpu: &mut CPU, _bus: &Rc<RefCell<dyn Bus>>) {
+        (cpu.a, cpu.x) = (cpu.x, cpu.a);
+    }
+
+    #[test]
+    fn registered_opcode_runs_its_handler() {
+        // LDA #$11; LDX #$22; custom $FF; NOP
+        let (mut cpu, _) = setup(&[0xa9, 0x11, 0xa2, 0x22, 0xff, 0xea]);
+        cpu.register_opcode(0xff, swap_a_x);
+        run(&mut cpu, 3);
+
+        assert_eq!((cpu.a, cpu.x), (0x22, 0x11));
+        assert_eq!(cpu.pc, ORIGIN + 5);
+    }
+
+
+    #[test]
+    fn decimal_adc_carries_between_digits() {
+        assert_eq!(decimal(0x09, 0x01, false, false), (0x10, false));
+        assert_eq!(decimal(0x99, 0x01, false, false), (0x00, true));
+        assert_eq!(decimal(0x10, 0x01, true, true), (0x09, true));
+    }
+
+
+    #[test]
+    fn set_flag_only_touches_that_flag() {
+        let (mut cpu, _) = setup(&[]);
+        let before = cpu.get_flags();
+
+        cpu.set_flag(CARRY_FLAG, !cpu.get_flag(CARRY_FLAG));
+        assert_eq!(cpu.get_flags() ^ before, CARRY_FLAG);
+
+        // the reserved bit stays set
+        cpu.set_flag(RESERVED_FLAG, false);
+        assert_eq!(cpu.get_flags() ^ before, CARRY_FLAG);
+    }
+
+
+    // the flags after LDA #a; CMP #op
+    fn compare(a: u8, op: u8) -> (bool, bool, bool) {
+        let (mut cpu, _) = setup(&[0xa9, a, 0xc9, op]);
+        run(&mut cpu, 2);
+
+        return (cpu.get_flag(CARRY_FLAG), cpu.get_flag(ZERO_FLAG), cpu.get_flag(NEGATIVE_FLAG));
+    }
+
+    #[test]
+    fn cmp_sets_carry_when_the_register_is_larger() {
+        assert_eq!(compare(0x10, 0x20), (false, false, true));
+        assert_eq!(compare(0x20, 0x10), (true, false, false));
+        assert_eq!(compare(0x20, 0x20), (true, true, false));
+    }
+
+
+    #[test]
+    fn memory_inc_and_dec_leave_the_carry_alone() {
+        // SEC; LDA #$FF; STA $10; INC $10
+        let (mut cpu, bus) = setup(&[0x38, 0xa9, 0xff, 0x85, 0x10, 0xe6, 0x10]);
+        run(&mut cpu, 4);
+
+        assert_eq!(bus.borrow().memory[0x10], 0x00);
+        assert!(cpu.get_flag(ZERO_FLAG));
+        assert!(cpu.get_flag(CARRY_FLAG));
+
+        // CLC; DEC $10
+        let (mut cpu, bus) = setup(&[0x18, 0xc6, 0x10]);
+        run(&mut cpu, 2);
+
+        assert_eq!(bus.borrow().memory[0x10], 0xff);
+        assert!(cpu.get_flag(NEGATIVE_FLAG));
+        assert!(!cpu.get_flag(CARRY_FLAG));
+    }
+
+    #[test]
+    fn register_inc_and_dec_leave_the_carry_alone() {
+        // SEC; LDX #$FE; INX
+        let (mut cpu, _) = setup(&[0x38, 0xa2, 0xfe, 0xe8]);
+        run(&mut cpu, 3);
+
+        assert_eq!(cpu.x, 0xff);
+        assert!(cpu.get_flag(CARRY_FLAG));
+
+        // CLC; LDX #$FF; INX
+        let (mut cpu, _) = setup(&[0x18, 0xa2, 0xff, 0xe8]);
+        run(&mut cpu, 3);
+
+        assert_eq!(cpu.x, 0x00);
+        assert!(cpu.get_flag(ZERO_FLAG));
+        assert!(!cpu.get_flag(CARRY_FLAG));
+
+        // SEC; LDX #$01; DEX
+        let (mut cpu, _) = setup(&[0x38, 0xa2, 0x01, 0xca]);
+        run(&mut cpu, 3);
+
+        assert_eq!(cpu.x, 0x00);
+        assert!(cpu.get_flag(CARRY_FLAG));
+
+        // CLC; LDX #$00; DEX
+        let (mut cpu, _) = setup(&[0x18, 0xa2, 0x00, 0xca]);
+        run(&mut cpu, 3);
+
+        assert_eq!(cpu.x, 0xff);
+        assert!(cpu.get_flag(NEGATIVE_FLAG));
+        assert!(!cpu.get_flag(CARRY_FLAG));
+    }
+
+
+    #[test]
+    fn zero_page_indexing_wraps_within_the_page() {
+        // LDX #$02; LDA $FF,X; STA $FE,X; LDA ($FF,X)
+        let (mut cpu, bus) = setup(&[0xa2, 0x02, 0xb5, 0xff, 0x95, 0xfe, 0xa1, 0xff]);
+        {
+            let mut bus = bus.borrow_mut();
+            bus.memory[0x0001] = 0x12;
+            bus.memory[0x0101] = 0x99;
+        }
+        run(&mut cpu, 3);
+
+        assert_eq!(cpu.a, 0x12);
+        assert_eq!(bus.borrow().memory[0x0000], 0x12);
+        assert_eq!(bus.borrow().memory[0x0100], 0x00);
+
+        // $FF + X is the pointer at $01
+        bus.borrow_mut().memory[0x0002] = 0x30;
+        bus.borrow_mut().memory[0x3012] = 0x77;
+        run(&mut cpu, 1);
+        assert_eq!(cpu.a, 0x77);
+    }
+
+
+    #[test]
+    fn indirect_y_store_wraps_the_pointer_and_crosses_a_page() {
+        // LDY #$FF; LDA #$42; STA ($FF),Y
+        let (mut cpu, bus) = setup(&[0xa0, 0xff, 0xa9, 0x42, 0x91, 0xff]);
+        bus.borrow_mut().memory[0x00ff] = 0x01;
+        bus.borrow_mut().memory[0x0000] = 0x20;
+        run(&mut cpu, 2);
+
+        // stores always take the extra cycle
+        assert_eq!(cpu.tick().unwrap(), 6);
+        assert_eq!(bus.borrow().memory[0x2100], 0x42);
+    }
+
+
+    #[test]
+    fn pushed_word_pops_back() {
+        let (mut cpu, bus) = setup(&[]);
+        cpu.push_word(0x1234);
+
+        assert_eq!(cpu.sp, SP_START_POS.wrapping_sub(2));
+        assert_eq!(bus.borrow().memory[0x0100 | SP_START_POS as usize], 0x12);
+        assert_eq!(cpu.pop_word(), 0x1234);
+        assert_eq!(cpu.sp, SP_START_POS);
+    }
+
+    #[test]
+    fn jsr_and_rts_leave_the_stack_balanced() {
+        // JSR $0300; LDA #$01, with RTS at $0300
+        let (mut cpu, bus) = setup(&[0x20, 0x00, 0x03, 0xa9, 0x01]);
+        bus.borrow_mut().memory[0x0300] = 0x60;
+        run(&mut cpu, 3);
+
+        assert_eq!(cpu.a, 0x01);
+        assert_eq!(cpu.sp, SP_START_POS);
+    }
+
+
+    #[test]
+    fn rts_resumes_after_the_jsr() {
+        // JSR $0300; NOP, with RTS at $0300
+        let (mut cpu, bus) = setup(&[0x20, 0x00, 0x03, 0xea]);
+        bus.borrow_mut().memory[0x0300] = 0x60;
+
+        run(&mut cpu, 1);
+        assert_eq!(cpu.pc, 0x0300);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.pc, ORIGIN + 3);
+    }
+
+
+    #[test]
+    fn bit_absolute_then_nop_keeps_the_pc_in_step() {
+        // LDA #$01; BIT $1234; NOP
+        let (mut cpu, bus) = setup(&[0xa9, 0x01, 0x2c, 0x34, 0x12, 0xea]);
+        bus.borrow_mut().memory[0x1234] = 0xc0;
+        run(&mut cpu, 2);
+
+        assert_eq!(cpu.pc, ORIGIN + 5);
+        assert!(cpu.get_flag(ZERO_FLAG));
+        assert!(cpu.get_flag(OVERFLOW_FLAG));
+        assert!(cpu.get_flag(NEGATIVE_FLAG));
+
+        run(&mut cpu, 1);
+        assert_eq!(cpu.pc, ORIGIN + 6);
+    }
+
+
+    #[test]
+    fn cycle_costs() {
+        // (program, setup instructions, cycles of the one after them)
+        let cases: [(&[u8], usize, u8); 4] = [
+            // LDA #$01
+            (&[0xa9, 0x01], 0, 2),
+            // LDX #$01; LDA $1234,X
+            (&[0xa2, 0x01, 0xbd, 0x34, 0x12], 1, 4),
+            // LDX #$D0; LDA $1234,X, across a page
+            (&[0xa2, 0xd0, 0xbd, 0x34, 0x12], 1, 5),
+            // SEC; BCS +0, taken
+            (&[0x38, 0xb0, 0x00], 1, 3)
+        ];
+
+        for (program, prelude, cycles) in cases {
+            let (mut cpu, _) = setup(program);
+            run(&mut cpu, prelude);
+
+            let before = cpu.cycles;
+            assert_eq!(cpu.tick().unwrap(), cycles, "{:02X?}", program);
+            assert_eq!(cpu.cycles - before, cycles as u64);
+        }
+    }
+
+
+    #[test]
+    fn reset_leaves_sp_at_fd() {
+        let (cpu, _) = setup(&[]);
+        assert_eq!(cpu.sp, 0xfd);
+    }
+
+
+    #[test]
+    fn nmi_jumps_through_its_vector() {
+        // SEI; NOP, with the handler at $0400
+        let (mut cpu, bus) = setup(&[0x78, 0xea]);
+        bus.borrow_mut().memory[0xfffa] = 0x00;
+        bus.borrow_mut().memory[0xfffb] = 0x04;
+        run(&mut cpu, 1);
+
+        // masking IRQs doesn't hold it off
+        cpu.non_maskable_interrupt_request();
+        run(&mut cpu, 1);
+
+        assert_eq!(cpu.pc, 0x0400);
+        assert!(cpu.get_flag(IRQ_DISABLE_FLAG));
+
+        let (flags, return_address) = cpu.interrupt_frame();
+        assert_eq!(flags & BREAK_FLAG, 0);
+        assert_eq!(return_address, ORIGIN + 1);
+    }
+
+
+    // the status byte a handler at $0300 pulls off the stack: PLA; STA $10
+    fn pulled_status(program: &[u8], irq: bool) -> u8 {
+        let (mut cpu, bus) = setup(program);
+        {
+            let mut bus = bus.borrow_mut();
+            bus.memory[0x0300 .. 0x0303].copy_from_slice(&[0x68, 0x85, 0x10]);
+            bus.memory[0xfffe] = 0x00;
+            bus.memory[0xffff] = 0x03;
+        }
+
+        run(&mut cpu, program.len());
+        if irq {
+            cpu.interrupt_request();
+        }
+        run(&mut cpu, 2);
+
+        return bus.borrow().memory[0x10];
+    }
+
+    #[test]
+    fn handler_reads_b_only_after_brk() {
+        // CLI; NOP, then the IRQ
+        assert_eq!(pulled_status(&[0x58, 0xea], true) & BREAK_FLAG, 0);
+        // SEI; BRK
+        assert_ne!(pulled_status(&[0x78, 0x00], false) & BREAK_FLAG, 0);
+    }
+
+
+    #[test]
+    fn jsr_stacks_the_return_address_minus_one() {
+        // JSR $0300, with RTS at $0300
+        let (mut cpu, bus) = setup(&[0x20, 0x00, 0x03]);
+        bus.borrow_mut().memory[0x0300] = 0x60;
+
+        assert_eq!(cpu.tick().unwrap(), 6);
+        {
+            let bus = bus.borrow();
+            let stacked = (bus.memory[0x0100 | (cpu.sp as usize + 1)] as u16) | ((bus.memory[0x0100 | (cpu.sp as usize + 2)] as u16) << 8);
+            assert_eq!(stacked, ORIGIN + 2);
         }
+
+        assert_eq!(cpu.tick().unwrap(), 6);
+        assert_eq!(cpu.pc, ORIGIN + 3);
+    }
+
+    #[test]
+    fn rti_costs_six_cycles() {
+        // SEI; BRK, with RTI at $0300
+        let (mut cpu, bus) = setup(&[0x78, 0x00]);
+        bus.borrow_mut().memory[0x0300] = 0x40;
+        bus.borrow_mut().memory[0xfffe] = 0x00;
+        bus.borrow_mut().memory[0xffff] = 0x03;
+        run(&mut cpu, 2);
+
+        assert_eq!(cpu.tick().unwrap(), 6);
+        assert_eq!(cpu.pc, ORIGIN + 3);
+    }
+
+
+    #[test]
+    fn kil_jams_until_reset() {
+        // INX; KIL; INX
+        let (mut cpu, _) = setup(&[0xe8, 0x02, 0xe8]);
+        run(&mut cpu, 5);
+
+        assert_eq!(cpu.run_state, RunState::Stopped);
+        assert_eq!(cpu.x, 1);
+        assert_eq!(cpu.pc, ORIGIN + 1);
+        assert_eq!(cpu.tick().unwrap(), 0);
+
+        cpu.reset();
+        assert_eq!(cpu.run_state, RunState::Running);
     }
-}
\ No newline at end of file
+
+
+    #[test]
+    fn run_until_sums_one_to_ten() {
+        // LDA #$00; LDX #$0A; loop: STX $10; CLC; ADC $10; DEX; BNE loop; STP
+        let (mut cpu, _) = setup(&[0xa9, 0x00, 0xa2, 0x0a, 0x86, 0x10, 0x18, 0x65, 0x10, 0xca, 0xd0, 0xf8, 0xdb]);
+
+        let steps = cpu.run_until(|cpu| cpu.run_state == RunState::Stopped, 1000).unwrap();
+
+        assert_eq!(cpu.a, 55);
+        assert_eq!(steps, 2 + 10 * 5 + 1);
+
+        // a budget that runs out leaves it mid program
+        let (mut cpu, _) = setup(&[0xa9, 0x00, 0xa2, 0x0a, 0x86, 0x10, 0x18, 0x65, 0x10, 0xca, 0xd0, 0xf8, 0xdb]);
+        assert_eq!(cpu.run_until(|cpu| cpu.run_state == RunState::Stopped, 10).unwrap(), 10);
+    }
+
+
+    #[test]
+    fn bra_skips_over_a_brk() {
+        // BRA +1; BRK; LDA #$42
+        let (mut cpu, _) = setup(&[0x80, 0x01, 0x00, 0xa9, 0x42]);
+
+        assert_eq!(cpu.tick().unwrap(), 3);
+        assert_eq!(cpu.pc, ORIGIN + 3);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.a, 0x42);
+        assert_eq!(cpu.sp, SP_START_POS);
+    }
+
+
+    #[test]
+    fn stz_clears_memory_and_nothing_else() {
+        // LDA #$AA; LDX #$01; STZ $2000; STZ $1FFF,X; STZ $10; STZ $0F,X
+        let (mut cpu, bus) = setup(&[0xa9, 0xaa, 0xa2, 0x01, 0x9c, 0x00, 0x20, 0x9e, 0xff, 0x1f, 0x64, 0x10, 0x74, 0x0f]);
+        {
+            let mut bus = bus.borrow_mut();
+            bus.memory[0x2000] = 0xaa;
+            bus.memory[0x10]   = 0xaa;
+        }
+        run(&mut cpu, 3);
+        assert_eq!(bus.borrow().memory[0x2000], 0x00);
+
+        bus.borrow_mut().memory[0x2000] = 0xaa;
+        run(&mut cpu, 1);
+        assert_eq!(bus.borrow().memory[0x2000], 0x00);
+
+        bus.borrow_mut().memory[0x10] = 0xaa;
+        run(&mut cpu, 1);
+        assert_eq!(bus.borrow().memory[0x10], 0x00);
+
+        bus.borrow_mut().memory[0x10] = 0xaa;
+        run(&mut cpu, 1);
+        assert_eq!(bus.borrow().memory[0x10], 0x00);
+
+        assert_eq!(cpu.a, 0xaa);
+        assert!(!cpu.get_flag(ZERO_FLAG));
+    }
+
+
+    #[test]
+    fn loop_branch_counts_its_iterations() {
+        // LDX #$05; loop: DEX; BNE loop; NOP
+        let (mut cpu, _) = setup(&[0xa2, 0x05, 0xca, 0xd0, 0xfd, 0xea]);
+        cpu.branch_stats = Some(HashMap::new());
+        run(&mut cpu, 1 + 5 * 2);
+
+        let stats = cpu.branch_stats.as_ref().unwrap()[&(ORIGIN + 3)];
+        assert_eq!((stats.taken, stats.not_taken), (4, 1));
+    }
+
+
+    #[test]
+    fn phx_and_plx_round_trip() {
+        // LDX #$42; PHX; LDX #$00; PLX; LDY #$80; PHY; LDY #$01; PLY
+        let (mut cpu, _) = setup(&[0xa2, 0x42, 0xda, 0xa2, 0x00, 0xfa, 0xa0, 0x80, 0x5a, 0xa0, 0x01, 0x7a]);
+
+        run(&mut cpu, 4);
+        assert_eq!(cpu.x, 0x42);
+        assert!(!cpu.get_flag(ZERO_FLAG));
+        assert!(!cpu.get_flag(NEGATIVE_FLAG));
+
+        run(&mut cpu, 4);
+        assert_eq!(cpu.y, 0x80);
+        assert!(cpu.get_flag(NEGATIVE_FLAG));
+        assert_eq!(cpu.sp, SP_START_POS);
+    }
+
+
+    #[test]
+    fn illegal_opcode_is_an_error_carrying_the_pc() {
+        assert!(!opcodes::is_jam(0xff));
+
+        // NOP; $FF
+        let (mut cpu, _) = setup(&[0xea, 0xff]);
+        assert_eq!(cpu.tick().unwrap(), 2);
+
+        match cpu.tick() {
+            Err(StepError::IllegalOpcode(opcode, pc)) => assert_eq!((opcode, pc), (0xff, ORIGIN + 1)),
+            other => panic!("expected an illegal opcode, got {:?}", other.map_err(|err| err.to_string()))
+        }
+        assert_eq!(cpu.pc, ORIGIN + 1);
+    }
+
+
+    #[test]
+    fn reset_clears_decimal_and_reloads_the_vector() {
+        // SED; NOP
+        let (mut cpu, bus) = setup(&[0xf8, 0xea]);
+        run(&mut cpu, 2);
+        cpu.non_maskable_interrupt_request();
+        assert!(cpu.get_flag(DEC_MODE_FLAG));
+
+        bus.borrow_mut().memory[0xfffc] = 0x34;
+        bus.borrow_mut().memory[0xfffd] = 0x12;
+        cpu.reset();
+
+        assert!(!cpu.get_flag(DEC_MODE_FLAG));
+        assert!(cpu.get_flag(IRQ_DISABLE_FLAG));
+        assert_eq!(cpu.pc, 0x1234);
+        assert_eq!(cpu.cycles, 0);
+        assert!(!cpu.nmi_pending);
+    }
+
+
+    #[test]
+    fn wai_sleeps_until_an_interrupt() {
+        // CLI; WAI; INX, with the handler at $0300
+        let (mut cpu, bus) = setup(&[0x58, 0xcb, 0xe8]);
+        bus.borrow_mut().memory[0xfffe] = 0x00;
+        bus.borrow_mut().memory[0xffff] = 0x03;
+        run(&mut cpu, 2);
+
+        assert_eq!(cpu.run_state, RunState::WaitingForInterrupt);
+        assert_eq!(cpu.tick().unwrap(), 1);
+        assert_eq!(cpu.pc, ORIGIN + 2);
+
+        cpu.interrupt_request();
+        assert_eq!(cpu.run_state, RunState::Running);
+        assert_eq!(cpu.pc, 0x0300);
+    }
+
+    #[test]
+    fn wai_with_interrupts_disabled_resumes_after_it() {
+        // SEI; WAI; INX
+        let (mut cpu, _) = setup(&[0x78, 0xcb, 0xe8]);
+        run(&mut cpu, 2);
+
+        cpu.interrupt_request();
+        run(&mut cpu, 1);
+        assert_eq!(cpu.x, 1);
+    }
+
+    #[test]
+    fn stp_stops_until_reset() {
+        // STP; INX
+        let (mut cpu, _) = setup(&[0xdb, 0xe8]);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.run_state, RunState::Stopped);
+
+        // interrupts don't wake it
+        cpu.interrupt_request();
+        cpu.non_maskable_interrupt_request();
+        assert_eq!(cpu.tick().unwrap(), 0);
+        assert_eq!(cpu.x, 0);
+
+        cpu.reset();
+        assert_eq!(cpu.run_state, RunState::Running);
+        assert_eq!(cpu.pc, ORIGIN);
+    }
+
+
+    // (result, V, C) of CLC; LDA #a; ADC #op
+    fn binary_adc(a: u8, op: u8) -> (u8, bool, bool) {
+        let (mut cpu, _) = setup(&[0x18, 0xa9, a, 0x69, op]);
+        run(&mut cpu, 3);
+
+        return (cpu.a, cpu.get_flag(OVERFLOW_FLAG), cpu.get_flag(CARRY_FLAG));
+    }
+
+    #[test]
+    fn adc_overflow_needs_same_sign_inputs() {
+        assert_eq!(binary_adc(0x50, 0x50), (0xa0, true, false));
+        assert_eq!(binary_adc(0xd0, 0x90), (0x60, true, true));
+        assert_eq!(binary_adc(0x50, 0xd0), (0x20, false, true));
+        assert_eq!(binary_adc(0x50, 0x10), (0x60, false, false));
+    }
+
+
+    #[test]
+    fn lsr_never_sets_negative() {
+        // LDA #$FF; LSR A
+        let (mut cpu, _) = setup(&[0xa9, 0xff, 0x4a]);
+        run(&mut cpu, 2);
+
+        assert_eq!(cpu.a, 0x7f);
+        assert!(cpu.get_flag(CARRY_FLAG));
+        assert!(!cpu.get_flag(NEGATIVE_FLAG));
+    }
+
+
+    // (result, carry) of setting the carry, loading `value` and rotating it
+    fn rotate(opcode: u8, value: u8, carry: bool) -> (u8, bool) {
+        let (mut cpu, _) = setup(&[if carry { 0x38 } else { 0x18 }, 0xa9, value, opcode]);
+        run(&mut cpu, 3);
+
+        return (cpu.a, cpu.get_flag(CARRY_FLAG));
+    }
+
+    #[test]
+    fn rotates_shift_the_old_carry_in() {
+        const ROL: u8 = 0x2a;
+        const ROR: u8 = 0x6a;
+
+        assert_eq!(rotate(ROR, 0x01, false), (0x00, true));
+        assert_eq!(rotate(ROR, 0x01, true),  (0x80, true));
+        assert_eq!(rotate(ROL, 0x80, false), (0x00, true));
+        assert_eq!(rotate(ROL, 0x80, true),  (0x01, true));
+        assert_eq!(rotate(ROL, 0x40, false), (0x80, false));
+    }
+
+
+    // a trace writer whose output the test can still read
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            return Ok(bytes.len());
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    #[test]
+    fn trace_lines_match_the_harness_format() {
+        // LDA #$42; STA $10; NOP
+        let (mut cpu, _) = setup(&[0xa9, 0x42, 0x85, 0x10, 0xea]);
+        let output = Rc::new(RefCell::new(Vec::new()));
+        cpu.trace = Some(Box::new(SharedBuffer(output.clone())));
+        run(&mut cpu, 3);
+
+        let text = String::from_utf8(output.borrow().clone()).unwrap();
+        let lines: Vec<String> = text.lines().map(str::to_string).collect();
+        assert_eq!(lines, [
+            "0200  A9 42     LDA #$42          A:00 X:00 Y:00 P:24 SP:FD CYC:0",
+            "0202  85 10     STA $10           A:42 X:00 Y:00 P:24 SP:FD CYC:2",
+            "0204  EA        NOP               A:42 X:00 Y:00 P:24 SP:FD CYC:5"
+        ]);
+    }
+}
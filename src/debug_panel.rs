@@ -0,0 +1,67 @@
+use crate::ppu;
+
+// rows taken by the panel, counted from the bottom of the window
+const PANEL_ROWS: u16 = 18;
+const MARGIN:     u8  = 4;
+
+pub const MEMORY_ROWS: u8 = 7;
+
+// the label shown under the next instruction is cut to this many characters
+pub const LABEL_WIDTH: usize = 40;
+
+// cell positions of every panel item, in text grid coordinates
+#[derive(Clone, Copy)]
+pub struct DebugPanel {
+    pub clock:   (u8, u8),
+    pub regs:    (u8, u8),
+    pub ports:   (u8, u8),
+    pub adapter: (u8, u8),
+    pub flags:   (u8, u8),
+    pub flags2:  (u8, u8),
+    pub ret:     (u8, u8),
+    pub disasm:  (u8, u8),
+    pub memory:  (u8, u8)
+}
+
+impl DebugPanel {
+    // the panel is anchored to the bottom of the screen, the flag
+    // columns to its right edge, everything else to the left margin
+    pub fn layout(ppu: &ppu::PPU) -> Self {
+        return DebugPanel::layout_for((ppu.width() / ppu::CHAR_X) as u8, (ppu.height() / ppu.glyph_height()) as u8);
+    }
+
+    // for a screen of `cols` by `rows` text cells
+    pub fn layout_for(cols: u8, rows: u8) -> Self {
+        let top = rows.saturating_sub(PANEL_ROWS as u8);
+        let right = cols.saturating_sub(20);
+
+        return DebugPanel {
+            clock:   (MARGIN,      top),
+            regs:    (MARGIN,      top + 2),
+            ports:   (MARGIN + 9,  top + 2),
+            adapter: (MARGIN + 21, top + 2),
+            flags:   (right,       top + 2),
+            flags2:  (right + 8,   top + 2),
+            ret:     (right,       top + 6),
+            disasm:  (MARGIN,      top + 7),
+            memory:  (MARGIN,      top + 9)
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_follows_the_bottom_of_the_screen() {
+        assert_eq!(DebugPanel::layout_for(64, 54).clock, (MARGIN, 36));
+        assert_eq!(DebugPanel::layout_for(80, 60).clock, (MARGIN, 42));
+    }
+
+    #[test]
+    fn flags_follow_the_right_edge() {
+        assert_eq!(DebugPanel::layout_for(64, 54).flags, (44, 38));
+        assert_eq!(DebugPanel::layout_for(80, 60).flags, (60, 44));
+    }
+}
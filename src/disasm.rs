@@ -0,0 +1,267 @@
+use crate::bus::Bus;
+use crate::opcodes;
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+    Relative
+}
+
+impl Mode {
+    fn operand_len(self) -> u8 {
+        return match self {
+            Mode::Implied | Mode::Accumulator => 0,
+            Mode::Absolute | Mode::AbsoluteX | Mode::AbsoluteY | Mode::Indirect => 2,
+            _ => 1
+        };
+    }
+}
+
+fn decode(opcode: u8) -> Option<(&'static str, Mode)> {
+    return Some(match opcode {
+        opcodes::NOP             => ("NOP", Mode::Implied),
+        opcodes::LDA_IMMEDIATE   => ("LDA", Mode::Immediate),
+        opcodes::LDA_ZERO_PAGE   => ("LDA", Mode::ZeroPage),
+        opcodes::LDA_ZERO_PAGE_X => ("LDA", Mode::ZeroPageX),
+        opcodes::LDA_ABSOLUTE    => ("LDA", Mode::Absolute),
+        opcodes::LDA_ABSOLUTE_X  => ("LDA", Mode::AbsoluteX),
+        opcodes::LDA_ABSOLUTE_Y  => ("LDA", Mode::AbsoluteY),
+        opcodes::LDA_INDIRECT_X  => ("LDA", Mode::IndirectX),
+        opcodes::LDA_INDIRECT_Y  => ("LDA", Mode::IndirectY),
+        opcodes::LDX_IMMEDIATE   => ("LDX", Mode::Immediate),
+        opcodes::LDX_ZERO_PAGE   => ("LDX", Mode::ZeroPage),
+        opcodes::LDX_ZERO_PAGE_Y => ("LDX", Mode::ZeroPageY),
+        opcodes::LDX_ABSOLUTE    => ("LDX", Mode::Absolute),
+        opcodes::LDX_ABSOLUTE_Y  => ("LDX", Mode::AbsoluteY),
+        opcodes::LDY_IMMEDIATE   => ("LDY", Mode::Immediate),
+        opcodes::LDY_ZERO_PAGE   => ("LDY", Mode::ZeroPage),
+        opcodes::LDY_ZERO_PAGE_X => ("LDY", Mode::ZeroPageX),
+        opcodes::LDY_ABSOLUTE    => ("LDY", Mode::Absolute),
+        opcodes::LDY_ABSOLUTE_X  => ("LDY", Mode::AbsoluteX),
+        opcodes::STA_ZERO_PAGE   => ("STA", Mode::ZeroPage),
+        opcodes::STA_ZERO_PAGE_X => ("STA", Mode::ZeroPageX),
+        opcodes::STA_ABSOLUTE    => ("STA", Mode::Absolute),
+        opcodes::STA_ABSOLUTE_X  => ("STA", Mode::AbsoluteX),
+        opcodes::STA_ABSOLUTE_Y  => ("STA", Mode::AbsoluteY),
+        opcodes::STA_INDIRECT_X  => ("STA", Mode::IndirectX),
+        opcodes::STA_INDIRECT_Y  => ("STA", Mode::IndirectY),
+        opcodes::STX_ZERO_PAGE   => ("STX", Mode::ZeroPage),
+        opcodes::STX_ZERO_PAGE_Y => ("STX", Mode::ZeroPageY),
+        opcodes::STX_ABSOLUTE    => ("STX", Mode::Absolute),
+        opcodes::STY_ZERO_PAGE   => ("STY", Mode::ZeroPage),
+        opcodes::STY_ZERO_PAGE_X => ("STY", Mode::ZeroPageX),
+        opcodes::STY_ABSOLUTE    => ("STY", Mode::Absolute),
+        opcodes::STZ_ZERO_PAGE   => ("STZ", Mode::ZeroPage),
+        opcodes::STZ_ZERO_PAGE_X => ("STZ", Mode::ZeroPageX),
+        opcodes::STZ_ABSOLUTE    => ("STZ", Mode::Absolute),
+        opcodes::STZ_ABSOLUTE_X  => ("STZ", Mode::AbsoluteX),
+        opcodes::JMP_ABSOLUTE    => ("JMP", Mode::Absolute),
+        opcodes::JMP_INDIRECT    => ("JMP", Mode::Indirect),
+        opcodes::JSR             => ("JSR", Mode::Absolute),
+        opcodes::RTS             => ("RTS", Mode::Implied),
+        opcodes::TSX             => ("TSX", Mode::Implied),
+        opcodes::TXS             => ("TXS", Mode::Implied),
+        opcodes::TAX             => ("TAX", Mode::Implied),
+        opcodes::TAY             => ("TAY", Mode::Implied),
+        opcodes::TXA             => ("TXA", Mode::Implied),
+        opcodes::TYA             => ("TYA", Mode::Implied),
+        opcodes::INX             => ("INX", Mode::Implied),
+        opcodes::INY             => ("INY", Mode::Implied),
+        opcodes::DEX             => ("DEX", Mode::Implied),
+        opcodes::DEY             => ("DEY", Mode::Implied),
+        opcodes::INC_ZERO_PAGE   => ("INC", Mode::ZeroPage),
+        opcodes::INC_ZERO_PAGE_X => ("INC", Mode::ZeroPageX),
+        opcodes::INC_ABSOLUTE    => ("INC", Mode::Absolute),
+        opcodes::INC_ABSOLUTE_X  => ("INC", Mode::AbsoluteX),
+        opcodes::DEC_ZERO_PAGE   => ("DEC", Mode::ZeroPage),
+        opcodes::DEC_ZERO_PAGE_X => ("DEC", Mode::ZeroPageX),
+        opcodes::DEC_ABSOLUTE    => ("DEC", Mode::Absolute),
+        opcodes::DEC_ABSOLUTE_X  => ("DEC", Mode::AbsoluteX),
+        opcodes::PHA             => ("PHA", Mode::Implied),
+        opcodes::PHP             => ("PHP", Mode::Implied),
+        opcodes::PLA             => ("PLA", Mode::Implied),
+        opcodes::PLP             => ("PLP", Mode::Implied),
+        opcodes::PHX             => ("PHX", Mode::Implied),
+        opcodes::PHY             => ("PHY", Mode::Implied),
+        opcodes::PLX             => ("PLX", Mode::Implied),
+        opcodes::PLY             => ("PLY", Mode::Implied),
+        opcodes::AND_IMMEDIATE   => ("AND", Mode::Immediate),
+        opcodes::AND_ZERO_PAGE   => ("AND", Mode::ZeroPage),
+        opcodes::AND_ZERO_PAGE_X => ("AND", Mode::ZeroPageX),
+        opcodes::AND_ABSOLUTE    => ("AND", Mode::Absolute),
+        opcodes::AND_ABSOLUTE_X  => ("AND", Mode::AbsoluteX),
+        opcodes::AND_ABSOLUTE_Y  => ("AND", Mode::AbsoluteY),
+        opcodes::AND_INDIRECT_X  => ("AND", Mode::IndirectX),
+        opcodes::AND_INDIRECT_Y  => ("AND", Mode::IndirectY),
+        opcodes::EOR_IMMEDIATE   => ("EOR", Mode::Immediate),
+        opcodes::EOR_ZERO_PAGE   => ("EOR", Mode::ZeroPage),
+        opcodes::EOR_ZERO_PAGE_X => ("EOR", Mode::ZeroPageX),
+        opcodes::EOR_ABSOLUTE    => ("EOR", Mode::Absolute),
+        opcodes::EOR_ABSOLUTE_X  => ("EOR", Mode::AbsoluteX),
+        opcodes::EOR_ABSOLUTE_Y  => ("EOR", Mode::AbsoluteY),
+        opcodes::EOR_INDIRECT_X  => ("EOR", Mode::IndirectX),
+        opcodes::EOR_INDIRECT_Y  => ("EOR", Mode::IndirectY),
+        opcodes::ORA_IMMEDIATE   => ("ORA", Mode::Immediate),
+        opcodes::ORA_ZERO_PAGE   => ("ORA", Mode::ZeroPage),
+        opcodes::ORA_ZERO_PAGE_X => ("ORA", Mode::ZeroPageX),
+        opcodes::ORA_ABSOLUTE    => ("ORA", Mode::Absolute),
+        opcodes::ORA_ABSOLUTE_X  => ("ORA", Mode::AbsoluteX),
+        opcodes::ORA_ABSOLUTE_Y  => ("ORA", Mode::AbsoluteY),
+        opcodes::ORA_INDIRECT_X  => ("ORA", Mode::IndirectX),
+        opcodes::ORA_INDIRECT_Y  => ("ORA", Mode::IndirectY),
+        opcodes::BIT_ZERO_PAGE   => ("BIT", Mode::ZeroPage),
+        opcodes::BIT_ABSOLUTE    => ("BIT", Mode::Absolute),
+        opcodes::BEQ             => ("BEQ", Mode::Relative),
+        opcodes::BNE             => ("BNE", Mode::Relative),
+        opcodes::BCS             => ("BCS", Mode::Relative),
+        opcodes::BCC             => ("BCC", Mode::Relative),
+        opcodes::BMI             => ("BMI", Mode::Relative),
+        opcodes::BPL             => ("BPL", Mode::Relative),
+        opcodes::BVC             => ("BVC", Mode::Relative),
+        opcodes::BVS             => ("BVS", Mode::Relative),
+        opcodes::BRA             => ("BRA", Mode::Relative),
+        opcodes::CLC             => ("CLC", Mode::Implied),
+        opcodes::SEC             => ("SEC", Mode::Implied),
+        opcodes::CLD             => ("CLD", Mode::Implied),
+        opcodes::SED             => ("SED", Mode::Implied),
+        opcodes::CLI             => ("CLI", Mode::Implied),
+        opcodes::SEI             => ("SEI", Mode::Implied),
+        opcodes::CLV             => ("CLV", Mode::Implied),
+        opcodes::ADC_IMMEDIATE   => ("ADC", Mode::Immediate),
+        opcodes::ADC_ZERO_PAGE   => ("ADC", Mode::ZeroPage),
+        opcodes::ADC_ZERO_PAGE_X => ("ADC", Mode::ZeroPageX),
+        opcodes::ADC_ABSOLUTE    => ("ADC", Mode::Absolute),
+        opcodes::ADC_ABSOLUTE_X  => ("ADC", Mode::AbsoluteX),
+        opcodes::ADC_ABSOLUTE_Y  => ("ADC", Mode::AbsoluteY),
+        opcodes::ADC_INDIRECT_X  => ("ADC", Mode::IndirectX),
+        opcodes::ADC_INDIRECT_Y  => ("ADC", Mode::IndirectY),
+        opcodes::SBC_IMMEDIATE   => ("SBC", Mode::Immediate),
+        opcodes::SBC_ZERO_PAGE   => ("SBC", Mode::ZeroPage),
+        opcodes::SBC_ZERO_PAGE_X => ("SBC", Mode::ZeroPageX),
+        opcodes::SBC_ABSOLUTE    => ("SBC", Mode::Absolute),
+        opcodes::SBC_ABSOLUTE_X  => ("SBC", Mode::AbsoluteX),
+        opcodes::SBC_ABSOLUTE_Y  => ("SBC", Mode::AbsoluteY),
+        opcodes::SBC_INDIRECT_X  => ("SBC", Mode::IndirectX),
+        opcodes::SBC_INDIRECT_Y  => ("SBC", Mode::IndirectY),
+        opcodes::CMP_IMMEDIATE   => ("CMP", Mode::Immediate),
+        opcodes::CMP_ZERO_PAGE   => ("CMP", Mode::ZeroPage),
+        opcodes::CMP_ZERO_PAGE_X => ("CMP", Mode::ZeroPageX),
+        opcodes::CMP_ABSOLUTE    => ("CMP", Mode::Absolute),
+        opcodes::CMP_ABSOLUTE_X  => ("CMP", Mode::AbsoluteX),
+        opcodes::CMP_ABSOLUTE_Y  => ("CMP", Mode::AbsoluteY),
+        opcodes::CMP_INDIRECT_X  => ("CMP", Mode::IndirectX),
+        opcodes::CMP_INDIRECT_Y  => ("CMP", Mode::IndirectY),
+        opcodes::CPX_IMMEDIATE   => ("CPX", Mode::Immediate),
+        opcodes::CPX_ZERO_PAGE   => ("CPX", Mode::ZeroPage),
+        opcodes::CPX_ABSOLUTE    => ("CPX", Mode::Absolute),
+        opcodes::CPY_IMMEDIATE   => ("CPY", Mode::Immediate),
+        opcodes::CPY_ZERO_PAGE   => ("CPY", Mode::ZeroPage),
+        opcodes::CPY_ABSOLUTE    => ("CPY", Mode::Absolute),
+        opcodes::ASL_ACCUMULATOR => ("ASL", Mode::Accumulator),
+        opcodes::ASL_ZERO_PAGE   => ("ASL", Mode::ZeroPage),
+        opcodes::ASL_ZERO_PAGE_X => ("ASL", Mode::ZeroPageX),
+        opcodes::ASL_ABSOLUTE    => ("ASL", Mode::Absolute),
+        opcodes::ASL_ABSOLUTE_X  => ("ASL", Mode::AbsoluteX),
+        opcodes::LSR_ACCUMULATOR => ("LSR", Mode::Accumulator),
+        opcodes::LSR_ZERO_PAGE   => ("LSR", Mode::ZeroPage),
+        opcodes::LSR_ZERO_PAGE_X => ("LSR", Mode::ZeroPageX),
+        opcodes::LSR_ABSOLUTE    => ("LSR", Mode::Absolute),
+        opcodes::LSR_ABSOLUTE_X  => ("LSR", Mode::AbsoluteX),
+        opcodes::ROL_ACCUMULATOR => ("ROL", Mode::Accumulator),
+        opcodes::ROL_ZERO_PAGE   => ("ROL", Mode::ZeroPage),
+        opcodes::ROL_ZERO_PAGE_X => ("ROL", Mode::ZeroPageX),
+        opcodes::ROL_ABSOLUTE    => ("ROL", Mode::Absolute),
+        opcodes::ROL_ABSOLUTE_X  => ("ROL", Mode::AbsoluteX),
+        opcodes::ROR_ACCUMULATOR => ("ROR", Mode::Accumulator),
+        opcodes::ROR_ZERO_PAGE   => ("ROR", Mode::ZeroPage),
+        opcodes::ROR_ZERO_PAGE_X => ("ROR", Mode::ZeroPageX),
+        opcodes::ROR_ABSOLUTE    => ("ROR", Mode::Absolute),
+        opcodes::ROR_ABSOLUTE_X  => ("ROR", Mode::AbsoluteX),
+        opcodes::BRK             => ("BRK", Mode::Implied),
+        opcodes::RTI             => ("RTI", Mode::Implied),
+        opcodes::WAI             => ("WAI", Mode::Implied),
+        opcodes::STP             => ("STP", Mode::Implied),
+        _ => return None
+    });
+}
+
+// the instruction at `addr` in assembler syntax, and its length in bytes.
+// reads go through peek_byte, so disassembling never touches the adapter
+pub fn disassemble(bus: &(impl Bus + ?Sized), addr: u16) -> (String, u8) {
+    let opcode = bus.peek_byte(addr);
+
+    let (mnemonic, mode) = match decode(opcode) {
+        Some(decoded) => decoded,
+        None => return (format!(".byte ${:02X}", opcode), 1)
+    };
+
+    let byte = bus.peek_byte(addr.wrapping_add(1));
+    let word = (byte as u16) | ((bus.peek_byte(addr.wrapping_add(2)) as u16) << 8);
+
+    let operand = match mode {
+        Mode::Implied     => String::new(),
+        Mode::Accumulator => " A".to_string(),
+        Mode::Immediate   => format!(" #${:02X}", byte),
+        Mode::ZeroPage    => format!(" ${:02X}", byte),
+        Mode::ZeroPageX   => format!(" ${:02X},X", byte),
+        Mode::ZeroPageY   => format!(" ${:02X},Y", byte),
+        Mode::Absolute    => format!(" ${:04X}", word),
+        Mode::AbsoluteX   => format!(" ${:04X},X", word),
+        Mode::AbsoluteY   => format!(" ${:04X},Y", word),
+        Mode::Indirect    => format!(" (${:04X})", word),
+        Mode::IndirectX   => format!(" (${:02X},X)", byte),
+        Mode::IndirectY   => format!(" (${:02X}),Y", byte),
+        // shown as the branch target
+        Mode::Relative    => format!(" ${:04X}", addr.wrapping_add(2).wrapping_add(byte as i8 as u16))
+    };
+
+    return (mnemonic.to_string() + &operand, 1 + mode.operand_len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bus::FlatBus;
+
+    fn at_0200(bytes: &[u8]) -> (String, u8) {
+        return disassemble(&FlatBus::with_program(0x0200, bytes), 0x0200);
+    }
+
+    #[test]
+    fn disassembles_each_addressing_mode() {
+        let cases: [(&[u8], &str, u8); 11] = [
+            (&[0xa9, 0x40],       "LDA #$40",      2),
+            (&[0x0a],             "ASL A",         1),
+            (&[0xea],             "NOP",           1),
+            (&[0xa5, 0x10],       "LDA $10",       2),
+            (&[0xb6, 0x10],       "LDX $10,Y",     2),
+            (&[0x8d, 0x34, 0x12], "STA $1234",     3),
+            (&[0xbd, 0x34, 0x12], "LDA $1234,X",   3),
+            (&[0x6c, 0xfc, 0xff], "JMP ($FFFC)",   3),
+            (&[0xa1, 0x20],       "LDA ($20,X)",   2),
+            (&[0x91, 0x20],       "STA ($20),Y",   2),
+            (&[0xd0, 0xfe],       "BNE $0200",     2)
+        ];
+
+        for (bytes, text, len) in cases {
+            assert_eq!(at_0200(bytes), (text.to_string(), len));
+        }
+    }
+
+    #[test]
+    fn unknown_opcodes_are_shown_as_data() {
+        assert_eq!(at_0200(&[0xff]), (".byte $FF".to_string(), 1));
+    }
+}
@@ -0,0 +1,134 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum EmuError {
+    RomLoad(String, io::Error),
+    CharsetLoad(String, io::Error),
+    CartridgeLoad(String, io::Error),
+    ObjectLoad(String, io::Error),
+    ImageLoad(String, io::Error),
+    SymbolLoad(String, io::Error),
+    GifRecord(String, io::Error),
+    TraceFile(String, io::Error),
+    // save states need the serde feature
+    #[allow(unused)]
+    StateLoad(String, io::Error),
+    InvalidConfig(String)
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            EmuError::RomLoad(file, err)       => write!(f, "Couldn't load ROM file \"{}\": {}", file, err),
+            EmuError::CharsetLoad(file, err)   => write!(f, "Couldn't load charset file \"{}\": {}", file, err),
+            EmuError::CartridgeLoad(file, err) => write!(f, "Couldn't load cartridge file \"{}\": {}", file, err),
+            EmuError::ObjectLoad(file, err)    => write!(f, "Couldn't load o65 object \"{}\": {}", file, err),
+            EmuError::ImageLoad(file, err)     => write!(f, "Couldn't load memory image \"{}\": {}", file, err),
+            EmuError::SymbolLoad(file, err)    => write!(f, "Couldn't load symbol file \"{}\": {}", file, err),
+            EmuError::GifRecord(file, err)     => write!(f, "Couldn't record gif \"{}\": {}", file, err),
+            EmuError::TraceFile(file, err)     => write!(f, "Couldn't open trace file \"{}\": {}", file, err),
+            EmuError::StateLoad(file, err)     => write!(f, "Couldn't load save state \"{}\": {}", file, err),
+            EmuError::InvalidConfig(msg)       => write!(f, "Invalid configuration: {}", msg)
+        };
+    }
+}
+
+impl Error for EmuError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        return match self {
+            EmuError::RomLoad(_, err) | EmuError::CharsetLoad(_, err) |
+            EmuError::CartridgeLoad(_, err) | EmuError::ObjectLoad(_, err) | EmuError::ImageLoad(_, err) |
+            EmuError::SymbolLoad(_, err) | EmuError::GifRecord(_, err) |
+            EmuError::TraceFile(_, err) | EmuError::StateLoad(_, err) => Some(err),
+            EmuError::InvalidConfig(_) => None
+        };
+    }
+}
+
+// a write the bus dropped, with the address it was aimed at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusFault {
+    Rom(u16),
+    Adapter(u16),
+    Unmapped(u16)
+}
+
+impl fmt::Display for BusFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            BusFault::Rom(addr)      => write!(f, "CPU is trying to write to ROM at {:04X}", addr),
+            BusFault::Adapter(addr)  => write!(f, "CPU is trying to write to read only or invalid adapter address {:04X}", addr),
+            BusFault::Unmapped(addr) => write!(f, "CPU is trying to write to unmapped address {:04X}", addr)
+        };
+    }
+}
+
+impl Error for BusFault {}
+
+// raised by the CPU for bytes it can't decode and trapped bus faults, the host decides whether to halt.
+// the instruction that faulted still ran, so the fault carries the cycles it took
+#[derive(Debug, Clone, Copy)]
+pub enum StepError {
+    IllegalOpcode(u8, u16),
+    BusFault(BusFault, u8)
+}
+
+impl StepError {
+    // an illegal opcode doesn't run at all
+    pub fn cycles(&self) -> u8 {
+        return match self {
+            StepError::IllegalOpcode(..)   => 0,
+            StepError::BusFault(_, cycles) => *cycles
+        };
+    }
+}
+
+impl fmt::Display for StepError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            StepError::IllegalOpcode(opcode, pc) => write!(f, "Illegal opcode {:02X} at {:04X}", opcode, pc),
+            StepError::BusFault(fault, _)        => write!(f, "{}", fault)
+        };
+    }
+}
+
+impl Error for StepError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn not_found() -> io::Error {
+        return io::Error::new(io::ErrorKind::NotFound, "no such file");
+    }
+
+    #[test]
+    fn load_errors_name_the_file_and_the_cause() {
+        let cases = [
+            (EmuError::RomLoad("a.bin".to_string(), not_found()),       "Couldn't load ROM file \"a.bin\": no such file"),
+            (EmuError::CharsetLoad("c.bin".to_string(), not_found()),   "Couldn't load charset file \"c.bin\": no such file"),
+            (EmuError::CartridgeLoad("d.bin".to_string(), not_found()), "Couldn't load cartridge file \"d.bin\": no such file"),
+            (EmuError::ObjectLoad("e.o65".to_string(), not_found()),    "Couldn't load o65 object \"e.o65\": no such file"),
+            (EmuError::ImageLoad("f.bin".to_string(), not_found()),     "Couldn't load memory image \"f.bin\": no such file"),
+            (EmuError::SymbolLoad("g.sym".to_string(), not_found()),    "Couldn't load symbol file \"g.sym\": no such file"),
+            (EmuError::GifRecord("h.gif".to_string(), not_found()),     "Couldn't record gif \"h.gif\": no such file"),
+            (EmuError::TraceFile("i.log".to_string(), not_found()),     "Couldn't open trace file \"i.log\": no such file"),
+            (EmuError::StateLoad("j.state".to_string(), not_found()),   "Couldn't load save state \"j.state\": no such file")
+        ];
+
+        for (err, message) in cases {
+            assert_eq!(err.to_string(), message);
+            assert!(err.source().is_some());
+        }
+    }
+
+    #[test]
+    fn invalid_config_has_no_source() {
+        let err = EmuError::InvalidConfig("ram and rom overlap".to_string());
+
+        assert_eq!(err.to_string(), "Invalid configuration: ram and rom overlap");
+        assert!(err.source().is_none());
+    }
+}
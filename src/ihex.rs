@@ -0,0 +1,147 @@
+use std::io;
+
+// loader for the Intel HEX files most assemblers can emit
+// spec: https://en.wikipedia.org/wiki/Intel_HEX
+
+const REC_DATA:          u8 = 0x00;
+const REC_END_OF_FILE:   u8 = 0x01;
+const REC_EXT_SEGMENT:   u8 = 0x02;
+const REC_START_SEGMENT: u8 = 0x03;
+const REC_EXT_LINEAR:    u8 = 0x04;
+const REC_START_LINEAR:  u8 = 0x05;
+
+fn invalid(line: usize, msg: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line, msg));
+}
+
+// every record starts with a colon, a raw binary rarely starts with
+// one followed by the two hex digits of a record length
+pub fn detect(bytes: &[u8]) -> bool {
+    return bytes.len() >= 3 && bytes[0] == b':' && bytes[1].is_ascii_hexdigit() && bytes[2].is_ascii_hexdigit();
+}
+
+// a binary that only looks like HEX is taken as it is
+pub fn load_or_raw(bytes: Vec<u8>, base: u32, limit: usize) -> Vec<u8> {
+    if !detect(&bytes) {
+        return bytes;
+    }
+
+    return match load(&bytes, base, limit) {
+        Ok(image) => image,
+        Err(err) => {
+            println!("Warning: file starts like Intel HEX but isn't valid ({}), loading it as a raw binary", err);
+            bytes
+        }
+    };
+}
+
+fn parse_record(text: &str, line: usize) -> io::Result<Vec<u8>> {
+    let digits = text.strip_prefix(':').ok_or_else(|| invalid(line, "record doesn't start with ':'"))?;
+
+    if digits.len() % 2 != 0 || !digits.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(invalid(line, "record isn't made of hex byte pairs"));
+    }
+
+    let record: Vec<u8> = (0 .. digits.len()).step_by(2)
+        .map(|ix| u8::from_str_radix(&digits[ix .. ix + 2], 16).unwrap())
+        .collect();
+
+    // length, address, type and checksum
+    if record.len() < 5 || record.len() != record[0] as usize + 5 {
+        return Err(invalid(line, "record length doesn't match its contents"));
+    }
+
+    if record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+        return Err(invalid(line, "bad checksum"));
+    }
+
+    return Ok(record);
+}
+
+// lays the data records out in an image that starts at `base`, bytes no
+// record covers are left zero. the image grows to the highest address
+// written, but never past `limit` bytes
+pub fn load(bytes: &[u8], base: u32, limit: usize) -> io::Result<Vec<u8>> {
+    let text = std::str::from_utf8(bytes)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "not a text file"))?;
+
+    let mut image: Vec<u8> = Vec::new();
+    let mut upper: u32 = 0;
+
+    for (ix, raw) in text.lines().enumerate() {
+        let line = ix + 1;
+        let raw  = raw.trim();
+        if raw.is_empty() {
+            continue;
+        }
+
+        let record = parse_record(raw, line)?;
+        let offset = ((record[1] as u32) << 8) | record[2] as u32;
+        let data   = &record[4 .. record.len() - 1];
+
+        match record[3] {
+            REC_DATA => {
+                let address = upper.wrapping_add(offset);
+                if address < base {
+                    return Err(invalid(line, &format!("data at {:04X} is below the image start {:04X}", address, base)));
+                }
+
+                let start = (address - base) as usize;
+                let end   = start + data.len();
+                if end > limit {
+                    return Err(invalid(line, &format!("data at {:04X} doesn't fit in the image", address)));
+                }
+
+                if end > image.len() {
+                    image.resize(end, 0);
+                }
+                image[start .. end].copy_from_slice(data);
+            }
+            REC_END_OF_FILE => return Ok(image),
+            REC_EXT_SEGMENT | REC_EXT_LINEAR => {
+                if data.len() != 2 {
+                    return Err(invalid(line, "address record needs two bytes"));
+                }
+
+                let value = ((data[0] as u32) << 8) | data[1] as u32;
+                upper = if record[3] == REC_EXT_LINEAR { value << 16 } else { value << 4 };
+            }
+            // the reset vector decides where execution starts
+            REC_START_SEGMENT | REC_START_LINEAR => {}
+            _ => return Err(invalid(line, "unknown record type"))
+        }
+    }
+
+    return Err(io::Error::new(io::ErrorKind::InvalidData, "missing end of file record"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_two_data_records() {
+        let hex = b":02800000A94293\n:03800400EAEAEABB\n:00000001FF\n";
+        let image = load(hex, 0x8000, 0x8000).unwrap();
+
+        assert_eq!(image, [0xa9, 0x42, 0x00, 0x00, 0xea, 0xea, 0xea]);
+    }
+
+    #[test]
+    fn rejects_a_bad_checksum() {
+        assert!(load(b":02800000A94200\n:00000001FF\n", 0x8000, 0x8000).is_err());
+    }
+
+    #[test]
+    fn only_a_colon_at_the_start_is_hex() {
+        assert!(detect(b":10000000"));
+        // a raw binary starting with JSR $313A, not HEX after a space
+        assert!(!detect(b" :10000000"));
+    }
+
+    #[test]
+    fn invalid_hex_falls_back_to_raw() {
+        let bytes = b":12 not a record".to_vec();
+        assert_eq!(load_or_raw(bytes.clone(), 0, 0x8000), bytes);
+    }
+}
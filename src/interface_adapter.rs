@@ -8,55 +8,557 @@ pub const KEYUP  : u8 = 0xfe;
 pub const MOUSE_LCLICK: u8 = 0xfd;
 pub const MOUSE_RCLICK: u8 = 0xfc;
 
-use std::{fs::File, io::Read};
-use rand::Rng;
+pub const IDLE: u8 = 0xfb;
+
+pub const LATENCY_TEST: u8 = 0xfa;
+
+pub const TIMER: u8 = 0xf9;
+
+// extended registers, selected through 0xe and accessed through 0xd
+pub const EXT_MOUSE_X_HI: u8 = 0x00;
+pub const EXT_MOUSE_Y_HI: u8 = 0x01;
+pub const EXT_CLICK_X   : u8 = 0x02;
+pub const EXT_CLICK_Y   : u8 = 0x03;
+pub const EXT_CLICK_X_HI: u8 = 0x04;
+pub const EXT_CLICK_Y_HI: u8 = 0x05;
+
+// tone generator: frequency in Hz, then the gate register.
+// bit 7 of the gate turns the tone on, the low nibble is the volume
+pub const EXT_TONE_FREQ   : u8 = 0x06;
+pub const EXT_TONE_FREQ_HI: u8 = 0x07;
+pub const EXT_TONE_GATE   : u8 = 0x08;
+
+pub const TONE_GATE: u8 = 0x80;
+
+// interval timer: writing the high byte loads the counter from the latch
+// and starts it. it counts down once per CPU cycle and raises a TIMER
+// interrupt when it goes past 0, in continuous mode it reloads and keeps going
+pub const EXT_TIMER_LO  : u8 = 0x09;
+pub const EXT_TIMER_HI  : u8 = 0x0a;
+pub const EXT_TIMER_CTRL: u8 = 0x0b;
+
+pub const TIMER_CONTINUOUS: u8 = 0x01;
+pub const TIMER_RUNNING   : u8 = 0x80;
+
+// one bit per direction and the fire button, set while held
+pub const EXT_JOYSTICK: u8 = 0x0c;
+
+pub const JOY_UP   : u8 = 0x01;
+pub const JOY_DOWN : u8 = 0x02;
+pub const JOY_LEFT : u8 = 0x04;
+pub const JOY_RIGHT: u8 = 0x08;
+pub const JOY_FIRE : u8 = 0x10;
+
+// unsigned 8x8 bit multiplier, the result always reflects the current operands
+pub const EXT_MUL_A     : u8 = 0x0d;
+pub const EXT_MUL_B     : u8 = 0x0e;
+pub const EXT_MUL_RES   : u8 = 0x0f;
+pub const EXT_MUL_RES_HI: u8 = 0x10;
+
+// block copy within RAM, writing the high byte of the length starts it.
+// the mapper does the copy right away, the adapter only holds the registers
+pub const EXT_DMA_SRC   : u8 = 0x11;
+pub const EXT_DMA_SRC_HI: u8 = 0x12;
+pub const EXT_DMA_DST   : u8 = 0x13;
+pub const EXT_DMA_DST_HI: u8 = 0x14;
+pub const EXT_DMA_LEN   : u8 = 0x15;
+pub const EXT_DMA_LEN_HI: u8 = 0x16;
+
+// text cell drawn with its colors swapped, in cell coordinates
+pub const EXT_CURSOR_X   : u8 = 0x17;
+pub const EXT_CURSOR_Y   : u8 = 0x18;
+pub const EXT_CURSOR_CTRL: u8 = 0x19;
+
+pub const CURSOR_BLINK : u8 = 0x01;
+pub const CURSOR_ENABLE: u8 = 0x80;
+
+// how the PPU reads the framebuffer, see ppu::PPU::tick
+pub const EXT_VIDEO_MODE: u8 = 0x1a;
+
+pub const VIDEO_TEXT  : u8 = 0;
+pub const VIDEO_BITMAP: u8 = 1;
+
+// each sprite takes SPRITE_REGS registers starting at EXT_SPRITES:
+// X low, X high, Y low, Y high, glyph, attributes
+pub const EXT_SPRITES: u8 = 0x40;
+pub const SPRITE_REGS: u8 = 6;
+pub const NUM_SPRITES: u8 = 8;
+
+pub const SPRITE_ENABLE: u8 = 0x80;
+
+use std::{cell::{Cell, RefCell}, collections::VecDeque, fs::File, io::Read};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::error::EmuError;
+use crate::ihex;
+
+// position is in pixels, the low nibble of the attributes is the palette color
+#[derive(Clone, Copy, Default)]
+pub struct Sprite {
+    pub x: u16,
+    pub y: u16,
+    pub glyph: u8,
+    pub attr: u8
+}
+
+impl Sprite {
+    pub fn enabled(&self) -> bool {
+        return self.attr & SPRITE_ENABLE != 0;
+    }
+
+    fn write(&mut self, value: u8, register: u8) {
+        match register {
+            0 => self.x = (self.x & 0xff00) | value as u16,
+            1 => self.x = (self.x & 0x00ff) | ((value as u16) << 8),
+            2 => self.y = (self.y & 0xff00) | value as u16,
+            3 => self.y = (self.y & 0x00ff) | ((value as u16) << 8),
+            4 => self.glyph = value,
+            _ => self.attr  = value
+        }
+    }
+
+    fn read(&self, register: u8) -> u8 {
+        return match register {
+            0 => self.x as u8,
+            1 => (self.x >> 8) as u8,
+            2 => self.y as u8,
+            3 => (self.y >> 8) as u8,
+            4 => self.glyph,
+            _ => self.attr
+        };
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Cursor {
+    pub x: u8,
+    pub y: u8,
+    pub ctrl: u8
+}
+
+impl Cursor {
+    pub fn enabled(&self) -> bool {
+        return self.ctrl & CURSOR_ENABLE != 0;
+    }
+
+    pub fn blinks(&self) -> bool {
+        return self.ctrl & CURSOR_BLINK != 0;
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Tone {
+    pub freq: u16,
+    pub gate: u8
+}
+
+// only the audio feature plays the tone
+#[allow(unused)]
+impl Tone {
+    pub fn volume(&self) -> u8 {
+        return self.gate & 0x0f;
+    }
+
+    pub fn playing(&self) -> bool {
+        return self.gate & TONE_GATE != 0 && self.freq != 0 && self.volume() != 0;
+    }
+}
 
 pub struct Adapter {
     pub port_a: u8,
     pub port_b: u8,
 
+    // power-on state of the ports, set by the connected hardware
+    port_a_init: u8,
+    port_b_init: u8,
+
     pub keyb: u8,
+    pub joystick: u8,
+    // (interrupt id, scancode for key events) of events waiting for the previous one to be consumed
+    event_queue: VecDeque<(u8, Option<u8>)>,
+    // the delivered key hasn't been read from keyb yet
+    key_unread: Cell<bool>,
 
-    pub mouse_x: u8,
-    pub mouse_y: u8,
+    // in 16 bit mode these hold pixel coordinates,
+    // otherwise they hold text cell coordinates
+    pub mouse_x: u16,
+    pub mouse_y: u16,
+    pub mouse_16bit: bool,
+
+    // mouse position latched when a button was last pressed
+    pub click_x: u16,
+    pub click_y: u16,
 
     pub rom_ptr: u32,
     rom: Vec<u8>,
 
-    pub interrupt_id: u8
+    // selects the system ROM bank in banked mode, see mapper::BANK_SIZE
+    pub rom_bank: u8,
+
+    pub ext_select: u8,
+
+    pub sprites: [Sprite; NUM_SPRITES as usize],
+    pub cursor: Cursor,
+    pub video_mode: u8,
+    pub tone: Tone,
+
+    pub mul_a: u8,
+    pub mul_b: u8,
+
+    pub dma_src: u16,
+    pub dma_dst: u16,
+    pub dma_len: u16,
+    dma_pending: bool,
+
+    pub timer_latch:   u16,
+    pub timer_counter: u16,
+    pub timer_ctrl:    u8,
+    // the timer went off while another interrupt was still unacknowledged
+    timer_pending: bool,
+
+    // set when a register affecting the display is written
+    pub display_changed: bool,
+
+    pub interrupt_id: u8,
+    irq_pending: Cell<bool>,
+
+    // source of the RNG register, seeded so runs can be reproduced.
+    // the next value is drawn ahead of time so debug views can peek at it
+    rng: RefCell<StdRng>,
+    next_random: Cell<u8>,
+
+    // address of the instruction being run, readable as a debug register
+    pub cpu_pc: u16
 }
 
 impl Adapter {
     pub fn new() -> Self {
-        return Adapter { 
-            port_a: 0, port_b: 0, keyb: 0, 
-            mouse_x: 0, mouse_y: 0, rom_ptr: 0, 
-            rom: vec![0; MAX_ROM_SIZE as usize], interrupt_id: 0
+        let mut adapter = Adapter { 
+            port_a: 0, port_b: 0, port_a_init: 0, port_b_init: 0, keyb: 0, joystick: 0, event_queue: VecDeque::new(), key_unread: Cell::new(false),
+            mouse_x: 0, mouse_y: 0, mouse_16bit: false, click_x: 0, click_y: 0, rom_ptr: 0, 
+            rom: Vec::new(), rom_bank: 0, ext_select: 0, interrupt_id: 0,
+            sprites: [Sprite::default(); NUM_SPRITES as usize], cursor: Cursor::default(), video_mode: VIDEO_TEXT,
+            tone: Tone::default(), display_changed: false,
+            mul_a: 0, mul_b: 0, dma_src: 0, dma_dst: 0, dma_len: 0, dma_pending: false,
+            timer_latch: 0, timer_counter: 0, timer_ctrl: 0, timer_pending: false,
+            irq_pending: Cell::new(false), rng: RefCell::new(StdRng::seed_from_u64(0)), next_random: Cell::new(0), cpu_pc: 0
+        };
+
+        adapter.seed_rng(0);
+        return adapter;
+    }
+
+    // clears the volatile registers, the loaded cartridge is preserved
+    pub fn reset(&mut self) {
+        self.port_a = self.port_a_init;
+        self.port_b = self.port_b_init;
+        self.keyb   = 0;
+        self.event_queue.clear();
+        self.key_unread.set(false);
+        self.joystick = 0;
+
+        self.mouse_x = 0;
+        self.mouse_y = 0;
+        self.click_x = 0;
+        self.click_y = 0;
+
+        self.rom_ptr    = 0;
+        self.rom_bank   = 0;
+        self.ext_select = 0;
+
+        self.sprites = [Sprite::default(); NUM_SPRITES as usize];
+        self.cursor  = Cursor::default();
+        self.video_mode = VIDEO_TEXT;
+        self.tone    = Tone::default();
+        self.display_changed = true;
+
+        self.mul_a = 0;
+        self.mul_b = 0;
+
+        self.dma_src     = 0;
+        self.dma_dst     = 0;
+        self.dma_len     = 0;
+        self.dma_pending = false;
+
+        self.timer_latch   = 0;
+        self.timer_counter = 0;
+        self.timer_ctrl    = 0;
+        self.timer_pending = false;
+
+        self.interrupt_id = 0;
+        self.irq_pending.set(false);
+    }
+
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = RefCell::new(StdRng::seed_from_u64(seed));
+        self.next_random.set(self.rng.borrow_mut().gen_range(0 ..= 0xff));
+    }
+
+    fn random(&self) -> u8 {
+        return self.next_random.replace(self.rng.borrow_mut().gen_range(0 ..= 0xff));
+    }
+
+    pub fn set_port_defaults(&mut self, port_a: u8, port_b: u8) {
+        self.port_a_init = port_a;
+        self.port_b_init = port_b;
+
+        self.port_a = port_a;
+        self.port_b = port_b;
+    }
+
+    fn raise_interrupt(&mut self, id: u8) {
+        self.interrupt_id = id;
+        self.irq_pending.set(true);
+    }
+
+    // puts the IRQ line back as it was saved, without queueing anything
+    #[allow(unused)]
+    pub fn set_irq_line(&mut self, id: u8, asserted: bool) {
+        self.interrupt_id = id;
+        self.irq_pending.set(asserted);
+    }
+
+    pub fn queue_key(&mut self, scancode: u8, id: u8) {
+        self.event_queue.push_back((id, Some(scancode)));
+        self.deliver_pending();
+    }
+
+    pub fn queue_interrupt(&mut self, id: u8) {
+        self.event_queue.push_back((id, None));
+        self.deliver_pending();
+    }
+
+    // hands the next queued event to the CPU once the previous one was consumed,
+    // so events arriving within the same frame don't overwrite each other.
+    // an event is consumed once its interrupt was acknowledged and, for keys,
+    // keyb was read too. the timer goes first
+    pub fn deliver_pending(&mut self) {
+        if self.irq_asserted() || self.key_unread.get() {
+            return;
+        }
+
+        if self.timer_pending {
+            self.timer_pending = false;
+            self.raise_interrupt(TIMER);
+            return;
+        }
+
+        if let Some((id, scancode)) = self.event_queue.pop_front() {
+            if let Some(scancode) = scancode {
+                self.keyb = scancode;
+                self.key_unread.set(true);
+            }
+
+            self.raise_interrupt(id);
         }
     }
 
-    pub fn load_cartridge(&mut self, filename: &str) {
-        let mut file = File::open(filename)
-            .expect("Couldn't open cartridge file");
-        
-        let mut rom: Vec<u8> = Vec::new();
-        file.read_to_end(&mut rom)
-            .expect("Couldn't read cartridge file");
+    // (queued events, unread key, timer waiting to be delivered), for save states
+    #[allow(unused)]
+    pub fn pending_events(&self) -> (Vec<(u8, Option<u8>)>, bool, bool) {
+        return (self.event_queue.iter().copied().collect(), self.key_unread.get(), self.timer_pending);
+    }
+
+    #[allow(unused)]
+    pub fn restore_pending_events(&mut self, events: Vec<(u8, Option<u8>)>, key_unread: bool, timer_pending: bool) {
+        self.event_queue = events.into();
+        self.key_unread.set(key_unread);
+        self.timer_pending = timer_pending;
+        self.dma_pending   = false;
+    }
+
+    fn read_keyb(&self) -> u8 {
+        self.key_unread.set(false);
+        return self.keyb;
+    }
+
+    pub fn tick_timer(&mut self, cycles: u8) {
+        if self.timer_ctrl & TIMER_RUNNING == 0 {
+            return;
+        }
+
+        let cycles = cycles as u16;
+        if self.timer_counter >= cycles {
+            self.timer_counter -= cycles;
+            return;
+        }
+
+        // cycles left over after going past 0 count against the reloaded value
+        let overshoot = cycles - self.timer_counter - 1;
+        if self.timer_ctrl & TIMER_CONTINUOUS != 0 {
+            self.timer_counter = self.timer_latch.saturating_sub(overshoot);
+        } else {
+            self.timer_counter = 0;
+            self.timer_ctrl &= !TIMER_RUNNING;
+        }
+
+        self.timer_pending = true;
+        self.deliver_pending();
+    }
+
+    pub fn irq_asserted(&self) -> bool {
+        return self.irq_pending.get();
+    }
 
-        while rom.len() < MAX_ROM_SIZE as usize {
-            rom.push(0);
+    // reading the interrupt id acknowledges the interrupt and deasserts the IRQ line.
+    // reading it again without an interrupt pending gives up on an unread key,
+    // so programs that poll instead of reading keyb don't hold up the queue
+    pub fn acknowledge(&self) -> u8 {
+        if !self.irq_pending.get() {
+            self.key_unread.set(false);
         }
 
+        self.irq_pending.set(false);
+        return self.interrupt_id;
+    }
+
+    // Intel HEX addresses are offsets into the cartridge
+    pub fn load_cartridge(&mut self, filename: &str) -> Result<(), EmuError> {
+        let mut rom: Vec<u8> = Vec::new();
+        File::open(filename)
+            .and_then(|mut file| file.read_to_end(&mut rom))
+            .map_err(|err| EmuError::CartridgeLoad(filename.to_string(), err))?;
+
+        rom = ihex::load_or_raw(rom, 0, MAX_ROM_SIZE as usize);
+        rom.truncate(MAX_ROM_SIZE as usize);
+
         self.rom = rom;
+        return Ok(());
+    }
+
+    // the pointer is 24 bits wide, bytes past the end of the cartridge read as 0
+    fn cartridge_byte(&self) -> u8 {
+        return self.rom.get((self.rom_ptr & (MAX_ROM_SIZE - 1)) as usize).copied().unwrap_or(0);
+    }
+
+    // (source, destination, length) of a copy started since the last call
+    pub fn take_dma(&mut self) -> Option<(u16, u16, u16)> {
+        if !self.dma_pending {
+            return None;
+        }
+
+        self.dma_pending = false;
+        return Some((self.dma_src, self.dma_dst, self.dma_len));
+    }
+
+    fn product(&self) -> u16 {
+        return self.mul_a as u16 * self.mul_b as u16;
+    }
+
+    fn set_mouse_x_lo(&mut self, value: u8) {
+        self.mouse_x = (self.mouse_x & 0xff00) | value as u16;
     }
 
-    pub fn write_byte(&mut self, value: u8, address: u16) {
+    fn set_mouse_y_lo(&mut self, value: u8) {
+        self.mouse_y = (self.mouse_y & 0xff00) | value as u16;
+    }
+
+    pub fn latch_click(&mut self) {
+        self.click_x = self.mouse_x;
+        self.click_y = self.mouse_y;
+    }
+
+    pub fn write_ext(&mut self, value: u8, register: u8) {
+        match register {
+            EXT_MOUSE_X_HI => self.mouse_x = (self.mouse_x & 0x00ff) | ((value as u16) << 8),
+            EXT_MOUSE_Y_HI => self.mouse_y = (self.mouse_y & 0x00ff) | ((value as u16) << 8),
+            EXT_CLICK_X ..= EXT_CLICK_Y_HI => println!("CPU is trying to write to mouse click latch"),
+            EXT_TONE_FREQ    => self.tone.freq = (self.tone.freq & 0xff00) | value as u16,
+            EXT_TONE_FREQ_HI => self.tone.freq = (self.tone.freq & 0x00ff) | ((value as u16) << 8),
+            EXT_TONE_GATE    => self.tone.gate = value,
+            EXT_TIMER_LO     => self.timer_latch = (self.timer_latch & 0xff00) | value as u16,
+            EXT_TIMER_HI     => {
+                self.timer_latch   = (self.timer_latch & 0x00ff) | ((value as u16) << 8);
+                self.timer_counter = self.timer_latch;
+                self.timer_ctrl   |= TIMER_RUNNING;
+            },
+            EXT_TIMER_CTRL   => self.timer_ctrl = value,
+            EXT_JOYSTICK     => self.joystick = value,
+            EXT_MUL_A        => self.mul_a = value,
+            EXT_MUL_B        => self.mul_b = value,
+            EXT_MUL_RES ..= EXT_MUL_RES_HI => println!("CPU is trying to write to the multiplier result"),
+            EXT_DMA_SRC      => self.dma_src = (self.dma_src & 0xff00) | value as u16,
+            EXT_DMA_SRC_HI   => self.dma_src = (self.dma_src & 0x00ff) | ((value as u16) << 8),
+            EXT_DMA_DST      => self.dma_dst = (self.dma_dst & 0xff00) | value as u16,
+            EXT_DMA_DST_HI   => self.dma_dst = (self.dma_dst & 0x00ff) | ((value as u16) << 8),
+            EXT_DMA_LEN      => self.dma_len = (self.dma_len & 0xff00) | value as u16,
+            EXT_CURSOR_X     => {
+                self.cursor.x = value;
+                self.display_changed = true;
+            },
+            EXT_CURSOR_Y     => {
+                self.cursor.y = value;
+                self.display_changed = true;
+            },
+            EXT_CURSOR_CTRL  => {
+                self.cursor.ctrl = value;
+                self.display_changed = true;
+            },
+            EXT_VIDEO_MODE   => {
+                self.video_mode = value;
+                self.display_changed = true;
+            },
+            EXT_DMA_LEN_HI   => {
+                self.dma_len     = (self.dma_len & 0x00ff) | ((value as u16) << 8);
+                self.dma_pending = true;
+            },
+            EXT_SPRITES ..= 0xff if register < EXT_SPRITES + NUM_SPRITES * SPRITE_REGS => {
+                let offset = register - EXT_SPRITES;
+                self.sprites[(offset / SPRITE_REGS) as usize].write(value, offset % SPRITE_REGS);
+                self.display_changed = true;
+            },
+            _ => println!("Invalid extended adapter register {:02X}", register)
+        }
+    }
+
+    pub fn read_ext(&self, register: u8) -> u8 {
+        return match register {
+            EXT_MOUSE_X_HI => (self.mouse_x >> 8) as u8,
+            EXT_MOUSE_Y_HI => (self.mouse_y >> 8) as u8,
+            EXT_CLICK_X    => self.click_x as u8,
+            EXT_CLICK_Y    => self.click_y as u8,
+            EXT_CLICK_X_HI => (self.click_x >> 8) as u8,
+            EXT_CLICK_Y_HI => (self.click_y >> 8) as u8,
+            EXT_TONE_FREQ    => self.tone.freq as u8,
+            EXT_TONE_FREQ_HI => (self.tone.freq >> 8) as u8,
+            EXT_TONE_GATE    => self.tone.gate,
+            EXT_TIMER_LO     => self.timer_counter as u8,
+            EXT_TIMER_HI     => (self.timer_counter >> 8) as u8,
+            EXT_TIMER_CTRL   => self.timer_ctrl,
+            EXT_JOYSTICK     => self.joystick,
+            EXT_MUL_A        => self.mul_a,
+            EXT_MUL_B        => self.mul_b,
+            EXT_MUL_RES      => self.product() as u8,
+            EXT_MUL_RES_HI   => (self.product() >> 8) as u8,
+            EXT_DMA_SRC      => self.dma_src as u8,
+            EXT_DMA_SRC_HI   => (self.dma_src >> 8) as u8,
+            EXT_DMA_DST      => self.dma_dst as u8,
+            EXT_DMA_DST_HI   => (self.dma_dst >> 8) as u8,
+            EXT_DMA_LEN      => self.dma_len as u8,
+            EXT_DMA_LEN_HI   => (self.dma_len >> 8) as u8,
+            EXT_CURSOR_X     => self.cursor.x,
+            EXT_CURSOR_Y     => self.cursor.y,
+            EXT_CURSOR_CTRL  => self.cursor.ctrl,
+            EXT_VIDEO_MODE   => self.video_mode,
+            EXT_SPRITES ..= 0xff if register < EXT_SPRITES + NUM_SPRITES * SPRITE_REGS => {
+                let offset = register - EXT_SPRITES;
+                self.sprites[(offset / SPRITE_REGS) as usize].read(offset % SPRITE_REGS)
+            },
+            _ => {
+                println!("Invalid extended adapter register {:02X}", register);
+                0
+            }
+        };
+    }
+
+    // false for addresses that aren't registers and for the read only cartridge byte
+    pub fn write_byte(&mut self, value: u8, address: u16) -> bool {
         match address {
             0x0 => self.port_b  = value,
             0x1 => self.port_a  = value,
             0x2 => self.keyb    = value,
-            0x3 => self.mouse_x = value,
-            0x4 => self.mouse_y = value,
+            0x3 => self.set_mouse_x_lo(value),
+            0x4 => self.set_mouse_y_lo(value),
             0x5 => {
                 self.rom_ptr &= 0x00ffff00;
                 self.rom_ptr |= value as u32;
@@ -69,26 +571,47 @@ impl Adapter {
                 self.rom_ptr &= 0x0000ffff;
                 self.rom_ptr |= (value as u32) << 16;
             }
-            0x8 => panic!("CPU is trying to write to adapter ROM"),
+            0x8 => return false,
             0x9 => println!("CPU is trying to write to RNG source"),
+            0xa | 0xb => println!("CPU is trying to write to the PC debug register"),
+            0xc => self.rom_bank = value,
+            0xd => self.write_ext(value, self.ext_select),
+            0xe => self.ext_select = value,
             0xf => self.interrupt_id = value,
-            _   => println!("Invalid adapter address {:04X}", address)
+            _   => return false
         }
+
+        return true;
+    }
+
+    // like read_byte but without acknowledging, consuming the key or stepping the RNG
+    pub fn peek_byte(&self, address: u16) -> u8 {
+        return match address {
+            0x2 => self.keyb,
+            0x9 => self.next_random.get(),
+            0xf => self.interrupt_id,
+            _   => self.read_byte(address)
+        };
     }
 
     pub fn read_byte(&self, address: u16) -> u8 {
         return match address {
             0x0 => self.port_b,
             0x1 => self.port_a,
-            0x2 => self.keyb,
-            0x3 => self.mouse_x,
-            0x4 => self.mouse_y,
+            0x2 => self.read_keyb(),
+            0x3 => self.mouse_x as u8,
+            0x4 => self.mouse_y as u8,
             0x5 => (self.rom_ptr  & 0x00ff) as u8,
             0x6 => (self.rom_ptr >>      8) as u8,
             0x7 => (self.rom_ptr >>     16) as u8,
-            0x8 => self.rom[self.rom_ptr as usize],
-            0x9 => rand::thread_rng().gen_range(0 .. 0xff),
-            0xf => self.interrupt_id,
+            0x8 => self.cartridge_byte(),
+            0x9 => self.random(),
+            0xa => (self.cpu_pc & 0x00ff) as u8,
+            0xb => (self.cpu_pc >>      8) as u8,
+            0xc => self.rom_bank,
+            0xd => self.read_ext(self.ext_select),
+            0xe => self.ext_select,
+            0xf => self.acknowledge(),
             _   => {
                 println!("Invalid adapter address {:04X}", address);
                 0
@@ -96,6 +619,7 @@ impl Adapter {
         };
     }
 
+    #[allow(unused)]
     pub fn write_word(&mut self, value: u16, address: u16) -> bool {
         match address {
             0x0 => {
@@ -107,15 +631,15 @@ impl Adapter {
                 self.keyb   = (value >>      8) as u8;
             },
             0x2 => {
-                self.keyb    = (value  & 0x00ff) as u8;
-                self.mouse_x = (value >>      8) as u8;
+                self.keyb = (value & 0x00ff) as u8;
+                self.set_mouse_x_lo((value >> 8) as u8);
             },
             0x3 => {
-                self.mouse_x = (value  & 0x00ff) as u8;
-                self.mouse_y = (value >>      8) as u8;
+                self.set_mouse_x_lo((value & 0x00ff) as u8);
+                self.set_mouse_y_lo((value >>      8) as u8);
             },
             0x4 => {
-                self.mouse_y = (value & 0x00ff) as u8;
+                self.set_mouse_y_lo((value & 0x00ff) as u8);
 
                 self.rom_ptr &= 0x00ffff00;
                 self.rom_ptr |= (value >> 8) as u32;
@@ -135,7 +659,24 @@ impl Adapter {
                 self.interrupt_id = (value >> 8) as u8;
             }
             0x8 => println!("CPU is trying to write to adapter ROM and RNG source"),
-            0x9 => println!("CPU is trying to write to RNG source and unbound memory"),
+            0x9 => println!("CPU is trying to write to RNG source and the PC debug register"),
+            0xa => println!("CPU is trying to write to the PC debug register"),
+            0xb => {
+                println!("CPU is trying to write to the PC debug register");
+                self.rom_bank = (value >> 8) as u8;
+            },
+            0xc => {
+                self.rom_bank = (value & 0x00ff) as u8;
+                self.write_ext((value >> 8) as u8, self.ext_select);
+            },
+            0xd => {
+                self.write_ext((value & 0x00ff) as u8, self.ext_select);
+                self.ext_select = (value >> 8) as u8;
+            },
+            0xe => {
+                self.ext_select   = (value & 0x00ff) as u8;
+                self.interrupt_id = (value >>      8) as u8;
+            },
             0xf => {
                 self.interrupt_id = (value & 0x00ff) as u8;
                 return true;
@@ -149,18 +690,20 @@ impl Adapter {
     pub fn read_word(&self, address: u16) -> Option<u16> {
         return match address {
             0x0 => Some((self.port_b  as u16) | ((self.port_a  as u16) << 8)),
-            0x1 => Some((self.port_a  as u16) | ((self.keyb    as u16) << 8)),
-            0x2 => Some((self.keyb    as u16) | ((self.mouse_x as u16) << 8)),
-            0x3 => Some((self.mouse_x as u16) | ((self.mouse_y as u16) << 8)),
-            0x4 => Some((self.mouse_y as u16) | ((self.rom_ptr & 0x000000ff) << 8) as u16),
+            0x1 => Some((self.port_a  as u16) | ((self.read_keyb() as u16) << 8)),
+            0x2 => Some((self.read_keyb() as u16) | ((self.mouse_x & 0x00ff) << 8)),
+            0x3 => Some((self.mouse_x & 0x00ff) | ((self.mouse_y & 0x00ff) << 8)),
+            0x4 => Some((self.mouse_y & 0x00ff) | ((self.rom_ptr & 0x000000ff) << 8) as u16),
             0x5 => Some((self.rom_ptr & 0x0000ffff) as u16),
             0x6 => Some(((self.rom_ptr & 0x00ffff00) >> 8) as u16),
-            0x7 => Some((self.rom_ptr >> 16) as u16 | ((self.rom[self.rom_ptr as usize] as u16) << 8)),
-            0x8 => Some((self.rom[self.rom_ptr as usize] as u16) | (rand::thread_rng().gen_range(0 .. 0xff) << 8)),
-            0x9 => {
-                println!("CPU is trying to access unbound memory");
-                Some(rand::thread_rng().gen_range(0 .. 0xff))
-            },
+            0x7 => Some((self.rom_ptr >> 16) as u16 | ((self.cartridge_byte() as u16) << 8)),
+            0x8 => Some((self.cartridge_byte() as u16) | ((self.random() as u16) << 8)),
+            0x9 => Some((self.random() as u16) | ((self.cpu_pc & 0x00ff) << 8)),
+            0xa => Some(self.cpu_pc),
+            0xb => Some((self.cpu_pc >> 8) | ((self.rom_bank as u16) << 8)),
+            0xc => Some((self.rom_bank as u16) | ((self.read_ext(self.ext_select) as u16) << 8)),
+            0xd => Some((self.read_ext(self.ext_select) as u16) | ((self.ext_select as u16) << 8)),
+            0xe => Some((self.ext_select as u16) | ((self.acknowledge() as u16) << 8)),
             0xf => None,
             _   => {
                 println!("Invalid adapter address {:04X}", address);
@@ -168,4 +711,281 @@ impl Adapter {
             }
         };
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // what an interrupt handler does: read the id, then the key for key events
+    fn service(adapter: &mut Adapter) -> (u8, u8) {
+        adapter.deliver_pending();
+        assert!(adapter.irq_asserted());
+
+        let id = adapter.read_byte(0xf);
+        return (id, adapter.read_byte(0x2));
+    }
+
+    #[test]
+    fn two_keys_in_one_frame_are_both_delivered() {
+        let mut adapter = Adapter::new();
+        adapter.queue_key(0x1e, KEYDOWN);
+        adapter.queue_key(0x30, KEYDOWN);
+
+        assert_eq!(service(&mut adapter), (KEYDOWN, 0x1e));
+        assert_eq!(service(&mut adapter), (KEYDOWN, 0x30));
+
+        adapter.deliver_pending();
+        assert!(!adapter.irq_asserted());
+    }
+
+    #[test]
+    fn next_key_waits_for_keyb_to_be_read() {
+        let mut adapter = Adapter::new();
+        adapter.queue_key(0x1e, KEYDOWN);
+        adapter.queue_key(0x30, KEYUP);
+
+        // acknowledged, but the handler hasn't got to keyb yet
+        assert_eq!(adapter.read_byte(0xf), KEYDOWN);
+        adapter.deliver_pending();
+        assert!(!adapter.irq_asserted());
+        assert_eq!(adapter.read_byte(0x2), 0x1e);
+
+        adapter.deliver_pending();
+        assert!(adapter.irq_asserted());
+        assert_eq!(adapter.read_byte(0xf), KEYUP);
+    }
+
+    #[test]
+    fn mouse_click_doesnt_overwrite_a_pending_key() {
+        let mut adapter = Adapter::new();
+        adapter.queue_key(0x1e, KEYDOWN);
+        adapter.queue_interrupt(MOUSE_LCLICK);
+
+        assert_eq!(service(&mut adapter), (KEYDOWN, 0x1e));
+
+        adapter.deliver_pending();
+        assert_eq!(adapter.read_byte(0xf), MOUSE_LCLICK);
+    }
+
+    #[test]
+    fn polling_the_interrupt_id_skips_an_unread_key() {
+        let mut adapter = Adapter::new();
+        adapter.queue_key(0x1e, KEYDOWN);
+        adapter.queue_interrupt(MOUSE_RCLICK);
+
+        assert_eq!(adapter.read_byte(0xf), KEYDOWN);
+        adapter.read_byte(0xf);
+
+        adapter.deliver_pending();
+        assert_eq!(adapter.read_byte(0xf), MOUSE_RCLICK);
+    }
+
+    #[test]
+    fn seeded_rng_is_reproducible() {
+        let mut first  = Adapter::new();
+        let mut second = Adapter::new();
+        first.seed_rng(1234);
+        second.seed_rng(1234);
+
+        let sequence: Vec<u8> = (0 .. 2).map(|_| first.read_byte(0x9)).collect();
+        assert_eq!(sequence, (0 .. 2).map(|_| second.read_byte(0x9)).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn peeking_the_rng_doesnt_step_it() {
+        let mut adapter = Adapter::new();
+        adapter.seed_rng(99);
+
+        let peeked = adapter.peek_byte(0x9);
+        assert_eq!(adapter.peek_byte(0x9), peeked);
+        assert_eq!(adapter.read_byte(0x9), peeked);
+    }
+
+    #[test]
+    fn rng_word_reads_into_the_pc_register() {
+        let mut adapter = Adapter::new();
+        adapter.cpu_pc = 0x1234;
+        let random = adapter.peek_byte(0x9);
+
+        assert_eq!(adapter.read_word(0x9), Some((random as u16) | 0x3400));
+    }
+
+
+    #[test]
+    fn reading_the_interrupt_id_deasserts_the_irq() {
+        let mut adapter = Adapter::new();
+        adapter.queue_interrupt(IDLE);
+        assert!(adapter.irq_asserted());
+
+        assert_eq!(adapter.read_byte(0xf), IDLE);
+        assert!(!adapter.irq_asserted());
+
+        // nothing else is pending, so it stays deasserted
+        adapter.deliver_pending();
+        assert!(!adapter.irq_asserted());
+    }
+
+
+    #[test]
+    fn moving_after_a_click_keeps_the_latched_position() {
+        let mut adapter = Adapter::new();
+        (adapter.mouse_x, adapter.mouse_y) = (12, 34);
+        adapter.latch_click();
+        (adapter.mouse_x, adapter.mouse_y) = (50, 60);
+
+        assert_eq!(adapter.read_ext(EXT_CLICK_X), 12);
+        assert_eq!(adapter.read_ext(EXT_CLICK_Y), 34);
+        assert_eq!(adapter.read_byte(0x3), 50);
+    }
+
+
+    #[test]
+    fn reset_clears_registers_but_keeps_the_cartridge() {
+        let mut adapter = Adapter::new();
+        adapter.rom = vec![0x11, 0x22, 0x33];
+        adapter.write_byte(0x5a, 0x0);
+        adapter.write_byte(0x01, 0x5);
+        (adapter.mouse_x, adapter.mouse_y) = (3, 4);
+        adapter.queue_interrupt(IDLE);
+
+        adapter.reset();
+
+        assert_eq!(adapter.read_byte(0x0), 0);
+        assert_eq!((adapter.mouse_x, adapter.mouse_y), (0, 0));
+        assert!(!adapter.irq_asserted());
+        assert_eq!(adapter.interrupt_id, 0);
+        // the pointer is back at the start of the cartridge
+        assert_eq!(adapter.read_byte(0x8), 0x11);
+    }
+
+
+    #[test]
+    fn ports_start_at_their_configured_values() {
+        let mut adapter = Adapter::new();
+        adapter.set_port_defaults(0xa5, 0x3c);
+
+        assert_eq!(adapter.read_byte(0x1), 0xa5);
+        assert_eq!(adapter.read_byte(0x0), 0x3c);
+
+        // and go back to them on reset
+        adapter.write_byte(0x00, 0x1);
+        adapter.reset();
+        assert_eq!(adapter.read_byte(0x1), 0xa5);
+    }
+
+
+    #[test]
+    fn rom_ptr_past_the_cartridge_reads_zero() {
+        let mut adapter = Adapter::new();
+        adapter.rom = vec![0x11, 0x22];
+
+        adapter.rom_ptr = 1;
+        assert_eq!(adapter.read_byte(0x8), 0x22);
+
+        adapter.rom_ptr = 2;
+        assert_eq!(adapter.read_byte(0x8), 0x00);
+        adapter.rom_ptr = MAX_ROM_SIZE - 1;
+        assert_eq!(adapter.read_byte(0x8), 0x00);
+        assert_eq!(adapter.read_word(0x8).map(|word| word & 0xff), Some(0x00));
+
+        // the pointer is 24 bits wide
+        adapter.rom_ptr = MAX_ROM_SIZE;
+        assert_eq!(adapter.read_byte(0x8), 0x11);
+    }
+
+
+    #[test]
+    fn rng_reaches_ff() {
+        let mut adapter = Adapter::new();
+        adapter.seed_rng(7);
+
+        let max = (0 .. 10_000).map(|_| adapter.read_byte(0x9)).max();
+        assert_eq!(max, Some(0xff));
+    }
+
+
+    // writes an extended register the way a program does, through 0xe and 0xd
+    fn write_ext_reg(adapter: &mut Adapter, register: u8, value: u8) {
+        adapter.write_byte(register, 0xe);
+        adapter.write_byte(value, 0xd);
+    }
+
+    #[test]
+    fn tone_registers_update_the_sound_state() {
+        let mut adapter = Adapter::new();
+        write_ext_reg(&mut adapter, EXT_TONE_FREQ, 0xb8);
+        write_ext_reg(&mut adapter, EXT_TONE_FREQ_HI, 0x01);
+        assert!(!adapter.tone.playing());
+
+        write_ext_reg(&mut adapter, EXT_TONE_GATE, TONE_GATE | 0x0a);
+        assert_eq!(adapter.tone, Tone { freq: 440, gate: TONE_GATE | 0x0a });
+        assert_eq!(adapter.tone.volume(), 0x0a);
+        assert!(adapter.tone.playing());
+
+        assert_eq!(adapter.read_ext(EXT_TONE_FREQ_HI), 0x01);
+    }
+
+
+    #[test]
+    fn timer_underflow_requests_an_irq() {
+        let mut adapter = Adapter::new();
+        write_ext_reg(&mut adapter, EXT_TIMER_LO, 10);
+        write_ext_reg(&mut adapter, EXT_TIMER_HI, 0);
+
+        adapter.tick_timer(7);
+        assert!(!adapter.irq_asserted());
+        adapter.tick_timer(4);
+        assert!(adapter.irq_asserted());
+        assert_eq!(adapter.read_byte(0xf), TIMER);
+
+        // one shot, so it stops there
+        assert_eq!(adapter.read_ext(EXT_TIMER_CTRL) & TIMER_RUNNING, 0);
+        adapter.tick_timer(100);
+        assert!(!adapter.irq_asserted());
+    }
+
+    #[test]
+    fn continuous_timer_reloads() {
+        let mut adapter = Adapter::new();
+        write_ext_reg(&mut adapter, EXT_TIMER_CTRL, TIMER_CONTINUOUS);
+        write_ext_reg(&mut adapter, EXT_TIMER_LO, 10);
+        write_ext_reg(&mut adapter, EXT_TIMER_HI, 0);
+
+        adapter.tick_timer(11);
+        assert_eq!(adapter.read_byte(0xf), TIMER);
+        adapter.tick_timer(11);
+        assert!(adapter.irq_asserted());
+    }
+
+
+    #[test]
+    fn joystick_register_round_trips() {
+        let mut adapter = Adapter::new();
+        adapter.joystick = JOY_UP | JOY_RIGHT | JOY_FIRE;
+        adapter.write_byte(EXT_JOYSTICK, 0xe);
+
+        assert_eq!(adapter.read_byte(0xd), 0x19);
+        // 0xd is the selected register, with the selection above it
+        assert_eq!(adapter.read_word(0xd), Some(0x19 | ((EXT_JOYSTICK as u16) << 8)));
+        assert_eq!(adapter.read_word(0xc), Some(0x1900));
+
+        write_ext_reg(&mut adapter, EXT_JOYSTICK, JOY_DOWN | JOY_LEFT);
+        assert_eq!(adapter.joystick, 0x06);
+    }
+
+
+    #[test]
+    fn multiplier_result_spans_two_registers() {
+        let mut adapter = Adapter::new();
+        write_ext_reg(&mut adapter, EXT_MUL_A, 0x0c);
+        write_ext_reg(&mut adapter, EXT_MUL_B, 0x0f);
+
+        assert_eq!(adapter.read_ext(EXT_MUL_RES), 0xb4);
+        assert_eq!(adapter.read_ext(EXT_MUL_RES_HI), 0x00);
+
+        write_ext_reg(&mut adapter, EXT_MUL_A, 0xff);
+        write_ext_reg(&mut adapter, EXT_MUL_B, 0xff);
+        assert_eq!(adapter.product(), 0xfe01);
+        assert_eq!(adapter.read_ext(EXT_MUL_RES_HI), 0xfe);
+    }
+}
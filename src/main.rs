@@ -1,25 +1,50 @@
 #![allow(arithmetic_overflow)]
+#![allow(clippy::needless_return, clippy::explicit_auto_deref, clippy::manual_range_contains)]
+#![allow(clippy::upper_case_acronyms, clippy::int_plus_one)]
 
+mod bus;
 mod cpu;
+mod error;
+mod debug_panel;
+mod disasm;
 mod ppu;
 mod opcodes;
 mod mapper;
 mod interface_adapter;
+mod o65;
+mod ihex;
+mod symbols;
+mod recorder;
+#[cfg(feature = "serde")]
+mod state;
+#[cfg(all(test, feature = "serde"))]
+mod single_step;
+#[cfg(feature = "audio")]
+mod audio;
 
 use std::cell::RefCell;
 use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::process;
 use std::rc::Rc;
-use std::time::{Instant, Duration};
+use std::time::{Instant, Duration, SystemTime, UNIX_EPOCH};
 use std::thread::sleep;
 
 use speedy2d::color::Color;
 use speedy2d::dimen::Vector2;
-use speedy2d::window::{WindowSize, WindowPosition, MouseButton};
+use speedy2d::image::{ImageDataType, ImageSmoothingMode};
+use speedy2d::shape::Rectangle;
+use speedy2d::window::{WindowSize, WindowPosition, MouseButton, VirtualKeyCode, ModifiersState};
 use speedy2d::window::{WindowHandler, WindowHelper, WindowCreationOptions};
 use speedy2d::{Graphics2D, Window};
 
 use clap::Parser;
 
+use bus::Bus;
+use error::{EmuError, StepError};
+
 const RESOLUTION_X: u16 = ppu::INTERNAL_RESOLUTION_X * 2;
 const RESOLUTION_Y: u16 = ppu::INTERNAL_RESOLUTION_Y * 2; 
 
@@ -27,6 +52,62 @@ const TICKS_PER_FRAME: u32 = 1;
 const DEFAULT_DELAY: f32 = 0.0;
 const FORCE_UPDATE_EACH: u16 = 3600;
 const UPDATE_EACH_CHANGED: u16 = 1;
+const IDLE_TIMEOUT: f32 = 0.0;
+const PPU_EVERY: u32 = 0;
+const O65_BASE: u16 = 0x0200;
+const RECORD_FRAMES: u32 = 300;
+#[cfg(feature = "serde")]
+const REWIND_EVERY: u32 = 60;
+#[cfg(feature = "serde")]
+const REWIND_DEPTH: usize = 32;
+
+const LATENCY_BUDGET: u64 = 1_000_000;
+
+// the functional test suites need around 30 million instructions
+const TRAP_BUDGET: u64 = 200_000_000;
+
+// with --target-hz, falling further behind than this starts the pacing over
+// instead of running flat out to catch up, e.g. after a pause
+const MAX_PACING_LAG: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy)]
+struct Viewport {
+    scale_x: f32,
+    scale_y: f32,
+
+    offset_x: f32,
+    offset_y: f32
+}
+
+impl Viewport {
+    // fits the internal resolution into the window with the given
+    // width / height ratio, letterboxing the rest. 0 disables correction
+    fn new(aspect: f32) -> Self {
+        let win_x = RESOLUTION_X as f32;
+        let win_y = RESOLUTION_Y as f32;
+
+        if aspect == 0.0 {
+            return Viewport {
+                scale_x: win_x / ppu::INTERNAL_RESOLUTION_X as f32,
+                scale_y: win_y / ppu::INTERNAL_RESOLUTION_Y as f32,
+                offset_x: 0.0, offset_y: 0.0
+            };
+        }
+
+        let (width, height) = if win_x / win_y > aspect {
+            (win_y * aspect, win_y)
+        } else {
+            (win_x, win_x / aspect)
+        };
+
+        return Viewport {
+            scale_x: width  / ppu::INTERNAL_RESOLUTION_X as f32,
+            scale_y: height / ppu::INTERNAL_RESOLUTION_Y as f32,
+            offset_x: (win_x - width)  / 2.0,
+            offset_y: (win_y - height) / 2.0
+        };
+    }
+}
 
 struct Emu {
     update_each: u16,
@@ -35,9 +116,46 @@ struct Emu {
     do_sleep: bool,
     sleep: Duration,
 
+    // cycles run since pace_start, only tracked with a target clock
+    target_hz:   u64,
+    pace_start:  Instant,
+    pace_cycles: u64,
+
     frame: u16,
     timer: Instant,
 
+    do_idle: bool,
+    idle_timeout: Duration,
+    idle_signaled: bool,
+    last_input: Instant,
+
+    ppu_every: u32,
+    ppu_cnt:   u32,
+
+    reset_adapter: bool,
+    ctrl: bool,
+
+    paused:  bool,
+    resumed: bool,
+    watch:   RegisterWatch,
+    breakpoints: HashSet<u16>,
+
+    viewport: Viewport,
+    crt:      bool,
+    panel:    debug_panel::DebugPanel,
+
+    snapshot: Option<Vec<u8>>,
+    symbols:  symbols::Symbols,
+    recorder: Option<recorder::GifRecorder>,
+
+    #[cfg(feature = "audio")]
+    audio: Option<audio::ToneOutput>,
+
+    #[cfg(feature = "serde")]
+    rewind: state::Rewind,
+    #[cfg(feature = "serde")]
+    state_file: String,
+
     ticks:  u32,
     mapper: Rc<RefCell<mapper::Map>>,
     cpu:    cpu::CPU,
@@ -52,95 +170,527 @@ impl Emu {
     }
 
     fn memoryrow(&mut self, addr: u16, mut x: u8, y: u8) {
-        self.draw_text(&(format!("{:04X}", addr) + &": ".to_string()), x, y, Color::WHITE);
+        self.draw_text(&(format!("{:04X}", addr) + ": "), x, y, Color::WHITE);
 
         x += 6;
         for i in 0 .. 16 {
-            let byte = (*self.mapper.borrow()).read_byte(addr + i as u16);
+            let byte = (*self.mapper.borrow()).peek_byte(addr + i);
             self.draw_text(&format!("{:02X}", byte), x, y, if addr + i == self.cpu.pc { Color::GREEN } else { Color::WHITE });
 
             x += 3;
         }
     }
+
+    fn draw_panel(&mut self, cycles: u64, cpu_time: f32) {
+        let panel = self.panel;
+
+        let clock_str = (cycles as f32 / cpu_time).to_string();
+        let lim = cmp::min(32, clock_str.len());
+        self.draw_text(("Clock: ".to_string() + &clock_str[..lim] + " Hz   ").as_str(), panel.clock.0, panel.clock.1, Color::WHITE);
+
+        let (x, y) = panel.regs;
+        self.draw_text(("X:  ".to_string() + &format!("{:02X}", self.cpu.x)).as_str(), x, y, Color::WHITE);
+        self.draw_text(("Y:  ".to_string() + &format!("{:02X}", self.cpu.y)).as_str(), x, y + 1, Color::WHITE);
+
+        self.draw_text(("A:  ".to_string() + &format!("{:02X}", self.cpu.a)).as_str(), x, y + 3, Color::WHITE);
+        self.draw_text(("SP: ".to_string() + &format!("{:02X}", self.cpu.sp)).as_str(), x, y + 4, Color::WHITE);
+
+        let (x, y) = panel.ports;
+        self.draw_text(("PORTA:  ".to_string() + &format!("{:02X}", (*self.mapper.borrow()).int_adapter.port_a)).as_str(), x, y, Color::WHITE);
+        self.draw_text(("PORTB:  ".to_string() + &format!("{:02X}", (*self.mapper.borrow()).int_adapter.port_b)).as_str(), x, y + 1, Color::WHITE);
+
+        self.draw_text(("MOUSEX: ".to_string() + &format!("{:02X}", (*self.mapper.borrow()).int_adapter.mouse_x)).as_str(), x, y + 3, Color::WHITE);
+        self.draw_text(("MOUSEY: ".to_string() + &format!("{:02X}", (*self.mapper.borrow()).int_adapter.mouse_y)).as_str(), x, y + 4, Color::WHITE);
+
+        let (x, y) = panel.adapter;
+        self.draw_text(("KEYB:   ".to_string() + &format!("{:02X}", (*self.mapper.borrow()).int_adapter.keyb)).as_str(), x, y, Color::WHITE);
+        self.draw_text(("INTID:  ".to_string() + &format!("{:02X}", (*self.mapper.borrow()).int_adapter.interrupt_id)).as_str(), x, y + 1, Color::WHITE);
+        
+        self.draw_text(("ROMPTR: ".to_string() + &format!("{:06X}", (*self.mapper.borrow()).int_adapter.rom_ptr)).as_str(), x, y + 3, Color::WHITE);
+
+        let (x, y) = panel.flags;
+        self.draw_text(("CF: ".to_string() + &format!("{:01X}", self.cpu.get_flag(cpu::CARRY_FLAG) as u8)).as_str(), x, y, Color::WHITE);
+        self.draw_text(("ZF: ".to_string() + &format!("{:01X}", self.cpu.get_flag(cpu::ZERO_FLAG) as u8)).as_str(), x, y + 1, Color::WHITE);
+        self.draw_text(("IF: ".to_string() + &format!("{:01X}", self.cpu.get_flag(cpu::IRQ_DISABLE_FLAG) as u8)).as_str(), x, y + 2, Color::WHITE);
+        self.draw_text(("DF: ".to_string() + &format!("{:01X}", self.cpu.get_flag(cpu::DEC_MODE_FLAG) as u8)).as_str(), x, y + 3, Color::WHITE);
+        
+        let (x, y) = panel.flags2;
+        self.draw_text(("BF: ".to_string() + &format!("{:01X}", self.cpu.get_flag(cpu::BREAK_FLAG) as u8)).as_str(), x, y, Color::WHITE);
+        self.draw_text(("VF: ".to_string() + &format!("{:01X}", self.cpu.get_flag(cpu::OVERFLOW_FLAG) as u8)).as_str(), x, y + 1, Color::WHITE);
+        self.draw_text(("NF: ".to_string() + &format!("{:01X}", self.cpu.get_flag(cpu::NEGATIVE_FLAG) as u8)).as_str(), x, y + 2, Color::WHITE);
+
+        let (frame_flags, frame_pc) = self.cpu.interrupt_frame();
+        self.draw_text(("RET: ".to_string() + &format!("{:04X} P: {:02X}", frame_pc, frame_flags)).as_str(), panel.ret.0, panel.ret.1, Color::WHITE);
+
+        let (instruction, _) = disasm::disassemble(&*self.mapper.borrow(), self.cpu.pc);
+        self.draw_text(&format!("NEXT: {:04X} {:<16}", self.cpu.pc, instruction), panel.disasm.0, panel.disasm.1, Color::WHITE);
+
+        let label = match self.symbols.lookup(self.cpu.pc) {
+            Some((name, 0))      => name.to_string(),
+            Some((name, offset)) => format!("{}+{:X}", name, offset),
+            None                 => String::new()
+        };
+        let label: String = label.chars().take(debug_panel::LABEL_WIDTH).collect();
+        self.draw_text(&format!("      {:<1$}", label, debug_panel::LABEL_WIDTH), panel.disasm.0, panel.disasm.1 + 1, Color::WHITE);
+
+        let (x, y) = panel.memory;
+        for i in 0 .. debug_panel::MEMORY_ROWS {
+            self.memoryrow((self.cpu.pc & 0xfff0) + (i as u16 * 0x10), x, y + i);
+        }
+    }
+
+    fn reset(&mut self) {
+        if self.reset_adapter {
+            (*self.mapper.borrow_mut()).int_adapter.reset();
+        }
+
+        self.cpu.reset();
+    }
+
+    fn print_diff(&self) {
+        let snapshot = match &self.snapshot {
+            Some(snapshot) => snapshot,
+            None => {
+                println!("No memory snapshot taken");
+                return;
+            }
+        };
+
+        let changes = (*self.mapper.borrow()).diff(snapshot);
+        println!("{} bytes changed since the last snapshot", changes.len());
+
+        for (addr, old, new) in changes {
+            println!("{:04X}: {:02X} -> {:02X}", addr, old, new);
+        }
+    }
+
+    fn print_branch_stats(&self) {
+        match &self.cpu.branch_stats {
+            Some(stats) => print_branch_stats(stats),
+            None => println!("Branch statistics are disabled, run with --branch-stats")
+        }
+    }
+
+    fn is_hotkey(&self, key: Option<VirtualKeyCode>) -> bool {
+        return match key {
+            Some(
+                VirtualKeyCode::F2 | VirtualKeyCode::F3 |
+                VirtualKeyCode::F5 | VirtualKeyCode::F6 | VirtualKeyCode::F7 | VirtualKeyCode::F8 |
+                VirtualKeyCode::F9 | VirtualKeyCode::F10 | VirtualKeyCode::F11 | VirtualKeyCode::F12
+            ) => true,
+            Some(key) => self.ctrl && flag_hotkey(key).is_some(),
+            None => false
+        };
+    }
+
+    #[cfg(feature = "serde")]
+    fn step_back(&mut self) {
+        if self.rewind() {
+            println!("Rewound to {:04X}", self.cpu.pc);
+        } else {
+            println!("No earlier state to rewind to");
+        }
+    }
+
+    #[cfg(not(feature = "serde"))]
+    fn step_back(&mut self) {
+        println!("Rewinding needs the serde feature");
+    }
+
+    #[cfg(feature = "serde")]
+    fn save_state_file(&mut self) {
+        if self.state_file.as_str() == "none" {
+            println!("No --state-file to save to");
+        } else if let Err(err) = self.save_state(&self.state_file) {
+            println!("Couldn't save state to \"{}\": {}", self.state_file, err);
+        } else {
+            println!("State saved to \"{}\"", self.state_file);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    fn load_state_file(&mut self) {
+        if self.state_file.as_str() == "none" {
+            println!("No --state-file to load from");
+        } else if let Err(err) = self.load_state(&self.state_file.clone()) {
+            println!("{}", EmuError::StateLoad(self.state_file.clone(), err));
+        } else {
+            println!("State loaded from \"{}\", PC {:04X}", self.state_file, self.cpu.pc);
+        }
+    }
+
+    #[cfg(not(feature = "serde"))]
+    fn save_state_file(&mut self) {
+        println!("Save states need the serde feature");
+    }
+
+    #[cfg(not(feature = "serde"))]
+    fn load_state_file(&mut self) {
+        println!("Save states need the serde feature");
+    }
+
+    fn hotkey(&mut self, key: VirtualKeyCode) {
+        if key == VirtualKeyCode::F2 {
+            self.save_state_file();
+        } else if key == VirtualKeyCode::F3 {
+            self.load_state_file();
+        } else if key == VirtualKeyCode::F5 {
+            self.reset();
+        } else if key == VirtualKeyCode::F6 {
+            self.cpu.non_maskable_interrupt_request();
+        } else if key == VirtualKeyCode::F7 {
+            self.paused  = !self.paused;
+            self.resumed = !self.paused;
+            println!("{}", if self.paused { "Paused" } else { "Resumed" });
+        } else if key == VirtualKeyCode::F8 {
+            if self.paused {
+                self.step();
+            }
+        } else if key == VirtualKeyCode::F9 {
+            self.snapshot = Some((*self.mapper.borrow()).snapshot());
+            println!("Memory snapshot taken");
+        } else if key == VirtualKeyCode::F10 {
+            self.print_diff();
+        } else if key == VirtualKeyCode::F11 {
+            self.print_branch_stats();
+        } else if key == VirtualKeyCode::F12 {
+            self.step_back();
+        } else if let Some(flag) = flag_hotkey(key) {
+            self.cpu.set_flag(flag, !self.cpu.get_flag(flag));
+        }
+
+        // refresh the overlay
+        (*self.mapper.borrow_mut()).fbuf_changed = true;
+    }
+
+    // the whole frame goes up as one texture, scaled to the viewport
+    fn draw_frame(&self, graphics: &mut Graphics2D) {
+        let view  = self.viewport;
+        let width = self.ppu.width() as u32;
+
+        let (pixels, height) = if self.crt {
+            (crt_image(self.ppu.present(), width as usize), self.ppu.height() as u32 * 2)
+        } else {
+            (self.ppu.present().to_vec(), self.ppu.height() as u32)
+        };
+
+        let image = match graphics.create_image_from_raw_pixels(
+            ImageDataType::RGBA, ImageSmoothingMode::NearestNeighbor, (width, height), &pixels
+        ) {
+            Ok(image) => image,
+            Err(err) => {
+                println!("Couldn't upload the frame: {}", err);
+                return;
+            }
+        };
+
+        graphics.draw_rectangle_image(Rectangle::from_tuples(
+            (view.offset_x, view.offset_y),
+            (view.offset_x + self.ppu.width() as f32 * view.scale_x, view.offset_y + self.ppu.height() as f32 * view.scale_y)
+        ), &image);
+    }
+
+    fn record_frame(&mut self) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            let done = match recorder.capture(&self.ppu.frame_buf) {
+                Ok(()) => recorder.finished(),
+                Err(err) => {
+                    println!("{}", err);
+                    true
+                }
+            };
+
+            if done {
+                // dropping the encoder writes the gif trailer
+                self.recorder = None;
+                println!("GIF recording finished");
+            }
+        }
+    }
+
+    // runs one instruction, pausing on errors and register watches
+    fn step(&mut self) -> u8 {
+        (*self.mapper.borrow_mut()).int_adapter.deliver_pending();
+
+        let before = (self.cpu.a, self.cpu.x, self.cpu.y);
+        let cycles = match self.cpu.tick() {
+            Ok(cycles) => cycles,
+            Err(err) => {
+                // an illegal opcode is hit again on resume, F5 resets. bus faults carry on
+                self.pause(&err.to_string());
+                err.cycles()
+            }
+        };
+
+        (*self.mapper.borrow_mut()).int_adapter.tick_timer(cycles);
+        (*self.mapper.borrow_mut()).advance_beam(cycles);
+
+        if let Some((reg, value)) = self.watch.hit(before, &self.cpu) {
+            self.pause(&format!("{} became {:02X} at {:04X}", reg, value, self.cpu.pc));
+        }
+
+        return cycles;
+    }
+
+    fn pace(&mut self, cycles: u64) {
+        if self.target_hz == 0 {
+            return;
+        }
+
+        self.pace_cycles += cycles;
+
+        let elapsed = self.pace_start.elapsed();
+        let behind  = elapsed.saturating_sub(Duration::from_secs_f64(self.pace_cycles as f64 / self.target_hz as f64));
+
+        if self.paused || behind > MAX_PACING_LAG {
+            self.pace_start  = Instant::now();
+            self.pace_cycles = 0;
+            return;
+        }
+
+        sleep(pacing_delay(self.target_hz, elapsed, self.pace_cycles));
+    }
+
+    fn take_fbuf_changed(&mut self) -> bool {
+        if (*self.mapper.borrow()).fbuf_changed {
+            (*self.mapper.borrow_mut()).fbuf_changed = false;
+            self.changed_cnt += 1;
+            return true;
+        }
+
+        return false;
+    }
+
+    pub fn breakpoints_mut(&mut self) -> &mut HashSet<u16> {
+        return &mut self.breakpoints;
+    }
+
+    fn pause(&mut self, reason: &str) {
+        println!("{}, paused", reason);
+        self.paused = true;
+
+        // refresh the overlay
+        (*self.mapper.borrow_mut()).fbuf_changed = true;
+    }
+
+    fn register_input(&mut self) {
+        self.last_input    = Instant::now();
+        self.idle_signaled = false;
+    }
+
+    fn check_idle(&mut self) {
+        if idle_due(self.do_idle, self.idle_signaled, self.last_input.elapsed(), self.idle_timeout) {
+            // signaled once per idle period, the next input event rearms it
+            self.idle_signaled = true;
+            (*self.mapper.borrow_mut()).int_adapter.queue_interrupt(interface_adapter::IDLE);
+        }
+    }
+}
+
+// window coordinates to what the mouse registers report: internal resolution
+// pixels in 16 bit mode, for sub-cell precision, text cells otherwise.
+// both are clamped to the display, positions in the letterbox count as its edge
+fn mouse_position(position: (f32, f32), view: &Viewport, ppu: &ppu::PPU, pixels: bool) -> (u16, u16) {
+    let x = ((position.0 - view.offset_x) / view.scale_x).max(0.0);
+    let y = ((position.1 - view.offset_y) / view.scale_y).max(0.0);
+
+    if pixels {
+        return (
+            cmp::min(x as u16, ppu.width()  - 1),
+            cmp::min(y as u16, ppu.height() - 1)
+        );
+    }
+
+    let (columns, rows) = ppu.text_size();
+    return (
+        cmp::min((x / ppu::CHAR_X as f32) as u16, columns as u16 - 1),
+        cmp::min((y / ppu.glyph_height() as f32) as u16, rows as u16 - 1)
+    );
+}
+
+fn breakpoint_hit(breakpoints: &HashSet<u16>, pc: u16) -> bool {
+    return breakpoints.contains(&pc);
+}
+
+// pauses execution when a register changes to the given value
+struct RegisterWatch {
+    a: Option<u8>,
+    x: Option<u8>,
+    y: Option<u8>
+}
+
+impl RegisterWatch {
+    // only the transition counts, so resuming doesn't stop again straight away
+    fn hit(&self, before: (u8, u8, u8), cpu: &cpu::CPU) -> Option<(&'static str, u8)> {
+        let regs = [
+            ("A", self.a, before.0, cpu.a),
+            ("X", self.x, before.1, cpu.x),
+            ("Y", self.y, before.2, cpu.y)
+        ];
+
+        for (name, target, old, new) in regs {
+            if target == Some(new) && old != new {
+                return Some((name, new));
+            }
+        }
+
+        return None;
+    }
+}
+
+// busiest branch sites first
+fn print_branch_stats(stats: &HashMap<u16, cpu::BranchStats>) {
+    let mut sites: Vec<(&u16, &cpu::BranchStats)> = stats.iter().collect();
+    sites.sort_by_key(|(&addr, site)| (cmp::Reverse(site.taken + site.not_taken), addr));
+
+    println!("{} branch sites", sites.len());
+    for (addr, site) in sites {
+        println!("{:04X}: taken {}, not taken {}", addr, site.taken, site.not_taken);
+    }
+}
+
+// CRT look: each pixel bleeds a little into its neighbours,
+// and is drawn as a lit line over a darkened scanline
+const CRT_GLOW    : f32 = 0.15;
+const CRT_SCANLINE: f32 = 0.55;
+
+fn crt_colors(row: &[u8], x: usize) -> ([u8; 4], [u8; 4]) {
+    let width = row.len() / 4;
+    let left  = x.saturating_sub(1);
+    let right = cmp::min(x + 1, width - 1);
+
+    let mut lit  = [0xff; 4];
+    let mut dark = [0xff; 4];
+    for c in 0 .. 3 {
+        let glow = row[x * 4 + c] as f32 * (1.0 - CRT_GLOW) + (row[left * 4 + c] as f32 + row[right * 4 + c] as f32) / 2.0 * CRT_GLOW;
+
+        lit[c]  = glow as u8;
+        dark[c] = (glow * CRT_SCANLINE) as u8;
+    }
+
+    return (lit, dark);
+}
+
+// every pixel row becomes a lit row over a dark scanline
+fn crt_image(rgba: &[u8], width: usize) -> Vec<u8> {
+    let mut image = Vec::with_capacity(rgba.len() * 2);
+
+    for row in rgba.chunks_exact(width * 4) {
+        let (lit, dark): (Vec<[u8; 4]>, Vec<[u8; 4]>) = (0 .. width).map(|x| crt_colors(row, x)).unzip();
+
+        image.extend(lit.iter().flatten());
+        image.extend(dark.iter().flatten());
+    }
+
+    return image;
+}
+
+// the arrow keys and space also drive the joystick register
+fn joystick_bit(key: VirtualKeyCode) -> u8 {
+    return match key {
+        VirtualKeyCode::Up    => interface_adapter::JOY_UP,
+        VirtualKeyCode::Down  => interface_adapter::JOY_DOWN,
+        VirtualKeyCode::Left  => interface_adapter::JOY_LEFT,
+        VirtualKeyCode::Right => interface_adapter::JOY_RIGHT,
+        VirtualKeyCode::Space => interface_adapter::JOY_FIRE,
+        _ => 0
+    };
+}
+
+// Ctrl + the flag's letter toggles it
+fn flag_hotkey(key: VirtualKeyCode) -> Option<u8> {
+    return match key {
+        VirtualKeyCode::C => Some(cpu::CARRY_FLAG),
+        VirtualKeyCode::Z => Some(cpu::ZERO_FLAG),
+        VirtualKeyCode::I => Some(cpu::IRQ_DISABLE_FLAG),
+        VirtualKeyCode::D => Some(cpu::DEC_MODE_FLAG),
+        VirtualKeyCode::B => Some(cpu::BREAK_FLAG),
+        VirtualKeyCode::V => Some(cpu::OVERFLOW_FLAG),
+        VirtualKeyCode::N => Some(cpu::NEGATIVE_FLAG),
+        _ => None
+    };
 }
 
 impl WindowHandler for Emu {
     fn on_draw(&mut self, helper: &mut WindowHelper, graphics: &mut Graphics2D) {
         let cpu_time = self.timer.elapsed().as_secs_f32();
-        let mut changed = false;
+        let mut changed  = false;
+        let mut rendered = false;
+        let mut cycles   = 0;
 
         for _ in 0 .. self.ticks {
-            self.cpu.tick();
+            // nothing but a reset wakes a stopped CPU, so don't spin on it
+            if self.paused || self.cpu.run_state == cpu::RunState::Stopped {
+                break;
+            }
+
+            // the breakpoint we were resumed from doesn't stop us again
+            if !self.resumed && breakpoint_hit(&self.breakpoints, self.cpu.pc) {
+                self.pause(&format!("Breakpoint at {:04X}", self.cpu.pc));
+                break;
+            }
+            self.resumed = false;
+
+            let taken = self.step();
+            cycles += taken as u64;
 
-            if (*self.mapper.borrow()).fbuf_changed {
-                (*self.mapper.borrow_mut()).fbuf_changed = false;
-                self.changed_cnt += 1;
+            if self.take_fbuf_changed() {
                 changed = true;
             }
+
+            // rendering twice without running anything in between changes nothing
+            if ppu_ticks_due(&mut self.ppu_cnt, self.ppu_every, taken) > 0 {
+                self.ppu.tick();
+                rendered = true;
+            }
         }
         
-        if self.frame == self.update_each && self.update_each != 0xffff {
-            self.frame = 0;
+        // pausing and single stepping change the overlay outside the loop
+        if self.paused && self.take_fbuf_changed() {
             changed = true;
         }
 
-        if changed {
-            self.ppu.tick();
-
-            if self.changed_cnt >= self.update_each_changed {
-                self.changed_cnt = 0;
+        self.check_idle();
 
-                let clock_str = (1.0 / (cpu_time / self.ticks as f32)).to_string();
-                let lim = cmp::min(32, clock_str.len());
-                self.draw_text(("Clock: ".to_string() + &clock_str[..lim] + " Hz   ").as_str(), 4, 34, Color::WHITE);
+        // a blinking cursor changes the display without the program writing anything
+        if self.ppu.blink() {
+            self.changed_cnt += 1;
+            changed = true;
+        }
 
-                self.draw_text(("X:  ".to_string() + &format!("{:02X}", self.cpu.x)).as_str(), 4, 36, Color::WHITE);
-                self.draw_text(("Y:  ".to_string() + &format!("{:02X}", self.cpu.y)).as_str(), 4, 37, Color::WHITE);
+        #[cfg(feature = "serde")]
+        if !self.paused {
+            self.rewind_tick();
+        }
 
-                self.draw_text(("A:  ".to_string() + &format!("{:02X}", self.cpu.a)).as_str(), 4, 39, Color::WHITE);
-                self.draw_text(("SP: ".to_string() + &format!("{:02X}", self.cpu.sp)).as_str(), 4, 40, Color::WHITE);
+        #[cfg(feature = "audio")]
+        if let Some(audio) = &self.audio {
+            audio.update((*self.mapper.borrow()).int_adapter.tone);
+        }
 
-                self.draw_text(("PORTA:  ".to_string() + &format!("{:02X}", (*self.mapper.borrow()).int_adapter.port_a)).as_str(), 13, 36, Color::WHITE);
-                self.draw_text(("PORTB:  ".to_string() + &format!("{:02X}", (*self.mapper.borrow()).int_adapter.port_b)).as_str(), 13, 37, Color::WHITE);
+        if self.frame == self.update_each && self.update_each != 0xffff {
+            self.frame = 0;
+            changed = true;
+        }
 
-                self.draw_text(("MOUSEX: ".to_string() + &format!("{:02X}", (*self.mapper.borrow()).int_adapter.mouse_x)).as_str(), 13, 39, Color::WHITE);
-                self.draw_text(("MOUSEY: ".to_string() + &format!("{:02X}", (*self.mapper.borrow()).int_adapter.mouse_y)).as_str(), 13, 40, Color::WHITE);
+        if changed || rendered {
+            if !rendered {
+                self.ppu.tick();
+            }
 
-                self.draw_text(("KEYB:   ".to_string() + &format!("{:02X}", (*self.mapper.borrow()).int_adapter.keyb)).as_str(), 25, 36, Color::WHITE);
-                self.draw_text(("INTID:  ".to_string() + &format!("{:02X}", (*self.mapper.borrow()).int_adapter.interrupt_id)).as_str(), 25, 37, Color::WHITE);
-                
-                self.draw_text(("ROMPTR: ".to_string() + &format!("{:06X}", (*self.mapper.borrow()).int_adapter.rom_ptr)).as_str(), 25, 39, Color::WHITE);
+            if self.changed_cnt >= self.update_each_changed || rendered {
+                self.changed_cnt = 0;
 
-                self.draw_text(("CF: ".to_string() + &format!("{:01X}", self.cpu.get_flag(cpu::CARRY_FLAG) as u8)).as_str(), 44, 36, Color::WHITE);
-                self.draw_text(("ZF: ".to_string() + &format!("{:01X}", self.cpu.get_flag(cpu::ZERO_FLAG) as u8)).as_str(), 44, 37, Color::WHITE);
-                self.draw_text(("IF: ".to_string() + &format!("{:01X}", self.cpu.get_flag(cpu::IRQ_DISABLE_FLAG) as u8)).as_str(), 44, 38, Color::WHITE);
-                self.draw_text(("DF: ".to_string() + &format!("{:01X}", self.cpu.get_flag(cpu::DEC_MODE_FLAG) as u8)).as_str(), 44, 39, Color::WHITE);
-                
-                self.draw_text(("BF: ".to_string() + &format!("{:01X}", self.cpu.get_flag(cpu::BREAK_FLAG) as u8)).as_str(), 52, 36, Color::WHITE);
-                self.draw_text(("VF: ".to_string() + &format!("{:01X}", self.cpu.get_flag(cpu::OVERFLOW_FLAG) as u8)).as_str(), 52, 37, Color::WHITE);
-                self.draw_text(("NF: ".to_string() + &format!("{:01X}", self.cpu.get_flag(cpu::NEGATIVE_FLAG) as u8)).as_str(), 52, 38, Color::WHITE);
+                self.draw_panel(cycles, cpu_time);
 
-                for i in 0 .. 7 {
-                    self.memoryrow((self.cpu.pc & 0xfff0) + (i * 0x10), 4, 43 + i as u8);
+                let view = self.viewport;
+                if view.offset_x != 0.0 || view.offset_y != 0.0 {
+                    graphics.clear_screen(Color::BLACK);
                 }
 
-                for y in 0 .. ppu::INTERNAL_RESOLUTION_Y {
-                    for x in 0 .. ppu::INTERNAL_RESOLUTION_X {
-                        let ix = (x * 2) as f32;
-                        let iy = (y * 2) as f32;
-        
-                        graphics.draw_quad(
-                            [
-                                Vector2::new(ix, iy), 
-                                Vector2::new(ix + 2.0, iy),
-                                Vector2::new(ix + 2.0, iy + 2.0), 
-                                Vector2::new(ix, iy + 2.0)
-                            ], 
-                            *self.ppu.frame_buf.get(y as usize).unwrap()
-                                .get(x as usize).unwrap()
-                        );
-                    }
-                }
+                self.draw_frame(graphics);
+
+                self.record_frame();
             }
         }
 
@@ -150,6 +700,8 @@ impl WindowHandler for Emu {
         if self.do_sleep {
             sleep(self.sleep);
         }
+
+        self.pace(cycles);
         
         helper.request_redraw();
     }
@@ -160,10 +712,16 @@ impl WindowHandler for Emu {
             virtual_key_code: Option<speedy2d::window::VirtualKeyCode>,
             scancode: speedy2d::window::KeyScancode
     ) {
-        (*self.mapper.borrow_mut()).int_adapter.keyb         = scancode as u8;
-        (*self.mapper.borrow_mut()).int_adapter.interrupt_id = interface_adapter::KEYDOWN;
+        if self.is_hotkey(virtual_key_code) {
+            self.hotkey(virtual_key_code.unwrap());
+            return;
+        }
+
+        self.register_input();
 
-        self.cpu.interrupt_request();
+        let adapter = &mut (*self.mapper.borrow_mut()).int_adapter;
+        adapter.joystick |= virtual_key_code.map_or(0, joystick_bit);
+        adapter.queue_key(scancode as u8, interface_adapter::KEYDOWN);
     }
 
     #[allow(unused)]
@@ -172,27 +730,42 @@ impl WindowHandler for Emu {
             virtual_key_code: Option<speedy2d::window::VirtualKeyCode>,
             scancode: speedy2d::window::KeyScancode
     ) {
-        (*self.mapper.borrow_mut()).int_adapter.keyb         = scancode as u8;
-        (*self.mapper.borrow_mut()).int_adapter.interrupt_id = interface_adapter::KEYUP;
+        if self.is_hotkey(virtual_key_code) {
+            return;
+        }
+
+        self.register_input();
 
-        self.cpu.interrupt_request();
+        let adapter = &mut (*self.mapper.borrow_mut()).int_adapter;
+        adapter.joystick &= !virtual_key_code.map_or(0, joystick_bit);
+        adapter.queue_key(scancode as u8, interface_adapter::KEYUP);
+    }
+
+    #[allow(unused)]
+    fn on_keyboard_modifiers_changed(&mut self, helper: &mut WindowHelper, state: ModifiersState) {
+        self.ctrl = state.ctrl();
     }
 
     #[allow(unused)]
     fn on_mouse_move(&mut self, helper: &mut WindowHelper, position: speedy2d::dimen::Vec2) {
-        (*self.mapper.borrow_mut()).int_adapter.mouse_x = (position.x / ppu::CHAR_X as f32) as u8;
-        (*self.mapper.borrow_mut()).int_adapter.mouse_y = (position.y / ppu::CHAR_Y as f32) as u8;
+        self.register_input();
+
+        let adapter = &mut (*self.mapper.borrow_mut()).int_adapter;
+        (adapter.mouse_x, adapter.mouse_y) = mouse_position(
+            (position.x, position.y), &self.viewport, &self.ppu, adapter.mouse_16bit
+        );
     }
 
     #[allow(unused)]
     fn on_mouse_button_down(&mut self, helper: &mut WindowHelper, button: speedy2d::window::MouseButton) {
+        self.register_input();
+        (*self.mapper.borrow_mut()).int_adapter.latch_click();
+
         match button {
-            MouseButton::Left  => (*self.mapper.borrow_mut()).int_adapter.interrupt_id = interface_adapter::MOUSE_LCLICK,
-            MouseButton::Right => (*self.mapper.borrow_mut()).int_adapter.interrupt_id = interface_adapter::MOUSE_RCLICK,
+            MouseButton::Left  => (*self.mapper.borrow_mut()).int_adapter.queue_interrupt(interface_adapter::MOUSE_LCLICK),
+            MouseButton::Right => (*self.mapper.borrow_mut()).int_adapter.queue_interrupt(interface_adapter::MOUSE_RCLICK),
             _ => {}
         }
-
-        self.cpu.interrupt_request();
     }
 }
 
@@ -204,41 +777,451 @@ struct Args {
     #[arg(short, long, default_value_t = String::from("none"))]
     cartridge: String,
 
+    #[arg(long, default_value_t = String::from("none"))]
+    o65: String,
+
+    #[arg(long, default_value_t = O65_BASE)]
+    o65_base: u16,
+
+    #[arg(long, default_value_t = String::from("none"))]
+    symbols: String,
+
+    #[arg(long, default_value_t = String::from("none"))]
+    image: String,
+
+    #[arg(long, value_parser = parse_address)]
+    start: Option<u16>,
+
+    #[arg(long)]
+    run_to_trap: bool,
+
+    #[arg(long, value_parser = parse_address)]
+    expect_trap: Option<u16>,
+
     #[arg(short, long, default_value_t = DEFAULT_DELAY)]
     delay: f32,
 
+    #[arg(long, default_value_t = 0)]
+    target_hz: u64,
+
     #[arg(long, default_value_t = UPDATE_EACH_CHANGED)]
     update_each_changed: u16,
 
     #[arg(long, default_value_t = FORCE_UPDATE_EACH)]
     update_each: u16,
 
+    #[arg(long, default_value_t = IDLE_TIMEOUT)]
+    idle_timeout: f32,
+
+    #[arg(long)]
+    warn_smc: bool,
+
+    #[arg(long)]
+    trap_uninit: bool,
+
+    #[arg(long)]
+    open_bus: bool,
+
+    #[arg(long, default_value_t = 0)]
+    fb_contention: u8,
+
+    #[arg(long)]
+    mouse_16bit: bool,
+
+    #[arg(long, default_value_t = 0)]
+    port_a_init: u8,
+
+    #[arg(long, default_value_t = 0)]
+    port_b_init: u8,
+
+    #[arg(long, default_value_t = PPU_EVERY)]
+    ppu_every: u32,
+
+    #[arg(long)]
+    keep_adapter_on_reset: bool,
+
+    #[arg(long, default_value_t = 0)]
+    bench: u64,
+
+    #[arg(long, default_value_t = 0)]
+    irq_latency: u64,
+
+    #[arg(long, default_value_t = 0.0)]
+    aspect: f32,
+
+    #[arg(long)]
+    crt: bool,
+
+    #[arg(long)]
+    branch_stats: bool,
+
+    #[arg(long)]
+    trap_bus_faults: bool,
+
+    #[arg(long)]
+    trace_adapter: bool,
+
+    #[arg(long, default_value_t = String::from("none"))]
+    trace: String,
+
+    #[arg(long)]
+    rng_seed: Option<u64>,
+
+    #[arg(long, value_parser = parse_address)]
+    breakpoint: Vec<u16>,
+
+    #[arg(long)]
+    break_on_a: Option<u8>,
+
+    #[arg(long)]
+    break_on_x: Option<u8>,
+
+    #[arg(long)]
+    break_on_y: Option<u8>,
+
+    #[arg(long, default_value_t = String::from("none"))]
+    record_gif: String,
+
+    #[arg(long, default_value_t = RECORD_FRAMES)]
+    record_frames: u32,
+
+    #[arg(long, value_parser = parse_range)]
+    ram: Option<(u16, u16)>,
+
+    #[arg(long, value_parser = parse_range)]
+    rom: Option<(u16, u16)>,
+
+    #[arg(long, value_parser = parse_address)]
+    io_base: Option<u16>,
+
+    #[arg(long)]
+    banked_rom: bool,
+
+    #[cfg(feature = "serde")]
+    #[arg(long, default_value_t = REWIND_EVERY)]
+    rewind_every: u32,
+
+    #[cfg(feature = "serde")]
+    #[arg(long, default_value_t = REWIND_DEPTH)]
+    rewind_depth: usize,
+
+    #[cfg(feature = "serde")]
+    #[arg(long, default_value_t = String::from("none"))]
+    state_file: String,
+
     file: String
 }
 
-fn main() {
-    let args = Args::parse();
+// accepts decimal, or hex with a 0x or $ prefix
+fn parse_address(arg: &str) -> Result<u16, String> {
+    let parsed = if let Some(hex) = arg.strip_prefix("0x").or_else(|| arg.strip_prefix('$')) {
+        u16::from_str_radix(hex, 16)
+    } else {
+        arg.parse::<u16>()
+    };
 
-    let map = Rc::new(RefCell::new(mapper::Map::new(args.file.as_str())));
+    return parsed.map_err(|err| format!("invalid address \"{}\": {}", arg, err));
+}
+
+// START-END, both inclusive
+fn parse_range(arg: &str) -> Result<(u16, u16), String> {
+    let (start, end) = arg.split_once('-').ok_or_else(|| format!("invalid range \"{}\", expected START-END", arg))?;
+    return Ok((parse_address(start)?, parse_address(end)?));
+}
+
+fn duration_arg(name: &str, secs: f32) -> Result<(Duration, bool), EmuError> {
+    if secs < 0.0 || !secs.is_finite() {
+        return Err(EmuError::InvalidConfig(format!("--{} must be a positive amount of seconds", name)));
+    }
+
+    if secs == 0.0 {
+        return Ok((Duration::from_secs_f32(0.0), false));
+    }
+
+    return Ok((Duration::from_secs_f32(secs), true));
+}
+
+// the idle interrupt fires once `timeout` passes without input, once per idle period
+fn idle_due(enabled: bool, signaled: bool, idle_for: Duration, timeout: Duration) -> bool {
+    return enabled && !signaled && idle_for >= timeout;
+}
+
+// how long to wait so `cycles` take as long as they would at `target_hz`,
+// zero when already behind or uncapped
+fn pacing_delay(target_hz: u64, elapsed: Duration, cycles: u64) -> Duration {
+    if target_hz == 0 {
+        return Duration::ZERO;
+    }
+
+    return Duration::from_secs_f64(cycles as f64 / target_hz as f64).saturating_sub(elapsed);
+}
+
+// how often the PPU ticks in `cycles` more cycles with one tick every `every`
+// cycles, `counter` carries the cycles left over. 0 turns the cadence off
+fn ppu_ticks_due(counter: &mut u32, every: u32, cycles: u8) -> u32 {
+    if every == 0 {
+        return 0;
+    }
+
+    *counter += cycles as u32;
+    let due = *counter / every;
+    *counter %= every;
+
+    return due;
+}
+
+fn per_second(count: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs == 0.0 {
+        return 0.0;
+    }
+
+    return count as f64 / secs;
+}
+
+// runs the loaded program headlessly and reports the achieved speed
+fn bench(cpu: &mut cpu::CPU, instructions: u64) {
+    let start = Instant::now();
+    let result  = cpu.run_until(|cpu| cpu.run_state == cpu::RunState::Stopped, instructions as usize);
+    let elapsed = start.elapsed();
+
+    let steps = match result {
+        Ok(steps) => steps,
+        Err(err) => {
+            println!("{}", err);
+            return;
+        }
+    };
+
+    println!(
+        "Ran {} instructions ({} cycles) in {:.3} s, {:.0} Hz",
+        steps, cpu.cycles, elapsed.as_secs_f64(), per_second(cpu.cycles, elapsed)
+    );
+
+    if let Some(stats) = &cpu.branch_stats {
+        print_branch_stats(stats);
+    }
+}
+
+// asserts the IRQ line after the given amount of instructions, then measures
+// how long it takes until the handler's first instruction is reached:
+// (cycles, instructions that ran before the interrupt sequence)
+fn measure_irq_latency(cpu: &mut cpu::CPU, map: &Rc<RefCell<mapper::Map>>, instructions: u64) -> Result<Option<(u64, u64)>, StepError> {
+    cpu.run_until(|_| false, instructions as usize)?;
+
+    let handler = (*map.borrow()).read_word(cpu::INTERRUPT_VECTOR);
+    (*map.borrow_mut()).int_adapter.queue_interrupt(interface_adapter::LATENCY_TEST);
+
+    let start = cpu.cycles;
+    for waited in 0 .. LATENCY_BUDGET {
+        let taken = cpu.tick()?;
+
+        // the interrupt sequence is a step of its own landing on the handler
+        if cpu.pc == handler && taken == cpu::INTERRUPT_CYCLES {
+            return Ok(Some((cpu.cycles - start, waited)));
+        }
+    }
+
+    return Ok(None);
+}
+
+fn irq_latency(cpu: &mut cpu::CPU, map: &Rc<RefCell<mapper::Map>>, instructions: u64) -> Result<(), StepError> {
+    match measure_irq_latency(cpu, map, instructions)? {
+        Some((cycles, waited)) => println!(
+            "IRQ serviced after {} cycles, {} instructions ran before the interrupt sequence", cycles, waited
+        ),
+        None => println!("IRQ not serviced within {} instructions, are interrupts disabled?", LATENCY_BUDGET)
+    }
+
+    return Ok(());
+}
+
+// test suites signal their result by jumping or branching to the instruction
+// itself, so a step that leaves PC where it was ends the run
+fn run_to_trap(cpu: &mut cpu::CPU) -> Result<Option<u16>, StepError> {
+    for _ in 0 .. TRAP_BUDGET {
+        let pc = cpu.pc;
+        cpu.tick()?;
+
+        if cpu.pc == pc && cpu.run_state == cpu::RunState::Running {
+            return Ok(Some(pc));
+        }
+    }
+
+    return Ok(None);
+}
+
+// a whole memory image starting at 0000, written over RAM and ROM alike
+fn load_memory_image(map: &Rc<RefCell<mapper::Map>>, filename: &str) -> Result<(), EmuError> {
+    let mut image = fs::read(filename).map_err(|err| EmuError::ImageLoad(filename.to_string(), err))?;
+    image.truncate(0x10000);
+
+    map.borrow_mut().load_image(&image, 0);
+    return Ok(());
+}
+
+// relocates the object to `base` and points the reset vector at its text segment
+fn load_o65(map: &Rc<RefCell<mapper::Map>>, filename: &str, base: u16) -> Result<(), EmuError> {
+    let object = fs::read(filename)
+        .and_then(|bytes| o65::load(&bytes, base))
+        .map_err(|err| EmuError::ObjectLoad(filename.to_string(), err))?;
+
+    let mut map = map.borrow_mut();
+    map.load_image(&object.text, object.text_base);
+    map.load_image(&object.data, object.data_base);
+    map.load_image(&object.text_base.to_le_bytes(), 0xfffc);
+
+    return Ok(());
+}
+
+fn run(args: Args) -> Result<(), EmuError> {
+    let layout = mapper::MemoryMap {
+        ram:     args.ram.unwrap_or(mapper::MemoryMap::DEFAULT.ram),
+        rom:     args.rom.unwrap_or(mapper::MemoryMap::DEFAULT.rom),
+        io_base: args.io_base.unwrap_or(mapper::MemoryMap::DEFAULT.io_base),
+
+        banked_rom: args.banked_rom
+    };
+    let map    = Rc::new(RefCell::new(mapper::Map::new(args.file.as_str(), layout)?));
 
     if args.cartridge.as_str() != "none" {
-        (*map.borrow_mut()).int_adapter.load_cartridge(args.cartridge.as_str());
+        (*map.borrow_mut()).int_adapter.load_cartridge(args.cartridge.as_str())?;
+    }
+
+    if args.o65.as_str() != "none" {
+        load_o65(&map, args.o65.as_str(), args.o65_base)?;
+    }
+
+    if args.image.as_str() != "none" {
+        load_memory_image(&map, args.image.as_str())?;
+    }
+
+    if args.trace_adapter {
+        let io_base = layout.io_base;
+
+        (*map.borrow_mut()).set_trace(Box::new(move |event: mapper::BusEvent| {
+            if event.address.wrapping_sub(io_base) <= 0xf {
+                let access = if event.access == mapper::BusAccess::Read { "read" } else { "write" };
+                println!("Adapter {} {:04X}: {:02X}", access, event.address, event.value);
+            }
+        }));
     }
 
-    let mut cpu = cpu::CPU::new(Rc::clone(&map));
+    (*map.borrow_mut()).warn_smc = args.warn_smc;
+    (*map.borrow_mut()).trap_uninit = args.trap_uninit;
+    (*map.borrow_mut()).open_bus = args.open_bus;
+    (*map.borrow_mut()).contention = args.fb_contention;
+    (*map.borrow_mut()).int_adapter.mouse_16bit = args.mouse_16bit;
+    (*map.borrow_mut()).int_adapter.set_port_defaults(args.port_a_init, args.port_b_init);
+
+    // a different sequence every run unless a seed is given
+    let seed = args.rng_seed.unwrap_or_else(|| {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos() as u64)
+    });
+    (*map.borrow_mut()).int_adapter.seed_rng(seed);
+
+    let mut cpu = cpu::CPU::new(map.clone());
     cpu.reset();
 
-    let (delay, do_sleep) = if args.delay == 0.0 { 
-        (Duration::from_secs_f32(0.0), false)
+    if let Some(start) = args.start {
+        cpu.pc = start;
+    }
+
+    if args.branch_stats {
+        cpu.branch_stats = Some(HashMap::new());
+    }
+
+    cpu.trap_bus_faults = args.trap_bus_faults;
+
+    // line buffered, the window never returns to flush a bigger buffer
+    if args.trace.as_str() == "-" {
+        cpu.trace = Some(Box::new(io::stdout()));
+    } else if args.trace.as_str() != "none" {
+        let file = fs::File::create(args.trace.as_str())
+            .map_err(|err| EmuError::TraceFile(args.trace.clone(), err))?;
+        cpu.trace = Some(Box::new(io::LineWriter::new(file)));
+    }
+
+    if args.bench != 0 {
+        bench(&mut cpu, args.bench);
+        return Ok(());
+    }
+
+    if args.run_to_trap || args.expect_trap.is_some() {
+        let trap = match run_to_trap(&mut cpu) {
+            Ok(trap) => trap,
+            Err(err) => {
+                println!("{}", err);
+                process::exit(1);
+            }
+        };
+
+        match trap {
+            Some(pc) => println!("Trapped at {:04X} after {} cycles", pc, cpu.cycles),
+            None     => println!("No trap within {} instructions, last PC {:04X}", TRAP_BUDGET, cpu.pc)
+        }
+
+        if args.expect_trap.is_some() && trap != args.expect_trap {
+            process::exit(1);
+        }
+
+        return Ok(());
+    }
+
+    if args.irq_latency != 0 {
+        if let Err(err) = irq_latency(&mut cpu, &map, args.irq_latency) {
+            println!("{}", err);
+        }
+
+        return Ok(());
+    }
+
+    let (delay, do_sleep)       = duration_arg("delay", args.delay)?;
+    let (idle_timeout, do_idle) = duration_arg("idle-timeout", args.idle_timeout)?;
+
+    if args.aspect < 0.0 || !args.aspect.is_finite() {
+        return Err(EmuError::InvalidConfig("--aspect must be a positive width / height ratio".to_string()));
+    }
+
+    let symbols = if args.symbols.as_str() != "none" {
+        symbols::Symbols::load(args.symbols.as_str())?
     } else {
-        (Duration::from_secs_f32(args.delay), true)
+        symbols::Symbols::default()
     };
 
-    let emu = Emu {
+    let ppu   = ppu::PPU::new_default(Rc::clone(&map), "charset.bin")?;
+    let panel = debug_panel::DebugPanel::layout(&ppu);
+
+    let recorder = if args.record_gif.as_str() != "none" {
+        Some(recorder::GifRecorder::new(args.record_gif.as_str(), args.record_frames, &ppu)?)
+    } else {
+        None
+    };
+
+    let mut emu = Emu {
         mapper: Rc::clone(&map), cpu, ticks: args.ticks, update_each_changed: args.update_each_changed,
-        timer: Instant::now(), frame: 0, sleep: delay, do_sleep, changed_cnt: 0, update_each: args.update_each,
-        ppu: ppu::PPU::new(Rc::clone(&map), "charset.bin")
+        timer: Instant::now(), frame: 0, sleep: delay, do_sleep, changed_cnt: 0,
+        target_hz: args.target_hz, pace_start: Instant::now(), pace_cycles: 0, update_each: args.update_each,
+        ppu,
+        do_idle, idle_timeout, idle_signaled: false, last_input: Instant::now(),
+        ppu_every: args.ppu_every, ppu_cnt: 0, reset_adapter: !args.keep_adapter_on_reset, ctrl: false,
+        paused: false, resumed: false, breakpoints: HashSet::new(),
+        watch: RegisterWatch { a: args.break_on_a, x: args.break_on_x, y: args.break_on_y },
+        viewport: Viewport::new(args.aspect), crt: args.crt, snapshot: None, symbols, recorder, panel,
+        #[cfg(feature = "audio")]
+        audio: audio::ToneOutput::open(),
+        #[cfg(feature = "serde")]
+        rewind: state::Rewind::new(args.rewind_every, args.rewind_depth),
+        #[cfg(feature = "serde")]
+        state_file: args.state_file.clone()
     };
+    emu.breakpoints_mut().extend(args.breakpoint);
+
+    // a missing state file is created by the first save
+    #[cfg(feature = "serde")]
+    if args.state_file.as_str() != "none" && fs::metadata(&args.state_file).is_ok() {
+        emu.load_state(&args.state_file).map_err(|err| EmuError::StateLoad(args.state_file.clone(), err))?;
+    }
 
     let window = Window::new_with_options("6502 computer emulator", 
         WindowCreationOptions::new_windowed(
@@ -253,3 +1236,230 @@ fn main() {
 
     window.run_loop(emu);
 }
+
+fn main() {
+    if let Err(err) = run(Args::parse()) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ppu_cadence_counts_cycles() {
+        let mut counter = 0;
+        let ticks: u32 = (0 .. 25).map(|_| ppu_ticks_due(&mut counter, 10, 4)).sum();
+
+        // 100 cycles of 4 cycle instructions
+        assert_eq!(ticks, 10);
+        assert_eq!(counter, 0);
+    }
+
+    #[test]
+    fn ppu_cadence_carries_leftover_cycles() {
+        let mut counter = 0;
+
+        assert_eq!(ppu_ticks_due(&mut counter, 5, 3), 0);
+        assert_eq!(ppu_ticks_due(&mut counter, 5, 3), 1);
+        assert_eq!(counter, 1);
+        assert_eq!(ppu_ticks_due(&mut counter, 0, 7), 0);
+    }
+
+    // needs Klaus Dormann's prebuilt binary, see the README:
+    // FUNCTIONAL_TEST_BIN=path/to/6502_functional_test.bin cargo test -- --ignored
+    #[test]
+    #[ignore]
+    fn functional_test_traps_at_success() {
+        let path  = std::env::var("FUNCTIONAL_TEST_BIN").expect("FUNCTIONAL_TEST_BIN isn't set");
+        let image = fs::read(&path).unwrap();
+
+        let map = Rc::new(RefCell::new(mapper::Map::from_reader(&image[..], mapper::MemoryMap::DEFAULT).unwrap()));
+        load_memory_image(&map, &path).unwrap();
+
+        let mut cpu = cpu::CPU::new(map.clone());
+        cpu.reset();
+        cpu.pc = 0x400;
+
+        // run_to_trap gives up after TRAP_BUDGET instructions
+        assert_eq!(run_to_trap(&mut cpu).unwrap(), Some(0x3469));
+    }
+
+    #[test]
+    fn idle_fires_once_after_the_timeout() {
+        let timeout = Duration::from_secs(30);
+
+        assert!(!idle_due(true, false, Duration::from_secs(29), timeout));
+        assert!(idle_due(true, false, Duration::from_secs(30), timeout));
+        assert!(!idle_due(true, true, Duration::from_secs(90), timeout));
+        assert!(!idle_due(false, false, Duration::from_secs(90), timeout));
+    }
+
+    #[test]
+    fn zero_idle_timeout_turns_it_off() {
+        assert_eq!(duration_arg("idle-timeout", 0.0).unwrap(), (Duration::ZERO, false));
+        assert_eq!(duration_arg("idle-timeout", 1.5).unwrap(), (Duration::from_millis(1500), true));
+        assert!(duration_arg("idle-timeout", -1.0).is_err());
+    }
+
+
+    fn test_ppu() -> ppu::PPU {
+        let map = mapper::Map::from_reader(&vec![0; 0x8000][..], mapper::MemoryMap::DEFAULT).unwrap();
+        return ppu::PPU::new_default(Rc::new(RefCell::new(map)), "charset.bin").unwrap();
+    }
+
+    #[test]
+    fn mouse_pixels_pack_into_16_bit_registers() {
+        let ppu  = test_ppu();
+        let view = Viewport::new(0.0);
+
+        // the window is twice the internal resolution
+        let (x, y) = mouse_position((601.0, 401.0), &view, &ppu, true);
+        assert_eq!((x, y), (300, 200));
+
+        let mut adapter = interface_adapter::Adapter::new();
+        (adapter.mouse_x, adapter.mouse_y) = (x, y);
+        adapter.write_byte(interface_adapter::EXT_MOUSE_X_HI, 0xe);
+
+        assert_eq!(adapter.read_byte(0x3), 0x2c);
+        assert_eq!(adapter.read_byte(0xd), 0x01);
+        assert_eq!(adapter.read_byte(0x4), 200);
+    }
+
+
+    #[test]
+    fn cycles_per_second() {
+        assert_eq!(per_second(2_000_000, Duration::from_millis(500)), 4_000_000.0);
+        assert_eq!(per_second(1_000, Duration::ZERO), 0.0);
+    }
+
+
+    #[test]
+    fn viewport_letterboxes_to_the_target_aspect() {
+        let stretched = Viewport::new(0.0);
+        assert_eq!((stretched.scale_x, stretched.scale_y), (2.0, 2.0));
+        assert_eq!((stretched.offset_x, stretched.offset_y), (0.0, 0.0));
+
+        // wider than the window, so it's letterboxed top and bottom
+        let wide = Viewport::new(4.0 / 3.0);
+        let height = RESOLUTION_X as f32 * 3.0 / 4.0;
+        assert_eq!(wide.scale_x, 2.0);
+        assert_eq!(wide.scale_y, height / ppu::INTERNAL_RESOLUTION_Y as f32);
+        assert_eq!(wide.offset_x, 0.0);
+        assert_eq!(wide.offset_y, (RESOLUTION_Y as f32 - height) / 2.0);
+
+        // narrower, so the bars are on the sides
+        let tall = Viewport::new(0.5);
+        assert_eq!(tall.scale_y, 2.0);
+        assert_eq!(tall.offset_x, (RESOLUTION_X as f32 - RESOLUTION_Y as f32 * 0.5) / 2.0);
+    }
+
+
+    #[test]
+    fn crt_darkens_every_other_scanline() {
+        // one row of one color, so the glow doesn't change it
+        let rgba = [200, 100, 40, 255].repeat(3);
+        let image = crt_image(&rgba, 3);
+
+        assert_eq!(image.len(), rgba.len() * 2);
+        assert_eq!(&image[.. 12], &rgba[..]);
+        assert_eq!(&image[12 .. 16], &[110, 55, 22, 255]);
+    }
+
+    #[test]
+    fn crt_glow_bleeds_from_the_neighbours() {
+        let rgba = [[0, 0, 0, 255], [100, 100, 100, 255], [0, 0, 0, 255]].concat();
+        let (lit, _) = crt_colors(&rgba, 0);
+
+        assert_eq!(lit[0], (100.0 * CRT_GLOW / 2.0) as u8);
+        assert_eq!(lit[3], 0xff);
+    }
+
+
+    #[test]
+    fn register_watch_stops_right_after_a_reaches_the_target() {
+        // LDA #$10; LDA #$42; LDA #$42; NOP
+        let bus = Rc::new(RefCell::new(bus::FlatBus::with_program(0x0200, &[0xa9, 0x10, 0xa9, 0x42, 0xa9, 0x42, 0xea])));
+        let mut cpu = cpu::CPU::new(bus);
+        cpu.reset();
+
+        let watch = RegisterWatch { a: Some(0x42), x: None, y: None };
+        let mut stops = Vec::new();
+        for _ in 0 .. 4 {
+            let before = (cpu.a, cpu.x, cpu.y);
+            cpu.tick().unwrap();
+            if let Some(hit) = watch.hit(before, &cpu) {
+                stops.push((cpu.pc, hit));
+            }
+        }
+
+        // loading the same value again isn't a change
+        assert_eq!(stops, [(0x0204, ("A", 0x42))]);
+    }
+
+
+    // a default layout machine running `code` from $8000, with the IRQ handler at $8010
+    fn machine(code: &[u8]) -> (cpu::CPU, Rc<RefCell<mapper::Map>>) {
+        let mut rom = vec![0xea; 0x8000];
+        rom[.. code.len()].copy_from_slice(code);
+        rom[0x7ffc .. 0x8000].copy_from_slice(&[0x00, 0x80, 0x10, 0x80]);
+
+        let map = Rc::new(RefCell::new(mapper::Map::from_reader(&rom[..], mapper::MemoryMap::DEFAULT).unwrap()));
+        let mut cpu = cpu::CPU::new(map.clone());
+        cpu.reset();
+
+        return (cpu, map);
+    }
+
+    #[test]
+    fn irq_latency_is_the_interrupt_sequence_after_the_current_instruction() {
+        // CLI; NOP
+        let (mut cpu, map) = machine(&[0x58]);
+        assert_eq!(measure_irq_latency(&mut cpu, &map, 1).unwrap(), Some((cpu::INTERRUPT_CYCLES as u64, 0)));
+
+        // NOP; LDA $1234; CLI, the IRQ waits for the load and the CLI
+        let (mut cpu, map) = machine(&[0xea, 0xad, 0x34, 0x12, 0x58]);
+        assert_eq!(measure_irq_latency(&mut cpu, &map, 1).unwrap(), Some((4 + 2 + cpu::INTERRUPT_CYCLES as u64, 2)));
+    }
+
+
+    #[test]
+    fn breakpoints_hit_only_their_address() {
+        let breakpoints: HashSet<u16> = [0x8000, 0x8010].into_iter().collect();
+
+        assert!(breakpoint_hit(&breakpoints, 0x8010));
+        assert!(!breakpoint_hit(&breakpoints, 0x8011));
+        assert!(!breakpoint_hit(&HashSet::new(), 0x8000));
+    }
+
+
+    #[test]
+    fn mouse_cells_follow_the_window_scale() {
+        let ppu  = test_ppu();
+        let view = Viewport::new(0.0);
+        let cell = |x: f32, y: f32| mouse_position((x, y), &view, &ppu, false);
+
+        assert_eq!(cell(0.0, 0.0), (0, 0));
+        // a cell is 14x18 window pixels at the 2x scale
+        assert_eq!(cell(13.0, 17.0), (0, 0));
+        assert_eq!(cell(14.0, 18.0), (1, 1));
+        assert_eq!(cell(14.0 * 40.0 + 1.0, 18.0 * 20.0 + 1.0), (40, 20));
+
+        // clamped to the text grid, the debug panel and beyond count as its edge
+        assert_eq!(cell(-20.0, 5000.0), (0, ppu::DEFAULT_ROWS as u16 - 1));
+        assert_eq!(cell(RESOLUTION_X as f32 + 100.0, 0.0), (ppu::DEFAULT_COLUMNS as u16 - 1, 0));
+    }
+
+
+    #[test]
+    fn pacing_sleeps_off_the_time_the_cycles_should_take() {
+        // 1000 cycles at 1 MHz take 1ms
+        assert_eq!(pacing_delay(1_000_000, Duration::from_micros(400), 1_000), Duration::from_micros(600));
+        // running behind doesn't sleep
+        assert_eq!(pacing_delay(1_000_000, Duration::from_millis(2), 1_000), Duration::ZERO);
+        // 0 is uncapped
+        assert_eq!(pacing_delay(0, Duration::ZERO, 1_000_000), Duration::ZERO);
+    }
+}
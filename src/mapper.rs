@@ -1,117 +1,961 @@
 #![allow(arithmetic_overflow)]
 
-use std::{fs::File, io::Read};
+use std::{cell::{Cell, RefCell}, cmp, fs::File, io, io::Read};
 
+use crate::bus::Bus;
+use crate::error::{BusFault, EmuError};
+use crate::ihex;
 use crate::interface_adapter;
 
-const RAM_SIZE: u16 = 32768;
-const ROM_SIZE: u16 = 32768;
+// in banked mode the first BANK_SIZE bytes of the ROM range show the bank
+// selected through adapter register 0xc, the rest of the range always
+// shows the end of the ROM image, where the vectors are
+pub const BANK_SIZE: usize = 0x4000;
+
+// where RAM, ROM and the adapter registers are decoded, ranges are inclusive.
+// the 16 adapter registers shadow whatever is below them
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryMap {
+    pub ram:     (u16, u16),
+    pub rom:     (u16, u16),
+    pub io_base: u16,
+
+    pub banked_rom: bool
+}
+
+impl MemoryMap {
+    pub const DEFAULT: MemoryMap = MemoryMap {
+        ram:     (0x0000, 0x7fff),
+        rom:     (0x8000, 0xffff),
+        io_base: 0x6000,
+
+        banked_rom: false
+    };
+
+    fn validate(&self) -> Result<(), EmuError> {
+        if self.ram.0 > self.ram.1 || self.rom.0 > self.rom.1 {
+            return Err(EmuError::InvalidConfig("memory ranges must start before they end".to_string()));
+        }
+
+        if self.ram.0 <= self.rom.1 && self.rom.0 <= self.ram.1 {
+            return Err(EmuError::InvalidConfig("RAM and ROM ranges overlap".to_string()));
+        }
+
+        if self.banked_rom && MemoryMap::len(self.rom) <= BANK_SIZE {
+            return Err(EmuError::InvalidConfig("banked ROM needs a ROM range larger than the 16KB bank window".to_string()));
+        }
+
+        if self.io_base > 0xfff0 {
+            return Err(EmuError::InvalidConfig("the adapter registers don't fit below FFFF".to_string()));
+        }
+
+        // zero page addressing and the stack always hit the bottom two pages
+        if self.ram.0 != 0 || self.ram.1 < 0x01ff {
+            return Err(EmuError::InvalidConfig("RAM has to cover the zero page and the stack at 0000-01FF".to_string()));
+        }
+
+        if FRAMEBUFFER_START < self.ram.0 || FRAMEBUFFER_END - 1 > self.ram.1 {
+            return Err(EmuError::InvalidConfig(format!(
+                "RAM has to cover the framebuffer at {:04X}-{:04X}", FRAMEBUFFER_START, FRAMEBUFFER_END - 1
+            )));
+        }
+
+        let io_end = self.io_base + 0xf;
+        if io_end >= FRAMEBUFFER_START && self.io_base < FRAMEBUFFER_END {
+            return Err(EmuError::InvalidConfig("the adapter registers overlap the framebuffer".to_string()));
+        }
+
+        return Ok(());
+    }
+
+    fn len(range: (u16, u16)) -> usize {
+        return (range.1 - range.0) as usize + 1;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BusAccess {
+    Read,
+    Write
+}
+
+// word accesses show up as two byte events, low byte first
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BusEvent {
+    pub access:  BusAccess,
+    pub address: u16,
+    pub value:   u8
+}
+
+pub type BusTrace = Box<dyn FnMut(BusEvent)>;
+
+enum Region {
+    Io(u16),
+    Ram(usize),
+    Rom(usize),
+    Unmapped
+}
+
+pub const FRAMEBUFFER_START: u16 = 0x6010;
+pub const FRAMEBUFFER_END  : u16 = 0x7010;
+
+// every framebuffer cell is a character and an attribute byte
+const FRAMEBUFFER_CELLS: usize = (FRAMEBUFFER_END - FRAMEBUFFER_START) as usize / 2;
+
+// how many bytes ahead of the current instruction count as "instruction stream"
+const SMC_WINDOW: u16 = 8;
+
+// raster timing for framebuffer contention, a 60Hz frame at 1MHz.
+// the PPU scans the framebuffer during the first ACTIVE_CYCLES of each frame
+pub const FRAME_CYCLES : u32 = 16_667;
+pub const ACTIVE_CYCLES: u32 = 12_800;
 
 pub struct Map {
     pub fbuf_changed: bool,
 
+    // framebuffer cells written since the PPU last rendered, one bit per cell.
+    // adapter writes can move sprites anywhere, so they redraw everything
+    dirty_cells: Vec<u64>,
+    redraw_all:  bool,
+
+    pub warn_smc: bool,
+    pub cpu_pc:   u16,
+
+    // one bit per RAM byte, set once it has been written
+    pub trap_uninit: bool,
+    written: Vec<u64>,
+
+    // reads go through &self, hence the RefCell
+    trace: Option<RefCell<BusTrace>>,
+
+    // unmapped reads return the last byte read or written instead of 0.
+    // that depends on the exact access order, so it's off by default
+    pub open_bus: bool,
+    bus_value:    Cell<u8>,
+
+    // extra cycles a framebuffer write costs while the PPU scans it, 0 turns it off.
+    // `beam` is the position in the frame, in cycles
+    pub contention: u8,
+    beam:  u32,
+    stall: u8,
+
+    layout:  MemoryMap,
     rom:     Vec<u8>,
+    // length of the image before it was padded to whole banks
+    rom_end: usize,
     pub ram: Vec<u8>,
 
     pub int_adapter: interface_adapter::Adapter 
 }
 
+// an image without a single set byte can't have a reset vector either
+fn blank_rom_warning(rom: &[u8]) -> Option<&'static str> {
+    if rom.iter().all(|&byte| byte == 0) {
+        return Some("ROM file is empty or all zeros, no reset vector is present and execution will start at 0000");
+    }
+
+    return None;
+}
+
 impl Map {
-    pub fn new(filename: &str) -> Self {
-        let mut file = File::open(filename)
-            .expect("Couldn't open ROM file");
-        
+    // a filename of "-" reads the ROM from stdin
+    pub fn new(filename: &str, layout: MemoryMap) -> Result<Self, EmuError> {
+        layout.validate()?;
+
+        let map = if filename == "-" {
+            Map::from_reader(io::stdin().lock(), layout)
+        } else {
+            File::open(filename).and_then(|file| Map::from_reader(file, layout))
+        };
+
+        return map.map_err(|err| EmuError::RomLoad(filename.to_string(), err));
+    }
+
+    // the ROM image is cut or padded to the size of the ROM range.
+    // banked images can be larger, they're padded to whole banks but
+    // the fixed window still ends where the file does.
+    // Intel HEX addresses are CPU addresses, so they start at the ROM range
+    pub fn from_reader(mut reader: impl Read, layout: MemoryMap) -> io::Result<Self> {
         let mut rom: Vec<u8> = Vec::new();
-        file.read_to_end(&mut rom)
-            .expect("Couldn't read ROM file");
+        reader.read_to_end(&mut rom)?;
+
+        let limit = if layout.banked_rom { interface_adapter::MAX_ROM_SIZE as usize } else { MemoryMap::len(layout.rom) };
+        rom = ihex::load_or_raw(rom, layout.rom.0 as u32, limit);
 
-        while rom.len() < ROM_SIZE as usize {
-            rom.push(0);
+        if let Some(warning) = blank_rom_warning(&rom) {
+            println!("Warning: {}", warning);
         }
 
-        return Map {
-            rom, ram: vec![0; RAM_SIZE as usize], fbuf_changed: true,
-            int_adapter: interface_adapter::Adapter::new()
+        let ram_size = MemoryMap::len(layout.ram);
+        let (rom_end, rom_size) = if layout.banked_rom {
+            let end = cmp::max(rom.len(), MemoryMap::len(layout.rom));
+            (end, end.div_ceil(BANK_SIZE) * BANK_SIZE)
+        } else {
+            (MemoryMap::len(layout.rom), MemoryMap::len(layout.rom))
+        };
+        rom.resize(rom_size, 0);
+
+        return Ok(Map {
+            rom, rom_end, ram: vec![0; ram_size], fbuf_changed: true, layout, trace: None,
+            open_bus: false, bus_value: Cell::new(0), contention: 0, beam: 0, stall: 0,
+            dirty_cells: vec![0; FRAMEBUFFER_CELLS / 64], redraw_all: true,
+            int_adapter: interface_adapter::Adapter::new(),
+            warn_smc: false, cpu_pc: 0,
+            trap_uninit: false, written: vec![0; ram_size.div_ceil(64)]
+        });
+    }
+
+    // called for every read and write the CPU makes, peeks aren't traced
+    pub fn set_trace(&mut self, trace: BusTrace) {
+        self.trace = Some(RefCell::new(trace));
+    }
+
+    #[allow(unused)]
+    pub fn clear_trace(&mut self) {
+        self.trace = None;
+    }
+
+    // every traced access also leaves its value on the bus
+    fn trace(&self, access: BusAccess, address: u16, value: u8) {
+        self.bus_value.set(value);
+
+        if let Some(trace) = &self.trace {
+            (trace.borrow_mut())(BusEvent { access, address, value });
         }
     }
 
-    pub fn write_byte(&mut self, value: u8, address: u16) {
-        if address <= 0x7fff {
-            if address >= 0x6000 && address <= 0x600f {
-                self.int_adapter.write_byte(value, address & 0xf);
-            } else {
-                if address >= 0x6010 && address <= 0x7010 {
-                    self.fbuf_changed = true;
-                }
+    fn open_bus_value(&self) -> u8 {
+        return if self.open_bus { self.bus_value.get() } else { 0 };
+    }
 
-                (*self.ram.get_mut(address as usize).unwrap()) = value;
-            }
-        } else {
-            println!("CPU is trying to write to ROM!");
+    #[allow(unused)]
+    pub fn layout(&self) -> MemoryMap {
+        return self.layout;
+    }
+
+    fn decode(&self, address: u16) -> Region {
+        if address >= self.layout.io_base && address - self.layout.io_base <= 0xf {
+            return Region::Io(address - self.layout.io_base);
         }
+
+        return self.decode_memory(address);
     }
 
-    pub fn read_byte(&self, address: u16) -> u8 {
-        if address <= 0x7fff {
-            if address >= 0x6000 && address <= 0x600f {
-                return self.int_adapter.read_byte(address & 0xf);
-            } else {
-                return *self.ram.get(address as usize).unwrap();
+    // ignores the adapter registers, for loading images straight into memory
+    fn decode_memory(&self, address: u16) -> Region {
+        let (ram, rom) = (self.layout.ram, self.layout.rom);
+
+        if address >= ram.0 && address <= ram.1 {
+            return Region::Ram((address - ram.0) as usize);
+        }
+
+        if address >= rom.0 && address <= rom.1 {
+            return Region::Rom(self.rom_index((address - rom.0) as usize));
+        }
+
+        return Region::Unmapped;
+    }
+
+    // `ix` is the offset into the ROM range
+    fn rom_index(&self, ix: usize) -> usize {
+        if !self.layout.banked_rom {
+            return ix;
+        }
+
+        if ix < BANK_SIZE {
+            let banks = self.rom.len() / BANK_SIZE;
+            return (self.int_adapter.rom_bank as usize % banks) * BANK_SIZE + ix;
+        }
+
+        return self.rom_end - MemoryMap::len(self.layout.rom) + ix;
+    }
+
+    // the framebuffer always lives in RAM, so the PPU can skip address decoding
+    pub fn framebuffer_slice(&self) -> &[u8] {
+        let start = (FRAMEBUFFER_START - self.layout.ram.0) as usize;
+        return &self.ram[start .. start + (FRAMEBUFFER_END - FRAMEBUFFER_START) as usize];
+    }
+
+    pub fn snapshot(&self) -> Vec<u8> {
+        return self.ram.clone();
+    }
+
+    // (address, old value, new value) for every RAM byte changed since the snapshot
+    pub fn diff(&self, snapshot: &[u8]) -> Vec<(u16, u8, u8)> {
+        return self.ram.iter().zip(snapshot.iter()).enumerate()
+            .filter(|(_, (new, old))| new != old)
+            .map(|(ix, (&new, &old))| (self.layout.ram.0 + ix as u16, old, new))
+            .collect();
+    }
+
+    // copies a block straight into RAM or ROM, bypassing the adapter
+    pub fn load_image(&mut self, data: &[u8], address: u16) {
+        for (i, &byte) in data.iter().enumerate() {
+            match self.decode_memory(address.wrapping_add(i as u16)) {
+                Region::Ram(ix) => self.ram_write(byte, ix),
+                Region::Rom(ix) => self.rom[ix] = byte,
+                _ => {}
             }
-        } else {
-            return *self.rom.get((address & 0x7fff) as usize).unwrap();
         }
+
+        self.fbuf_changed = true;
     }
 
-    pub fn write_word(&mut self, value: u16, address: u16) {
-        let addr = address as usize;
+    // `ram` is public and can be swapped for a shorter vector, so
+    // indices past its end are mirrored instead of panicking
+    fn ram_index(&self, ix: usize) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None;
+        }
 
-        if address <= 0x7fff {
-            if address >= 0x6000 && address <= 0x600f {
-                if self.int_adapter.write_word(value, address & 0xf) {
-                    if address + 1 >= 0x6010 && address + 1 <= 0x7010 {
-                        self.fbuf_changed = true;
-                    }
+        return Some(ix % self.ram.len());
+    }
 
-                    (*self.ram.get_mut(addr + 1).unwrap()) = (value >> 8) as u8;
-                } 
-            } else {
-                if (address >= 0x6010 && address <= 0x7010) || (address + 1 >= 0x6010 && address + 1 <= 0x7010) {
-                    self.fbuf_changed = true;
-                }
+    // `ix` is an index into RAM, not an address
+    fn ram_write(&mut self, value: u8, ix: usize) {
+        let addr = self.layout.ram.0 as usize + ix;
+        if addr >= FRAMEBUFFER_START as usize && addr < FRAMEBUFFER_END as usize {
+            let cell = (addr - FRAMEBUFFER_START as usize) / 2;
+            self.dirty_cells[cell / 64] |= 1 << (cell % 64);
+            self.fbuf_changed = true;
+        }
 
-                (*self.ram.get_mut(addr).unwrap()) = (value & 0xff) as u8;
+        let ix = match self.ram_index(ix) {
+            Some(ix) => ix,
+            None => return
+        };
 
-                if address + 1 < 0x7fff {
-                    (*self.ram.get_mut(addr + 1).unwrap()) = (value >> 8) as u8;
-                } else {
-                    println!("CPU is trying to write to ROM!");
-                }
+        if let Some(word) = self.written.get_mut(ix / 64) {
+            *word |= 1 << (ix % 64);
+        }
+
+        self.ram[ix] = value;
+    }
+
+    fn ram_read(&self, ix: usize) -> u8 {
+        let addr = self.layout.ram.0 as usize + ix;
+        let ix = match self.ram_index(ix) {
+            Some(ix) => ix,
+            None => return 0
+        };
+
+        if self.traps_uninit_read(ix) {
+            println!("Read of uninitialized RAM at {:04X} (PC {:04X})", addr, self.cpu_pc);
+        }
+
+        return self.ram[ix];
+    }
+
+    fn traps_uninit_read(&self, ix: usize) -> bool {
+        let written = self.written.get(ix / 64).is_none_or(|word| word & (1 << (ix % 64)) != 0);
+        return self.trap_uninit && !written;
+    }
+
+    fn ram_peek(&self, ix: usize) -> u8 {
+        return self.ram_index(ix).map_or(0, |ix| self.ram[ix]);
+    }
+
+    // the write lands in the instructions just ahead of the PC
+    fn smc_suspected(&self, address: u16) -> bool {
+        return self.warn_smc && address.wrapping_sub(self.cpu_pc) < SMC_WINDOW;
+    }
+
+    fn check_smc(&self, address: u16) {
+        if self.smc_suspected(address) {
+            println!("Write to {:04X} close to PC {:04X}, possible self-modifying code", address, self.cpu_pc);
+        }
+    }
+
+    fn take_display_changed(&mut self) {
+        if self.int_adapter.display_changed {
+            self.int_adapter.display_changed = false;
+            self.fbuf_changed = true;
+            self.redraw_all   = true;
+        }
+    }
+
+    // copies as if through a temporary buffer, so overlapping blocks work.
+    // the copy stops at the first byte of either block that isn't in RAM
+    fn run_dma(&mut self) {
+        let (src, dst, len) = match self.int_adapter.take_dma() {
+            Some(dma) => dma,
+            None => return
+        };
+
+        let block: Vec<(usize, u8)> = (0 .. len)
+            .map_while(|i| match (self.decode_memory(src.wrapping_add(i)), self.decode_memory(dst.wrapping_add(i))) {
+                (Region::Ram(from), Region::Ram(to)) => Some((to, self.ram_peek(from))),
+                _ => None
+            })
+            .collect();
+
+        if block.len() < len as usize {
+            println!("DMA of {:04X} bytes from {:04X} to {:04X} leaves RAM, only {:04X} were copied", len, src, dst, block.len());
+        }
+
+        for (to, value) in block {
+            self.ram_write(value, to);
+        }
+    }
+
+    // moves the raster along by the cycles the CPU ran
+    pub fn advance_beam(&mut self, cycles: u8) {
+        self.beam = (self.beam + cycles as u32) % FRAME_CYCLES;
+    }
+
+    fn in_active_region(&self) -> bool {
+        return self.beam < ACTIVE_CYCLES;
+    }
+
+    // queues a cell for redrawing without anything being written to it
+    pub fn mark_cell(&mut self, cell: u16) {
+        if (cell as usize) < FRAMEBUFFER_CELLS {
+            self.dirty_cells[cell as usize / 64] |= 1 << (cell % 64);
+        }
+    }
+
+    // `None` when every cell has to be redrawn. adapter resets set
+    // `display_changed` without going through the bus, so it's checked too
+    pub fn take_dirty_cells(&mut self) -> Option<Vec<u16>> {
+        let cells = (0 .. FRAMEBUFFER_CELLS)
+            .filter(|&cell| self.dirty_cells[cell / 64] & (1 << (cell % 64)) != 0)
+            .map(|cell| cell as u16)
+            .collect();
+
+        self.dirty_cells.fill(0);
+
+        if self.redraw_all || self.int_adapter.display_changed {
+            self.redraw_all = false;
+            self.int_adapter.display_changed = false;
+            return None;
+        }
+
+        return Some(cells);
+    }
+
+    #[allow(unused)]
+    pub fn write_word(&mut self, value: u16, address: u16) -> Result<(), BusFault> {
+        if let Region::Io(register) = self.decode(address) {
+            self.check_smc(address);
+            self.trace(BusAccess::Write, address, (value & 0xff) as u8);
+
+            let spilled = self.int_adapter.write_word(value, register);
+            self.take_display_changed();
+            self.run_dma();
+
+            if spilled {
+                return self.write_byte((value >> 8) as u8, address.wrapping_add(1));
             }
-        } else {
-            println!("CPU is trying to write to ROM!");
+
+            self.trace(BusAccess::Write, address.wrapping_add(1), (value >> 8) as u8);
+            return Ok(());
         }
+
+        self.write_byte((value & 0xff) as u8, address)?;
+        return self.write_byte((value >> 8) as u8, address.wrapping_add(1));
     }
+}
+
+impl Bus for Map {
+    fn read_byte(&self, address: u16) -> u8 {
+        let value = match self.decode(address) {
+            Region::Io(register) => self.int_adapter.read_byte(register),
+            Region::Ram(ix)      => self.ram_read(ix),
+            Region::Rom(ix)      => self.rom[ix],
+            Region::Unmapped     => self.open_bus_value()
+        };
+
+        self.trace(BusAccess::Read, address, value);
+        return value;
+    }
+
+    fn write_byte(&mut self, value: u8, address: u16) -> Result<(), BusFault> {
+        self.check_smc(address);
+        self.trace(BusAccess::Write, address, value);
+
+        match self.decode(address) {
+            Region::Io(register) => {
+                let valid = self.int_adapter.write_byte(value, register);
+                self.take_display_changed();
+                self.run_dma();
 
-    pub fn read_word(&self, address: u16) -> u16 {
-        if address <= 0x7fff {
-            if address >= 0x6000 && address <= 0x600f {
-                return match self.int_adapter.read_word(address & 0xf) {
-                    Some(x) => x,
-                    None => (self.int_adapter.interrupt_id as u16) | ((self.ram[address as usize + 1] as u16) << 8)
-                };
+                if !valid {
+                    return Err(BusFault::Adapter(address));
+                }
             }
+            Region::Ram(ix)  => {
+                if self.contention != 0 && address >= FRAMEBUFFER_START && address < FRAMEBUFFER_END && self.in_active_region() {
+                    self.stall = self.stall.saturating_add(self.contention);
+                }
 
-            let addr = address as usize;
-            if address + 1 <= 0x7fff {
-                return (*self.ram.get(addr).unwrap() as u16) | ((*self.ram.get(addr + 1).unwrap() as u16) << 8);
-            } else {
-                return (*self.ram.get(addr).unwrap() as u16) | ((*self.rom.get(((address + 1) & 0x7fff) as usize).unwrap() as u16) << 8);
+                self.ram_write(value, ix);
             }
+            Region::Rom(_)   => return Err(BusFault::Rom(address)),
+            Region::Unmapped => return Err(BusFault::Unmapped(address))
+        }
+
+        return Ok(());
+    }
+
+    fn read_word(&self, address: u16) -> u16 {
+        let next = address.wrapping_add(1);
+
+        if let Region::Io(register) = self.decode(address) {
+            return match self.int_adapter.read_word(register) {
+                Some(x) => {
+                    self.trace(BusAccess::Read, address, (x & 0xff) as u8);
+                    self.trace(BusAccess::Read, next, (x >> 8) as u8);
+                    x
+                }
+                None => {
+                    let id = self.int_adapter.acknowledge();
+                    self.trace(BusAccess::Read, address, id);
+
+                    (id as u16) | ((self.read_byte(next) as u16) << 8)
+                }
+            };
+        }
+
+        return (self.read_byte(address) as u16) | ((self.read_byte(next) as u16) << 8);
+    }
+
+    fn peek_byte(&self, address: u16) -> u8 {
+        return match self.decode(address) {
+            Region::Io(register) => self.int_adapter.peek_byte(register),
+            Region::Ram(ix)      => self.ram_peek(ix),
+            Region::Rom(ix)      => self.rom[ix],
+            Region::Unmapped     => self.open_bus_value()
+        };
+    }
+
+    fn irq_asserted(&self) -> bool {
+        return self.int_adapter.irq_asserted();
+    }
+
+    fn set_cpu_pc(&mut self, pc: u16) {
+        self.cpu_pc = pc;
+        self.int_adapter.cpu_pc = pc;
+    }
+
+    fn take_stall_cycles(&mut self) -> u8 {
+        return std::mem::take(&mut self.stall);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+    use crate::error::StepError;
+    use std::rc::Rc;
+
+    // a 32KB ROM for the default layout with `code` at $8000 and the reset vector pointing there,
+    // each (address, bytes) pair is placed at that CPU address
+    fn rom_with(code: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        for &(address, bytes) in code {
+            let start = (address - 0x8000) as usize;
+            rom[start .. start + bytes.len()].copy_from_slice(bytes);
+        }
+
+        rom[0x7ffc] = 0x00;
+        rom[0x7ffd] = 0x80;
+        return rom;
+    }
+
+    fn map_with(code: &[(u16, &[u8])]) -> Rc<RefCell<Map>> {
+        return Rc::new(RefCell::new(Map::from_reader(&rom_with(code)[..], MemoryMap::DEFAULT).unwrap()));
+    }
+
+    // like the emulator loop, queued adapter events are delivered before every step
+    fn run(cpu: &mut CPU, map: &Rc<RefCell<Map>>, steps: usize) {
+        for _ in 0 .. steps {
+            (*map.borrow_mut()).int_adapter.deliver_pending();
+            cpu.tick().unwrap();
+        }
+    }
+
+    #[test]
+    fn guest_reads_three_queued_keys_in_order() {
+        let map = map_with(&[
+            // CLI; JMP *
+            (0x8000, &[0x58, 0x4c, 0x01, 0x80]),
+            // LDA $600F; LDA $6002; STA $10,X; INX; RTI
+            (0x8010, &[0xad, 0x0f, 0x60, 0xad, 0x02, 0x60, 0x95, 0x10, 0xe8, 0x40]),
+            (0xfffe, &[0x10, 0x80])
+        ]);
+        let mut cpu = CPU::new(map.clone());
+        cpu.reset();
+
+        // queued while reset still has interrupts disabled, they wait for the CLI
+        for scancode in [0x1e, 0x30, 0x2e] {
+            (*map.borrow_mut()).int_adapter.queue_key(scancode, interface_adapter::KEYDOWN);
+        }
+        run(&mut cpu, &map, 100);
+
+        assert_eq!(cpu.x, 3);
+        assert_eq!(&(*map.borrow()).ram[0x10 .. 0x13], &[0x1e, 0x30, 0x2e]);
+    }
+
+    #[test]
+    fn rom_write_is_a_bus_fault() {
+        let map = map_with(&[]);
+
+        assert_eq!((*map.borrow_mut()).write_byte(0x42, 0x9000), Err(BusFault::Rom(0x9000)));
+        assert_eq!((*map.borrow()).read_byte(0x9000), 0);
+    }
+
+    #[test]
+    fn cartridge_write_is_an_adapter_fault() {
+        let map = map_with(&[]);
+
+        assert_eq!((*map.borrow_mut()).write_byte(0x42, 0x6008), Err(BusFault::Adapter(0x6008)));
+    }
+
+    #[test]
+    fn trapped_fault_keeps_the_instruction_cycles() {
+        // STA $9000
+        let map = map_with(&[(0x8000, &[0x8d, 0x00, 0x90])]);
+        let mut cpu = CPU::new(map.clone());
+        cpu.reset();
+        cpu.trap_bus_faults = true;
+
+        match cpu.tick() {
+            Err(StepError::BusFault(BusFault::Rom(0x9000), 4)) => {}
+            other => panic!("expected a ROM fault taking 4 cycles, got {:?}", other)
+        }
+        assert_eq!(cpu.cycles, 4);
+        assert_eq!(cpu.pc, 0x8003);
+    }
+
+    fn banked_map(rom: &[u8]) -> Map {
+        return Map::from_reader(rom, MemoryMap { banked_rom: true, ..MemoryMap::DEFAULT }).unwrap();
+    }
+
+    #[test]
+    fn bank_register_switches_the_window() {
+        // three banks filled with their number, the last one is also the fixed window
+        let rom: Vec<u8> = (0 .. 3 * BANK_SIZE).map(|ix| (ix / BANK_SIZE) as u8 + 1).collect();
+        let mut map = banked_map(&rom);
+
+        assert_eq!(map.read_byte(0x8000), 1);
+        map.write_byte(2, 0x600c).unwrap();
+        assert_eq!(map.read_byte(0x8000), 3);
+        assert_eq!(map.read_byte(0xc000), 3);
+    }
+
+    #[test]
+    fn fixed_window_ends_with_an_uneven_banked_image() {
+        // 40KB, the reset vector in the last two bytes of the file
+        let mut rom = vec![0; 0xa000];
+        rom[0x9ffc] = 0x34;
+        rom[0x9ffd] = 0x12;
+        let map = banked_map(&rom);
+
+        assert_eq!(map.read_word(0xfffc), 0x1234);
+    }
+
+    #[test]
+    fn debug_register_reads_the_current_pc() {
+        // LDA $600A; LDX $600B
+        let map = map_with(&[(0x8000, &[0xad, 0x0a, 0x60, 0xae, 0x0b, 0x60])]);
+        let mut cpu = CPU::new(map.clone());
+        cpu.reset();
+        run(&mut cpu, &map, 2);
+
+        assert_eq!(cpu.a, 0x00);
+        assert_eq!(cpu.x, 0x80);
+    }
+
+    #[test]
+    fn framebuffer_write_in_the_active_region_is_penalized() {
+        // STA $6010; STA $6010; STA $0010
+        let map = map_with(&[(0x8000, &[0x8d, 0x10, 0x60, 0x8d, 0x10, 0x60, 0x85, 0x10])]);
+        (*map.borrow_mut()).contention = 3;
+        let mut cpu = CPU::new(map.clone());
+        cpu.reset();
+
+        assert_eq!(cpu.tick().unwrap(), 4 + 3);
+
+        (*map.borrow_mut()).beam = ACTIVE_CYCLES;
+        assert_eq!(cpu.tick().unwrap(), 4);
+
+        // the next frame starts scanning again, but only framebuffer writes are held up
+        (*map.borrow_mut()).beam = FRAME_CYCLES - 1;
+        (*map.borrow_mut()).advance_beam(1);
+        assert!((*map.borrow()).in_active_region());
+        assert_eq!(cpu.tick().unwrap(), 3);
+    }
+
+
+    #[test]
+    fn write_ahead_of_the_pc_is_suspected_smc() {
+        let map = map_with(&[]);
+        let mut map = map.borrow_mut();
+        map.warn_smc = true;
+        map.cpu_pc   = 0x0300;
+
+        assert!(map.smc_suspected(0x0302));
+        assert!(!map.smc_suspected(0x0400));
+        assert!(!map.smc_suspected(0x02ff));
+
+        map.warn_smc = false;
+        assert!(!map.smc_suspected(0x0302));
+    }
+
+
+    #[test]
+    fn empty_rom_warns_about_the_reset_vector() {
+        assert!(blank_rom_warning(&[]).unwrap().contains("no reset vector"));
+        assert!(blank_rom_warning(&[0; 0x8000]).is_some());
+        assert_eq!(blank_rom_warning(&rom_with(&[])), None);
+
+        let map = Map::from_reader(&[][..], MemoryMap::DEFAULT).unwrap();
+        assert_eq!(map.read_word(0xfffc), 0x0000);
+    }
+
+
+    #[test]
+    fn map_loads_from_any_reader() {
+        let rom = rom_with(&[(0x8000, &[0xa9, 0x42])]);
+        let map = Map::from_reader(io::Cursor::new(rom), MemoryMap::DEFAULT).unwrap();
+
+        assert_eq!(map.read_byte(0x8001), 0x42);
+        assert_eq!(map.read_word(0xfffc), 0x8000);
+    }
+
+
+    #[test]
+    fn framebuffer_slice_matches_read_word() {
+        let map = map_with(&[]);
+        let mut map = map.borrow_mut();
+        for (ix, address) in (FRAMEBUFFER_START .. FRAMEBUFFER_END).enumerate() {
+            map.write_byte((ix * 7) as u8, address).unwrap();
+        }
+
+        let slice = map.framebuffer_slice().to_vec();
+        assert_eq!(slice.len(), (FRAMEBUFFER_END - FRAMEBUFFER_START) as usize);
+
+        for (cell, word) in slice.chunks_exact(2).enumerate() {
+            let expected = map.read_word(FRAMEBUFFER_START + cell as u16 * 2);
+            assert_eq!((word[0] as u16) | ((word[1] as u16) << 8), expected);
+        }
+    }
+
+
+    #[test]
+    fn diff_reports_exactly_the_changed_bytes() {
+        let map = map_with(&[]);
+        let mut map = map.borrow_mut();
+        map.write_byte(0x11, 0x0300).unwrap();
+        let snapshot = map.snapshot();
+
+        map.write_byte(0x01, 0x0010).unwrap();
+        map.write_byte(0x22, 0x0300).unwrap();
+        map.write_byte(0xff, 0x7000).unwrap();
+        // same value, not a change
+        map.write_byte(0x00, 0x0400).unwrap();
+
+        assert_eq!(map.diff(&snapshot), [(0x0010, 0x00, 0x01), (0x0300, 0x11, 0x22), (0x7000, 0x00, 0xff)]);
+    }
+
+
+    #[test]
+    fn only_unwritten_ram_traps_on_read() {
+        let map = map_with(&[]);
+        let mut map = map.borrow_mut();
+        map.trap_uninit = true;
+        map.write_byte(0x42, 0x0300).unwrap();
+
+        assert!(!map.traps_uninit_read(0x0300));
+        assert!(map.traps_uninit_read(0x0301));
+
+        map.trap_uninit = false;
+        assert!(!map.traps_uninit_read(0x0301));
+    }
+
+
+    #[test]
+    fn writing_one_cell_marks_only_it_dirty() {
+        let map = map_with(&[]);
+        let mut map = map.borrow_mut();
+        // the first frame draws everything
+        assert_eq!(map.take_dirty_cells(), None);
+
+        map.write_byte(0x41, FRAMEBUFFER_START + 2 * 5).unwrap();
+        map.write_byte(0x0f, FRAMEBUFFER_START + 2 * 5 + 1).unwrap();
+        assert_eq!(map.take_dirty_cells(), Some(vec![5]));
+        assert_eq!(map.take_dirty_cells(), Some(vec![]));
+    }
+
+
+    #[test]
+    fn io_at_8000_with_rom_at_c000() {
+        let layout = MemoryMap { ram: (0x0000, 0x7fff), rom: (0xc000, 0xffff), io_base: 0x8000, banked_rom: false };
+        layout.validate().unwrap();
+
+        let mut rom = vec![0; 0x4000];
+        rom[0] = 0xa9;
+        rom[0x3ffc] = 0x00;
+        rom[0x3ffd] = 0xc0;
+        let mut map = Map::from_reader(&rom[..], layout).unwrap();
+
+        assert_eq!(map.read_byte(0xc000), 0xa9);
+        assert_eq!(map.read_word(0xfffc), 0xc000);
+
+        // port B is the first adapter register
+        map.write_byte(0x5a, 0x8000).unwrap();
+        assert_eq!(map.int_adapter.read_byte(0x0), 0x5a);
+        assert_eq!(map.read_byte(0x8000), 0x5a);
+
+        // the old adapter window is plain RAM now, between I/O and ROM is nothing
+        map.write_byte(0x77, 0x6000).unwrap();
+        assert_eq!(map.read_byte(0x6000), 0x77);
+        assert_eq!(map.read_byte(0xa000), 0x00);
+        assert_eq!(map.write_byte(0x01, 0xa000), Err(BusFault::Unmapped(0xa000)));
+    }
+
+    #[test]
+    fn overlapping_ranges_are_rejected() {
+        let layout = MemoryMap { rom: (0x7000, 0xffff), ..MemoryMap::DEFAULT };
+        assert!(matches!(layout.validate(), Err(EmuError::InvalidConfig(_))));
+    }
+
+
+    #[test]
+    fn short_ram_mirrors_instead_of_panicking() {
+        let map = map_with(&[]);
+        let mut map = map.borrow_mut();
+        map.ram.truncate(0x1000);
+        map.write_byte(0x42, 0x0fff).unwrap();
+
+        assert_eq!(map.read_byte(0x7fff), 0x42);
+        assert_eq!(map.read_word(0x7ffe), 0x4200);
+
+        // the stack page is plain RAM
+        map.write_byte(0x99, 0x01ff).unwrap();
+        assert_eq!(map.read_byte(0x01ff), 0x99);
+
+        map.ram.clear();
+        assert_eq!(map.read_byte(0x7fff), 0x00);
+        assert_eq!(map.write_byte(0x01, 0x7fff), Ok(()));
+    }
+
+
+    #[test]
+    fn trace_sees_every_access_of_the_program() {
+        // LDA #$42; STA $0300; STA $6000
+        let map = map_with(&[(0x8000, &[0xa9, 0x42, 0x8d, 0x00, 0x03, 0x8d, 0x00, 0x60])]);
+        let mut cpu = CPU::new(map.clone());
+        cpu.reset();
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let log = events.clone();
+        (*map.borrow_mut()).set_trace(Box::new(move |event| log.borrow_mut().push(event)));
+        run(&mut cpu, &map, 3);
+
+        let writes: Vec<BusEvent> = events.borrow().iter().copied().filter(|event| event.access == BusAccess::Write).collect();
+        assert_eq!(writes, [
+            BusEvent { access: BusAccess::Write, address: 0x0300, value: 0x42 },
+            BusEvent { access: BusAccess::Write, address: 0x6000, value: 0x42 }
+        ]);
+
+        // the opcode fetches are reads
+        assert_eq!(events.borrow()[0], BusEvent { access: BusAccess::Read, address: 0x8000, value: 0xa9 });
+    }
+
+
+    #[test]
+    fn open_bus_reads_back_the_last_value() {
+        let layout = MemoryMap { rom: (0xc000, 0xffff), ..MemoryMap::DEFAULT };
+        let mut map = Map::from_reader(&[0; 0x4000][..], layout).unwrap();
+        map.write_byte(0xab, 0x0300).unwrap();
+        assert_eq!(map.read_byte(0xa000), 0x00);
+
+        map.open_bus = true;
+        map.write_byte(0xab, 0x0300).unwrap();
+        assert_eq!(map.read_byte(0xa000), 0xab);
+        // peeks don't drive the bus either
+        assert_eq!(map.peek_byte(0x0301), 0x00);
+        assert_eq!(map.read_byte(0xa001), 0xab);
+    }
+
+
+    #[test]
+    fn dma_copies_sixteen_bytes_into_the_framebuffer() {
+        let map = map_with(&[]);
+        let mut map = map.borrow_mut();
+        for ix in 0 .. 16 {
+            map.write_byte(0x40 + ix as u8, 0x0400 + ix).unwrap();
+        }
+        map.take_dirty_cells();
+        map.fbuf_changed = false;
+
+        let registers = [
+            (interface_adapter::EXT_DMA_SRC, 0x00), (interface_adapter::EXT_DMA_SRC_HI, 0x04),
+            (interface_adapter::EXT_DMA_DST, 0x10), (interface_adapter::EXT_DMA_DST_HI, 0x60),
+            (interface_adapter::EXT_DMA_LEN, 16),   (interface_adapter::EXT_DMA_LEN_HI, 0x00)
+        ];
+        for (register, value) in registers {
+            map.write_byte(register, 0x600e).unwrap();
+            map.write_byte(value, 0x600d).unwrap();
         }
-        
-        let addr = (address & 0x7fff) as usize;
-        let addr_plus_one = ((address + 1) & 0x7fff) as usize;
-        return (*self.rom.get(addr).unwrap() as u16) | ((*self.rom.get(addr_plus_one).unwrap() as u16) << 8);
+
+        let expected: Vec<u8> = (0x40 .. 0x50).collect();
+        assert_eq!(&map.framebuffer_slice()[.. 16], &expected[..]);
+        assert!(map.fbuf_changed);
+        assert_eq!(map.take_dirty_cells(), Some((0 .. 8).collect()));
+    }
+
+
+    #[test]
+    fn adapter_irq_vectors_through_fffe() {
+        // CLI; NOP, with the handler at $8020
+        let map = map_with(&[(0x8000, &[0x58, 0xea]), (0xfffe, &[0x20, 0x80])]);
+        let mut cpu = CPU::new(map.clone());
+        cpu.reset();
+        run(&mut cpu, &map, 1);
+
+        (*map.borrow_mut()).int_adapter.queue_interrupt(interface_adapter::IDLE);
+        assert!((*map.borrow()).irq_asserted());
+
+        assert_eq!(cpu.tick().unwrap(), crate::cpu::INTERRUPT_CYCLES);
+        assert_eq!(cpu.pc, 0x8020);
+        assert!(cpu.get_flag(crate::cpu::IRQ_DISABLE_FLAG));
+    }
+
+
+    #[test]
+    fn word_reads_across_the_ram_rom_seam_and_the_top_of_memory() {
+        let map = map_with(&[(0x8000, &[0x12]), (0xffff, &[0x34])]);
+        let mut map = map.borrow_mut();
+        map.write_byte(0x56, 0x7fff).unwrap();
+        map.write_byte(0x78, 0x0000).unwrap();
+
+        assert_eq!(map.read_word(0x7fff), 0x1256);
+        // wraps around to the zero page
+        assert_eq!(map.read_word(0xffff), 0x7834);
     }
-}
\ No newline at end of file
+
+
+    #[test]
+    fn word_writes_touching_the_framebuffer_mark_it_changed() {
+        let map = map_with(&[]);
+        let mut map = map.borrow_mut();
+
+        for (address, cell) in [(0x700f, 0x07ff), (0x6010, 0x0000)] {
+            map.take_dirty_cells();
+            map.fbuf_changed = false;
+
+            map.write_word(0x4142, address).unwrap();
+            assert!(map.fbuf_changed, "word write at {:04X}", address);
+            assert_eq!(map.take_dirty_cells(), Some(vec![cell]));
+        }
+
+        // the adapter spills its high byte into the first cell
+        map.take_dirty_cells();
+        map.fbuf_changed = false;
+        map.write_word(0x4142, 0x600f).unwrap();
+        assert!(map.fbuf_changed);
+    }
+}
@@ -0,0 +1,233 @@
+use std::io;
+
+// loader for the relocatable object format produced by the cc65 / xa toolchains
+// spec: http://www.6502.org/users/andre/o65/fileformat.html
+
+const MAGIC: [u8; 5] = [0x01, 0x00, b'o', b'6', b'5'];
+
+const MODE_65816:   u16 = 0x8000;
+const MODE_PAGED:   u16 = 0x4000;
+const MODE_SIZE_32: u16 = 0x2000;
+
+const SEG_UNDEFINED: u8 = 0;
+const SEG_ABSOLUTE:  u8 = 1;
+const SEG_TEXT:      u8 = 2;
+const SEG_DATA:      u8 = 3;
+const SEG_BSS:       u8 = 4;
+const SEG_ZERO:      u8 = 5;
+
+const RELOC_WORD: u8 = 0x80;
+const RELOC_HIGH: u8 = 0x40;
+const RELOC_LOW:  u8 = 0x20;
+
+pub struct Object {
+    pub text_base: u16,
+    pub text: Vec<u8>,
+
+    pub data_base: u16,
+    pub data: Vec<u8>
+}
+
+fn invalid(msg: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> io::Result<u8> {
+        let value = *self.bytes.get(self.pos).ok_or_else(|| invalid("unexpected end of file"))?;
+        self.pos += 1;
+
+        return Ok(value);
+    }
+
+    fn word(&mut self) -> io::Result<u16> {
+        let lo = self.byte()? as u16;
+        let hi = self.byte()? as u16;
+
+        return Ok(lo | (hi << 8));
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let end = self.pos + len;
+        if end > self.bytes.len() {
+            return Err(invalid("unexpected end of file"));
+        }
+
+        let slice = self.bytes[self.pos .. end].to_vec();
+        self.pos = end;
+
+        return Ok(slice);
+    }
+
+    fn skip_string(&mut self) -> io::Result<()> {
+        while self.byte()? != 0 {}
+        return Ok(());
+    }
+}
+
+// how much every segment moves: (text, data, bss, zero page)
+struct Deltas([u16; 4]);
+
+impl Deltas {
+    fn of(&self, segment: u8) -> io::Result<u16> {
+        return match segment {
+            SEG_ABSOLUTE => Ok(0),
+            SEG_TEXT | SEG_DATA | SEG_BSS | SEG_ZERO => Ok(self.0[(segment - SEG_TEXT) as usize]),
+            SEG_UNDEFINED => Err(invalid("references to undefined symbols are not supported")),
+            _ => Err(invalid("relocation entry refers to an unknown segment"))
+        };
+    }
+}
+
+fn relocate(reader: &mut Reader, segment: &mut [u8], deltas: &Deltas) -> io::Result<()> {
+    // offsets are relative to the previous entry, starting one byte before the segment
+    let mut addr: isize = -1;
+
+    loop {
+        let offset = reader.byte()?;
+        if offset == 0 {
+            return Ok(());
+        }
+
+        if offset == 0xff {
+            addr += 0xfe;
+            continue;
+        }
+
+        addr += offset as isize;
+
+        let type_byte = reader.byte()?;
+        let delta = deltas.of(type_byte & 0x0f)?;
+        let ix = addr as usize;
+
+        match type_byte & 0xe0 {
+            RELOC_WORD => {
+                if ix + 1 >= segment.len() {
+                    return Err(invalid("relocation entry outside of its segment"));
+                }
+
+                let value = ((segment[ix] as u16) | ((segment[ix + 1] as u16) << 8)).wrapping_add(delta);
+                segment[ix]     = (value & 0xff) as u8;
+                segment[ix + 1] = (value >> 8) as u8;
+            }
+            RELOC_HIGH => {
+                // the low byte of the full address is stored in the table to carry correctly
+                let lo = reader.byte()? as u16;
+                let slot = segment.get_mut(ix).ok_or_else(|| invalid("relocation entry outside of its segment"))?;
+
+                *slot = ((((*slot as u16) << 8) | lo).wrapping_add(delta) >> 8) as u8;
+            }
+            RELOC_LOW => {
+                let slot = segment.get_mut(ix).ok_or_else(|| invalid("relocation entry outside of its segment"))?;
+                *slot = slot.wrapping_add((delta & 0xff) as u8);
+            }
+            _ => return Err(invalid("unsupported relocation type"))
+        }
+    }
+}
+
+// relocates the text segment to `base`, with data and bss following it.
+// zero page references are kept where the object placed them
+pub fn load(bytes: &[u8], base: u16) -> io::Result<Object> {
+    let mut reader = Reader { bytes, pos: 0 };
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(invalid("not an o65 object"));
+    }
+    reader.byte()?; // version
+
+    let mode = reader.word()?;
+    if mode & (MODE_65816 | MODE_SIZE_32) != 0 {
+        return Err(invalid("65816 and 32 bit objects are not supported"));
+    }
+    if mode & MODE_PAGED != 0 {
+        return Err(invalid("pagewise relocation is not supported"));
+    }
+
+    let tbase = reader.word()?;
+    let tlen  = reader.word()?;
+    let dbase = reader.word()?;
+    let dlen  = reader.word()?;
+    let bbase = reader.word()?;
+    reader.word()?; // blen
+    reader.word()?; // zbase
+    reader.word()?; // zlen
+    reader.word()?; // stack
+
+    // header options, each prefixed by its total length
+    loop {
+        let len = reader.byte()?;
+        if len == 0 {
+            break;
+        }
+
+        reader.take(len as usize - 1)?;
+    }
+
+    let mut text = reader.take(tlen as usize)?;
+    let mut data = reader.take(dlen as usize)?;
+
+    let undefined = reader.word()?;
+    for _ in 0 .. undefined {
+        reader.skip_string()?;
+    }
+
+    let text_base = base;
+    let data_base = base.wrapping_add(tlen);
+    let bss_base  = data_base.wrapping_add(dlen);
+
+    let deltas = Deltas([
+        text_base.wrapping_sub(tbase),
+        data_base.wrapping_sub(dbase),
+        bss_base.wrapping_sub(bbase),
+        0
+    ]);
+
+    relocate(&mut reader, &mut text, &deltas)?;
+    relocate(&mut reader, &mut data, &deltas)?;
+
+    return Ok(Object { text_base, text, data_base, data });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LDA data; JMP start, assembled for text at $1000 and data at $2000
+    const OBJECT: [u8; 42] = [
+        0x01, 0x00, b'o', b'6', b'5', 0x00,
+        0x00, 0x00,
+        0x00, 0x10, 0x06, 0x00,
+        0x00, 0x20, 0x01, 0x00,
+        0x00, 0x30, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00,
+        0x00,
+        0xad, 0x00, 0x20, 0x4c, 0x00, 0x10,
+        0x55,
+        0x00, 0x00,
+        // words at offsets 1 (data) and 4 (text), then nothing in the data segment
+        0x02, RELOC_WORD | SEG_DATA, 0x03, RELOC_WORD | SEG_TEXT, 0x00,
+        0x00
+    ];
+
+    #[test]
+    fn relocated_references_point_into_the_moved_segments() {
+        let object = load(&OBJECT, 0x0400).unwrap();
+
+        assert_eq!(object.text_base, 0x0400);
+        assert_eq!(object.data_base, 0x0406);
+        assert_eq!(object.text, [0xad, 0x06, 0x04, 0x4c, 0x00, 0x04]);
+        assert_eq!(object.data, [0x55]);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(load(b"\x01\x00o64", 0x0400).is_err());
+    }
+}
@@ -37,6 +37,12 @@ pub const STY_ZERO_PAGE  : u8 = 0x84;
 pub const STY_ZERO_PAGE_X: u8 = 0x94;
 pub const STY_ABSOLUTE   : u8 = 0x8c;
 
+// 65C02
+pub const STZ_ZERO_PAGE  : u8 = 0x64;
+pub const STZ_ZERO_PAGE_X: u8 = 0x74;
+pub const STZ_ABSOLUTE   : u8 = 0x9c;
+pub const STZ_ABSOLUTE_X : u8 = 0x9e;
+
 pub const JMP_ABSOLUTE: u8 = 0x4c;
 pub const JMP_INDIRECT: u8 = 0x6c;
 
@@ -69,6 +75,12 @@ pub const PHP: u8 = 0x08;
 pub const PLA: u8 = 0x68;
 pub const PLP: u8 = 0x28;
 
+// 65C02
+pub const PHX: u8 = 0xda;
+pub const PHY: u8 = 0x5a;
+pub const PLX: u8 = 0xfa;
+pub const PLY: u8 = 0x7a;
+
 pub const AND_IMMEDIATE  : u8 = 0x29;
 pub const AND_ZERO_PAGE  : u8 = 0x25;
 pub const AND_ZERO_PAGE_X: u8 = 0x35;
@@ -107,6 +119,7 @@ pub const BMI: u8 = 0x30;
 pub const BPL: u8 = 0x10;
 pub const BVC: u8 = 0x50;
 pub const BVS: u8 = 0x70;
+pub const BRA: u8 = 0x80; // 65C02
 
 pub const CLC: u8 = 0x18;
 pub const SEC: u8 = 0x38;
@@ -177,3 +190,48 @@ pub const ROR_ABSOLUTE_X : u8 = 0x7e;
 
 pub const BRK: u8 = 0x00;
 pub const RTI: u8 = 0x40;
+
+pub const WAI: u8 = 0xcb; // 65C02
+pub const STP: u8 = 0xdb; // 65C02
+
+// KIL / JAM, these lock the NMOS 6502 up until reset
+pub fn is_jam(opcode: u8) -> bool {
+    return matches!(opcode, 0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2 | 0xf2);
+}
+
+// base cycle counts, indexed by opcode. the 65C02 additions use their 65C02 counts,
+// unimplemented opcodes keep the count of their NMOS illegal counterpart
+pub const CYCLES: [u8; 256] = [
+//  0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F
+    7, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6, // 0
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 1
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6, // 2
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 3
+    6, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6, // 4
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 3, 7, 4, 4, 7, 7, // 5
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6, // 6
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 4, 7, 4, 4, 7, 7, // 7
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // 8
+    2, 6, 2, 6, 4, 4, 4, 4, 2, 5, 2, 5, 4, 5, 5, 5, // 9
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // A
+    2, 5, 2, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4, // B
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 3, 4, 4, 6, 6, // C
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 3, 3, 4, 4, 7, 7, // D
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // E
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 4, 7, 4, 4, 7, 7  // F
+];
+
+// indexed reads take one more cycle when the effective address crosses a page.
+// stores and read-modify-write instructions always pay it, so it's in their base count
+pub fn page_penalty(opcode: u8) -> bool {
+    return matches!(opcode,
+        LDA_ABSOLUTE_X | LDA_ABSOLUTE_Y | LDA_INDIRECT_Y |
+        LDX_ABSOLUTE_Y | LDY_ABSOLUTE_X |
+        AND_ABSOLUTE_X | AND_ABSOLUTE_Y | AND_INDIRECT_Y |
+        ORA_ABSOLUTE_X | ORA_ABSOLUTE_Y | ORA_INDIRECT_Y |
+        EOR_ABSOLUTE_X | EOR_ABSOLUTE_Y | EOR_INDIRECT_Y |
+        ADC_ABSOLUTE_X | ADC_ABSOLUTE_Y | ADC_INDIRECT_Y |
+        SBC_ABSOLUTE_X | SBC_ABSOLUTE_Y | SBC_INDIRECT_Y |
+        CMP_ABSOLUTE_X | CMP_ABSOLUTE_Y | CMP_INDIRECT_Y
+    );
+}
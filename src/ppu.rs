@@ -1,125 +1,522 @@
-#![allow(arithmetic_overflow)]
-
-use crate::mapper;
-use std::cell::RefCell;
-use std::fs::File;
-use std::io::Read;
-use std::rc::Rc;
-use speedy2d::color::Color;
-
-// character set: https://opengameart.org/content/ascii-bitmap-font-oldschool
-
-pub const INTERNAL_RESOLUTION_X: u16 = 448;
-pub const INTERNAL_RESOLUTION_Y: u16 = 470; // base is 288 + ui
-
-pub const CHAR_X: u16 = 7;
-pub const CHAR_Y: u16 = 9;
-
-const RESOLUTION_X: u8 = 64;
-const RESOLUTION_Y: u8 = 32;
-
-const DOUBLE_RESOLUTION_X: u16 = (2 * RESOLUTION_X) as u16;
-
-const FRAMEBUFFER_START: u16 = 0x6010; // ends at 0x7010
-
-const COLOR_PALETTE: [[f32; 3]; 16] = [
-    [ 0.0,  0.0,  0.0],
-    [ 0.0,  0.0,  0.5],
-    [ 0.0,  0.5,  0.0],
-    [ 0.0,  0.5,  0.5],
-    [ 0.5,  0.0,  0.0],
-    [ 0.5,  0.0,  0.5],
-    [ 0.5,  0.5,  0.0],
-    [ 0.5,  0.5,  0.5],
-    [0.25, 0.25, 0.25],
-    [ 0.0,  0.0,  1.0],
-    [ 0.0,  1.0,  0.0],
-    [ 0.0,  1.0,  1.0],
-    [ 1.0,  0.0,  0.0],
-    [ 1.0,  0.0,  1.0],
-    [ 1.0,  1.0,  0.0],
-    [ 1.0,  1.0,  1.0]
-];
-
-pub struct PPU {
-    chars        : Vec<Vec<u8>>,
-    pub frame_buf: Vec<Vec<Color>>,
-
-    mapper: Rc<RefCell<mapper::Map>>
-}
-
-impl PPU {
-    pub fn new(mapper: Rc<RefCell<mapper::Map>>, charset: &str) -> Self {
-        let mut chars: Vec<Vec<u8>> = Vec::new();
-        let mut file = File::open(charset)
-            .expect("Couldn't open charset file");
-
-        loop {
-            let mut chunk: Vec<u8> = Vec::with_capacity(CHAR_Y as usize);
-            let i = file.by_ref().take(CHAR_Y as u64)
-                .read_to_end(&mut chunk).unwrap();
-
-            if i == 0 {
-                break;
-            }
-
-            chars.push(chunk);
-
-            if i < CHAR_Y as usize {
-                break;
-            }
-        }
-
-        return PPU {
-            mapper, chars,
-            frame_buf: vec![vec![Color::BLUE; INTERNAL_RESOLUTION_X as usize]; INTERNAL_RESOLUTION_Y as usize]
-        }
-    }
-
-    pub fn draw_char_at(&mut self, x: u8, y: u8, chr: u8, ch_color: Color, bg_color: Color) {
-        let lx = ((x as u16) * CHAR_X) as usize;
-        let ly = ((y as u16) * CHAR_Y) as usize;
-        let ch = self.chars.get((chr & 0x7f) as usize).unwrap();
-
-        for ccy in 0 .. CHAR_Y {
-            let line = ch.get(ccy as usize).unwrap();
-
-            for ccx in 0 .. CHAR_X {
-                if line & (1 << ccx) != 0 {
-                    *(self.frame_buf.get_mut(ly + ccy as usize).unwrap()
-                        .get_mut(lx + ccx as usize).unwrap()) = ch_color.clone();
-                } else {
-                    *(self.frame_buf.get_mut(ly + ccy as usize).unwrap()
-                        .get_mut(lx + ccx as usize).unwrap()) = bg_color.clone();
-                }
-            }
-        }
-    }
-
-    pub fn tick(&mut self) {
-        for y in 0 .. RESOLUTION_Y {
-            let mut cx: u16 = 0;
-            for x in 0 .. RESOLUTION_X {
-                let data = (*self.mapper.borrow()).read_word(
-                    FRAMEBUFFER_START + cx + (y as u16 * DOUBLE_RESOLUTION_X)
-                );
-
-                cx += 2;
-
-                let ch = COLOR_PALETTE[((data >> 8) & 0x0f) as usize];
-                let bg = COLOR_PALETTE[(data >> 12) as usize];
-
-                self.draw_char_at(
-                    x, y, 
-                    (data & 0x00ff) as u8, 
-                    Color::from_rgb(
-                        ch[0], ch[1], ch[2]
-                    ), 
-                    Color::from_rgb(
-                        bg[0], bg[1], bg[2]
-                    )
-                );
-            }
-        }
-    }
-}
\ No newline at end of file
+#![allow(arithmetic_overflow)]
+
+use crate::error::EmuError;
+use crate::interface_adapter::{self, Cursor, Sprite};
+use crate::mapper;
+use std::cell::RefCell;
+use std::cmp;
+use std::fs;
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use speedy2d::color::Color;
+
+// character set: https://opengameart.org/content/ascii-bitmap-font-oldschool
+
+// frame buffer size of the default 64x32 text mode
+pub const INTERNAL_RESOLUTION_X: u16 = 448;
+pub const INTERNAL_RESOLUTION_Y: u16 = 470; // base is 288 + ui
+
+pub const CHAR_X: u16 = 7;
+pub const CHAR_Y: u16 = 9;
+
+pub const DEFAULT_COLUMNS: u8 = 64;
+pub const DEFAULT_ROWS   : u8 = 32;
+
+// the debug panel below the text area needs at least this much room
+const UI_COLUMNS: u16 = 64;
+const UI_HEIGHT : u16 = INTERNAL_RESOLUTION_Y - DEFAULT_ROWS as u16 * CHAR_Y;
+
+// two pixels per framebuffer byte in bitmap mode
+const FRAMEBUFFER_PIXELS: usize = (mapper::FRAMEBUFFER_END - mapper::FRAMEBUFFER_START) as usize * 2;
+
+// how long a blinking cursor stays on and off. the window doesn't
+// draw at a fixed rate, so this goes by time instead of frames
+const CURSOR_BLINK_PERIOD: Duration = Duration::from_millis(500);
+
+pub const DEFAULT_PALETTE: [[f32; 3]; 16] = [
+    [ 0.0,  0.0,  0.0],
+    [ 0.0,  0.0,  0.5],
+    [ 0.0,  0.5,  0.0],
+    [ 0.0,  0.5,  0.5],
+    [ 0.5,  0.0,  0.0],
+    [ 0.5,  0.0,  0.5],
+    [ 0.5,  0.5,  0.0],
+    [ 0.5,  0.5,  0.5],
+    [0.25, 0.25, 0.25],
+    [ 0.0,  0.0,  1.0],
+    [ 0.0,  1.0,  0.0],
+    [ 0.0,  1.0,  1.0],
+    [ 1.0,  0.0,  0.0],
+    [ 1.0,  0.0,  1.0],
+    [ 1.0,  1.0,  0.0],
+    [ 1.0,  1.0,  1.0]
+];
+
+fn rgba_bytes(color: Color) -> [u8; 4] {
+    let byte = |c: f32| (c * 255.0).round() as u8;
+    return [byte(color.r()), byte(color.g()), byte(color.b()), byte(color.a())];
+}
+
+pub struct PPU {
+    chars        : Vec<Vec<u8>>,
+    glyph_height : u16,
+    pub frame_buf: Vec<Vec<Color>>,
+
+    columns: u8,
+    rows:    u8,
+    palette: [[f32; 3]; 16],
+
+    // frame_buf as RGBA bytes, ready to upload as a texture
+    rgba: Vec<u8>,
+
+    cursor_shown: bool,
+    blink_timer:  Instant,
+
+    // bitmap mode resolution, and how many screen pixels wide and tall each pixel is
+    bitmap_size : (u16, u16),
+    bitmap_scale: u16,
+
+    mapper: Rc<RefCell<mapper::Map>>
+}
+
+impl PPU {
+    pub fn new_default(mapper: Rc<RefCell<mapper::Map>>, charset: &str) -> Result<Self, EmuError> {
+        return PPU::new(mapper, charset, CHAR_Y, DEFAULT_COLUMNS, DEFAULT_ROWS, DEFAULT_PALETTE);
+    }
+
+    // every cell takes two bytes of the framebuffer, so the text mode has to fit in it.
+    // the charset is a list of glyphs, `glyph_height` rows of one byte each
+    pub fn new(
+            mapper: Rc<RefCell<mapper::Map>>, charset: &str, glyph_height: u16,
+            columns: u8, rows: u8, palette: [[f32; 3]; 16]
+    ) -> Result<Self, EmuError> {
+        let cells = columns as usize * rows as usize;
+        if cells == 0 || cells * 2 > (mapper::FRAMEBUFFER_END - mapper::FRAMEBUFFER_START) as usize {
+            return Err(EmuError::InvalidConfig(format!("a {}x{} text mode doesn't fit in the framebuffer", columns, rows)));
+        }
+
+        if glyph_height == 0 {
+            return Err(EmuError::InvalidConfig("glyphs need at least one row".to_string()));
+        }
+
+        let bytes = fs::read(charset)
+            .map_err(|err| EmuError::CharsetLoad(charset.to_string(), err))?;
+
+        if bytes.is_empty() || bytes.len() % glyph_height as usize != 0 {
+            return Err(EmuError::CharsetLoad(charset.to_string(), io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} bytes isn't a whole number of {} row glyphs", bytes.len(), glyph_height)
+            )));
+        }
+
+        let chars: Vec<Vec<u8>> = bytes.chunks_exact(glyph_height as usize)
+            .map(|glyph| glyph.to_vec())
+            .collect();
+
+        // the panel keeps the same number of text rows whatever the glyph height
+        let width  = cmp::max(columns as u16, UI_COLUMNS) * CHAR_X;
+        let height = rows as u16 * glyph_height + UI_HEIGHT * glyph_height / CHAR_Y;
+
+        // the bitmap covers the text area with the smallest scale whose
+        // 4 bit pixels fit in the framebuffer, 112x72 in the default mode
+        let (text_width, text_height) = (columns as u16 * CHAR_X, rows as u16 * glyph_height);
+        let bitmap_scale = (1 ..)
+            .find(|&scale| (text_width / scale) as usize * (text_height / scale) as usize <= FRAMEBUFFER_PIXELS)
+            .unwrap();
+
+        return Ok(PPU {
+            mapper, chars, glyph_height, columns, rows, palette,
+            frame_buf: vec![vec![Color::BLUE; width as usize]; height as usize],
+            rgba: rgba_bytes(Color::BLUE).repeat(width as usize * height as usize),
+            cursor_shown: true, blink_timer: Instant::now(),
+            bitmap_size: (text_width / bitmap_scale, text_height / bitmap_scale), bitmap_scale
+        });
+    }
+
+    #[allow(unused)]
+    pub fn glyph_count(&self) -> usize {
+        return self.chars.len();
+    }
+
+    pub fn glyph_height(&self) -> u16 {
+        return self.glyph_height;
+    }
+
+    // (columns, rows) of the text area
+    pub fn text_size(&self) -> (u8, u8) {
+        return (self.columns, self.rows);
+    }
+
+    // codes are masked to the charset size rounded up to a power of two,
+    // so a 128 glyph charset keeps ignoring the top bit. anything still
+    // past the end draws glyph 0
+    fn glyph_index(&self, chr: u8) -> usize {
+        let ix = chr as usize & (self.chars.len().next_power_of_two() - 1);
+
+        if ix < self.chars.len() {
+            return ix;
+        }
+
+        return 0;
+    }
+
+    pub fn width(&self) -> u16 {
+        return self.frame_buf[0].len() as u16;
+    }
+
+    pub fn height(&self) -> u16 {
+        return self.frame_buf.len() as u16;
+    }
+
+    pub fn palette(&self) -> &[[f32; 3]; 16] {
+        return &self.palette;
+    }
+
+    // the window draws this as a single texture instead of touching frame_buf
+    pub fn present(&self) -> &[u8] {
+        return &self.rgba;
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: Color) {
+        let ix = (y * self.frame_buf[0].len() + x) * 4;
+
+        self.frame_buf[y][x] = color;
+        self.rgba[ix .. ix + 4].copy_from_slice(&rgba_bytes(color));
+    }
+
+    pub fn draw_char_at(&mut self, x: u8, y: u8, chr: u8, ch_color: Color, bg_color: Color) {
+        let lx = ((x as u16) * CHAR_X) as usize;
+        let ly = ((y as u16) * self.glyph_height) as usize;
+        let glyph = self.glyph_index(chr);
+
+        for ccy in 0 .. self.glyph_height {
+            let line = self.chars[glyph][ccy as usize];
+
+            for ccx in 0 .. CHAR_X {
+                let color = if line & (1 << ccx) != 0 { ch_color } else { bg_color };
+                self.set_pixel(lx + ccx as usize, ly + ccy as usize, color);
+            }
+        }
+    }
+
+    // sprites are transparent where the glyph has no pixels,
+    // and are clipped to the text area
+    fn draw_sprite(&mut self, sprite: &Sprite) {
+        let glyph = self.glyph_index(sprite.glyph);
+        let color = self.palette[(sprite.attr & 0x0f) as usize];
+        let color = Color::from_rgb(color[0], color[1], color[2]);
+
+        for ccy in 0 .. self.glyph_height {
+            let py = sprite.y as usize + ccy as usize;
+            if py >= (self.rows as usize) * (self.glyph_height as usize) {
+                break;
+            }
+
+            let line = self.chars[glyph][ccy as usize];
+
+            for ccx in 0 .. CHAR_X {
+                let px = sprite.x as usize + ccx as usize;
+                if px >= (self.columns as usize) * (CHAR_X as usize) {
+                    break;
+                }
+
+                if line & (1 << ccx) != 0 {
+                    self.set_pixel(px, py, color);
+                }
+            }
+        }
+    }
+
+    // flips a blinking cursor once per period, true when its cell was queued for redrawing
+    pub fn blink(&mut self) -> bool {
+        let cursor = self.mapper.borrow().int_adapter.cursor;
+        if !cursor.enabled() || !cursor.blinks() || self.blink_timer.elapsed() < CURSOR_BLINK_PERIOD {
+            return false;
+        }
+
+        self.blink_timer  = Instant::now();
+        self.cursor_shown = !self.cursor_shown;
+
+        self.mapper.borrow_mut().mark_cell(cursor.y as u16 * self.columns as u16 + cursor.x as u16);
+        return true;
+    }
+
+    // the cell the cursor is drawn on right now, if any
+    fn cursor_cell(&self, cursor: Cursor) -> Option<u16> {
+        if !cursor.enabled() || (cursor.blinks() && !self.cursor_shown) ||
+            cursor.x >= self.columns || cursor.y >= self.rows {
+            return None;
+        }
+
+        return Some(cursor.y as u16 * self.columns as u16 + cursor.x as u16);
+    }
+
+    fn draw_cell(&mut self, fbuf: &[u8], cell: u16, inverted: bool) {
+        let offset = cell as usize * 2;
+        let data   = (fbuf[offset] as u16) | ((fbuf[offset + 1] as u16) << 8);
+
+        let mut ch = self.palette[((data >> 8) & 0x0f) as usize];
+        let mut bg = self.palette[(data >> 12) as usize];
+
+        if inverted {
+            (ch, bg) = (bg, ch);
+        }
+
+        self.draw_char_at(
+            (cell % self.columns as u16) as u8,
+            (cell / self.columns as u16) as u8,
+            (data & 0x00ff) as u8, 
+            Color::from_rgb(
+                ch[0], ch[1], ch[2]
+            ), 
+            Color::from_rgb(
+                bg[0], bg[1], bg[2]
+            )
+        );
+    }
+
+    // the bitmap doesn't always cover the text area exactly, the rest is left in color 0
+    fn clear_bitmap_margin(&mut self) {
+        let width  = (self.bitmap_size.0 * self.bitmap_scale) as usize;
+        let height = (self.bitmap_size.1 * self.bitmap_scale) as usize;
+        let color  = Color::from_rgb(self.palette[0][0], self.palette[0][1], self.palette[0][2]);
+
+        for y in 0 .. self.rows as usize * self.glyph_height as usize {
+            for x in 0 .. self.columns as usize * CHAR_X as usize {
+                if x >= width || y >= height {
+                    self.set_pixel(x, y, color);
+                }
+            }
+        }
+    }
+
+    // in bitmap mode a cell is the 4 pixels packed in its two bytes, low nibble first
+    fn draw_pixels(&mut self, fbuf: &[u8], cell: u16) {
+        let (width, height) = (self.bitmap_size.0 as usize, self.bitmap_size.1 as usize);
+        let scale = self.bitmap_scale as usize;
+
+        for pixel in cell as usize * 4 .. cell as usize * 4 + 4 {
+            if pixel >= width * height {
+                return;
+            }
+
+            let byte  = fbuf[pixel / 2];
+            let index = if pixel % 2 == 0 { byte & 0x0f } else { byte >> 4 };
+            let color = self.palette[index as usize];
+            let color = Color::from_rgb(color[0], color[1], color[2]);
+
+            let (x, y) = (pixel % width * scale, pixel / width * scale);
+            for py in y .. y + scale {
+                for px in x .. x + scale {
+                    self.set_pixel(px, py, color);
+                }
+            }
+        }
+    }
+
+    // only re-renders the cells written since the last tick. in text mode every
+    // framebuffer word is a character and its colors, in bitmap mode it's 4 pixels
+    pub fn tick(&mut self) {
+        let mapper = Rc::clone(&self.mapper);
+        let mut map = mapper.borrow_mut();
+
+        let bitmap = map.int_adapter.video_mode == interface_adapter::VIDEO_BITMAP;
+        let cells  = if bitmap {
+            (self.bitmap_size.0 as usize * self.bitmap_size.1 as usize).div_ceil(4) as u16
+        } else {
+            self.columns as u16 * self.rows as u16
+        };
+
+        let cursor = self.cursor_cell(map.int_adapter.cursor);
+        let dirty  = map.take_dirty_cells();
+        let fbuf   = map.framebuffer_slice();
+
+        if bitmap && dirty.is_none() {
+            self.clear_bitmap_margin();
+        }
+
+        let dirty = dirty.unwrap_or_else(|| (0 .. cells).collect());
+
+        for cell in dirty.into_iter().filter(|&cell| cell < cells) {
+            if bitmap {
+                self.draw_pixels(fbuf, cell);
+            } else {
+                self.draw_cell(fbuf, cell, cursor == Some(cell));
+            }
+        }
+
+        // sprites are redrawn over whatever is below them, moving
+        // one goes through the adapter and redraws everything
+        let sprites = map.int_adapter.sprites;
+        for sprite in sprites.iter().filter(|sprite| sprite.enabled()) {
+            self.draw_sprite(sprite);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bus::Bus;
+    use interface_adapter::{EXT_SPRITES, SPRITE_ENABLE};
+
+    fn blank_map() -> Rc<RefCell<mapper::Map>> {
+        return Rc::new(RefCell::new(mapper::Map::from_reader(&vec![0; 0x8000][..], mapper::MemoryMap::DEFAULT).unwrap()));
+    }
+
+    // a blank framebuffer drawn with the default text mode and charset
+    fn test_ppu() -> PPU {
+        return PPU::new_default(blank_map(), "charset.bin").unwrap();
+    }
+
+    #[test]
+    fn sprite_overwrites_the_background_with_its_glyph() {
+        let mut ppu = test_ppu();
+        {
+            let mut map = ppu.mapper.borrow_mut();
+            // sprite 0 with glyph 'A' in white at (10, 12)
+            for (register, value) in [(0, 10), (1, 0), (2, 12), (3, 0), (4, b'A'), (5, SPRITE_ENABLE | 0x0f)] {
+                map.int_adapter.write_ext(value, EXT_SPRITES + register);
+            }
+        }
+        ppu.tick();
+
+        let glyph = ppu.chars[ppu.glyph_index(b'A')].clone();
+        assert!(glyph.iter().any(|&line| line != 0));
+
+        for (ccy, line) in glyph.iter().enumerate() {
+            for ccx in 0 .. CHAR_X as usize {
+                let expected = if line & (1 << ccx) != 0 { Color::WHITE } else { Color::BLACK };
+                assert_eq!(ppu.frame_buf[12 + ccy][10 + ccx], expected, "pixel ({}, {})", ccx, ccy);
+            }
+        }
+
+        // the cells around it are still the background
+        assert_eq!(ppu.frame_buf[12][9], Color::BLACK);
+        assert_eq!(ppu.frame_buf[12 + CHAR_Y as usize][10], Color::BLACK);
+    }
+
+
+    #[test]
+    fn frame_buf_fits_a_40x25_text_mode() {
+        let ppu = PPU::new(blank_map(), "charset.bin", CHAR_Y, 40, 25, DEFAULT_PALETTE).unwrap();
+
+        assert_eq!(ppu.text_size(), (40, 25));
+        // the debug panel keeps it at least UI_COLUMNS wide
+        assert_eq!(ppu.width(), UI_COLUMNS * CHAR_X);
+        assert_eq!(ppu.height(), 25 * CHAR_Y + UI_HEIGHT);
+        assert_eq!(ppu.frame_buf.len(), ppu.height() as usize);
+        assert!(ppu.frame_buf.iter().all(|row| row.len() == ppu.width() as usize));
+    }
+
+    #[test]
+    fn text_mode_has_to_fit_in_the_framebuffer() {
+        assert!(PPU::new(blank_map(), "charset.bin", CHAR_Y, 200, 200, DEFAULT_PALETTE).is_err());
+    }
+
+
+    #[test]
+    fn full_charset_renders_codes_past_127() {
+        // 256 glyphs, solid from 128 on and blank below
+        let glyphs: Vec<u8> = (0 ..= 255u8).flat_map(|chr| [if chr >= 128 { 0x7f } else { 0x00 }; CHAR_Y as usize]).collect();
+        let path = std::env::temp_dir().join(format!("emu6502-charset-{}.bin", std::process::id()));
+        fs::write(&path, glyphs).unwrap();
+
+        let map = blank_map();
+        let ppu = PPU::new_default(map.clone(), path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        let mut ppu = ppu.unwrap();
+        assert_eq!(ppu.glyph_count(), 256);
+
+        // code $C0 in white on black in the first cell
+        map.borrow_mut().write_word(0x0fc0, mapper::FRAMEBUFFER_START).unwrap();
+        ppu.tick();
+
+        assert_eq!(ppu.frame_buf[0][0], Color::WHITE);
+        assert_eq!(ppu.frame_buf[CHAR_Y as usize - 1][CHAR_X as usize - 1], Color::WHITE);
+    }
+
+    #[test]
+    fn charset_must_be_whole_glyphs() {
+        let path = std::env::temp_dir().join(format!("emu6502-short-charset-{}.bin", std::process::id()));
+        fs::write(&path, [0; 10]).unwrap();
+
+        let ppu = PPU::new_default(blank_map(), path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(ppu, Err(EmuError::CharsetLoad(..))));
+    }
+
+
+    #[test]
+    fn rgba_buffer_of_a_full_frame() {
+        let mut ppu = test_ppu();
+        {
+            let mut map = ppu.mapper.borrow_mut();
+            for cell in 0 .. DEFAULT_COLUMNS as u16 * DEFAULT_ROWS as u16 {
+                map.write_word(0x1f00 | (cell & 0x7f), mapper::FRAMEBUFFER_START + cell * 2).unwrap();
+            }
+        }
+
+        let start = Instant::now();
+        ppu.tick();
+        let rgba = ppu.present();
+        println!("full frame to RGBA in {:?}", start.elapsed());
+
+        assert_eq!(rgba.len(), ppu.width() as usize * ppu.height() as usize * 4);
+        for (ix, pixel) in rgba.chunks_exact(4).enumerate().step_by(97) {
+            let color = ppu.frame_buf[ix / ppu.width() as usize][ix % ppu.width() as usize];
+            assert_eq!(pixel, rgba_bytes(color));
+        }
+    }
+
+
+    #[test]
+    fn cursor_inverts_the_cell_it_is_on() {
+        let mut ppu = test_ppu();
+        {
+            let mut map = ppu.mapper.borrow_mut();
+            // 'A' in white on black in the first two cells, the cursor on the second
+            map.write_word(0x0f00 | b'A' as u16, mapper::FRAMEBUFFER_START).unwrap();
+            map.write_word(0x0f00 | b'A' as u16, mapper::FRAMEBUFFER_START + 2).unwrap();
+            map.int_adapter.write_ext(1, interface_adapter::EXT_CURSOR_X);
+            map.int_adapter.write_ext(interface_adapter::CURSOR_ENABLE, interface_adapter::EXT_CURSOR_CTRL);
+        }
+        ppu.tick();
+
+        let glyph = ppu.chars[ppu.glyph_index(b'A')].clone();
+        for (ccy, line) in glyph.iter().enumerate() {
+            for ccx in 0 .. CHAR_X as usize {
+                let plain = ppu.frame_buf[ccy][ccx];
+                let under_cursor = ppu.frame_buf[ccy][CHAR_X as usize + ccx];
+
+                assert_eq!(plain, if line & (1 << ccx) != 0 { Color::WHITE } else { Color::BLACK });
+                assert_ne!(plain, under_cursor);
+            }
+        }
+    }
+
+
+    #[test]
+    fn bitmap_nibbles_index_the_palette() {
+        let mut ppu = test_ppu();
+        {
+            let mut map = ppu.mapper.borrow_mut();
+            map.int_adapter.write_ext(interface_adapter::VIDEO_BITMAP, interface_adapter::EXT_VIDEO_MODE);
+            // low nibble first: red, then green
+            map.write_byte(0x2c, mapper::FRAMEBUFFER_START).unwrap();
+        }
+        ppu.tick();
+
+        let color = |index: usize| Color::from_rgb(DEFAULT_PALETTE[index][0], DEFAULT_PALETTE[index][1], DEFAULT_PALETTE[index][2]);
+        let scale = ppu.bitmap_scale as usize;
+        assert_eq!(ppu.bitmap_size, (112, 72));
+
+        assert_eq!(ppu.frame_buf[0][0], color(0xc));
+        assert_eq!(ppu.frame_buf[scale - 1][scale - 1], color(0xc));
+        assert_eq!(ppu.frame_buf[0][scale], color(0x2));
+        assert_eq!(ppu.frame_buf[0][scale * 2], color(0x0));
+    }
+}
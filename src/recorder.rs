@@ -0,0 +1,172 @@
+use std::fs::File;
+use std::io;
+use std::time::Instant;
+
+use gif::{Encoder, Frame, Repeat};
+use speedy2d::color::Color;
+
+use crate::error::EmuError;
+use crate::ppu;
+
+// records rendered frames into an animated gif, using the 16 color palette
+pub struct GifRecorder {
+    encoder: Encoder<File>,
+    palette: [[f32; 3]; 16],
+    width:   u16,
+    height:  u16,
+
+    frames_left: u32,
+    // a frame's delay is only known once the next one is rendered,
+    // so the last captured frame waits here until then
+    pending:    Option<Frame<'static>>,
+    last_frame: Instant,
+
+    filename: String
+}
+
+fn nearest_color(palette: &[[f32; 3]; 16], color: &Color) -> u8 {
+    let mut best = 0;
+    let mut best_dist = f32::MAX;
+
+    for (i, entry) in palette.iter().enumerate() {
+        let dr = entry[0] - color.r();
+        let dg = entry[1] - color.g();
+        let db = entry[2] - color.b();
+        let dist = dr * dr + dg * dg + db * db;
+
+        if dist < best_dist {
+            best_dist = dist;
+            best = i as u8;
+        }
+    }
+
+    return best;
+}
+
+// palette indices of a frame buffer, row by row
+fn quantize(palette: &[[f32; 3]; 16], frame_buf: &[Vec<Color>]) -> Vec<u8> {
+    return frame_buf.iter().flatten().map(|color| nearest_color(palette, color)).collect();
+}
+
+impl GifRecorder {
+    pub fn new(filename: &str, frames: u32, ppu: &ppu::PPU) -> Result<Self, EmuError> {
+        return GifRecorder::with_palette(filename, frames, ppu.width(), ppu.height(), ppu.palette());
+    }
+
+    fn with_palette(filename: &str, frames: u32, width: u16, height: u16, colors: &[[f32; 3]; 16]) -> Result<Self, EmuError> {
+        let palette: Vec<u8> = colors.iter().flatten()
+            .map(|&channel| (channel * 255.0) as u8)
+            .collect();
+
+        let encoder = File::create(filename)
+            .and_then(|file| {
+                let mut encoder = Encoder::new(file, width, height, &palette)
+                    .map_err(io::Error::other)?;
+                encoder.set_repeat(Repeat::Infinite).map_err(io::Error::other)?;
+
+                Ok(encoder)
+            })
+            .map_err(|err| EmuError::GifRecord(filename.to_string(), err))?;
+
+        return Ok(GifRecorder {
+            encoder, palette: *colors, width, height, frames_left: frames, pending: None, last_frame: Instant::now(),
+            filename: filename.to_string()
+        });
+    }
+
+    pub fn finished(&self) -> bool {
+        return self.frames_left == 0 && self.pending.is_none();
+    }
+
+    // each frame is shown for as long as it took to render the next one.
+    // the call after the last frame only writes it out
+    pub fn capture(&mut self, frame_buf: &[Vec<Color>]) -> Result<(), EmuError> {
+        return self.capture_at(frame_buf, Instant::now());
+    }
+
+    fn capture_at(&mut self, frame_buf: &[Vec<Color>], now: Instant) -> Result<(), EmuError> {
+        self.write_pending(now)?;
+        if self.frames_left == 0 {
+            return Ok(());
+        }
+
+        self.pending = Some(Frame {
+            width:  self.width,
+            height: self.height,
+            buffer: quantize(&self.palette, frame_buf).into(),
+            ..Frame::default()
+        });
+        self.last_frame = now;
+
+        self.frames_left -= 1;
+        return Ok(());
+    }
+
+    fn write_pending(&mut self, now: Instant) -> Result<(), EmuError> {
+        if let Some(mut frame) = self.pending.take() {
+            frame.delay = (now.duration_since(self.last_frame).as_millis() / 10).min(u16::MAX as u128) as u16;
+
+            self.encoder.write_frame(&frame)
+                .map_err(|err| EmuError::GifRecord(self.filename.clone(), io::Error::other(err)))?;
+        }
+
+        return Ok(());
+    }
+}
+
+// a recording cut short still gets its last frame
+impl Drop for GifRecorder {
+    fn drop(&mut self) {
+        if let Err(err) = self.write_pending(Instant::now()) {
+            println!("{}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const PALETTE: [[f32; 3]; 16] = {
+        let mut palette = [[0.0; 3]; 16];
+        palette[1] = [1.0, 1.0, 1.0];
+        palette[2] = [1.0, 0.0, 0.0];
+        palette
+    };
+
+    #[test]
+    fn quantizes_to_the_nearest_palette_entry() {
+        let frame_buf = vec![
+            vec![Color::from_rgb(0.9, 0.9, 0.9), Color::from_rgb(0.1, 0.0, 0.0)],
+            vec![Color::from_rgb(0.8, 0.1, 0.1), Color::from_rgb(1.0, 1.0, 1.0)]
+        ];
+
+        assert_eq!(quantize(&PALETTE, &frame_buf), [1, 0, 2, 1]);
+    }
+
+    #[test]
+    fn frames_get_the_delay_until_the_next_one() {
+        let path = std::env::temp_dir().join(format!("emu6502-delays-{}.gif", std::process::id()));
+        let filename = path.to_str().unwrap();
+        let frame_buf = vec![vec![Color::BLACK; 2]; 2];
+
+        let start = Instant::now();
+        let mut recorder = GifRecorder::with_palette(filename, 2, 2, 2, &PALETTE).unwrap();
+        recorder.capture_at(&frame_buf, start).unwrap();
+        recorder.capture_at(&frame_buf, start + Duration::from_millis(100)).unwrap();
+        assert!(!recorder.finished());
+        recorder.capture_at(&frame_buf, start + Duration::from_millis(350)).unwrap();
+        assert!(recorder.finished());
+        drop(recorder);
+
+        let mut decoder = gif::DecodeOptions::new().read_info(File::open(&path).unwrap()).unwrap();
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(delays, [10, 25]);
+    }
+}
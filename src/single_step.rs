@@ -0,0 +1,174 @@
+// runs the core against the SingleStepTests (Tom Harte) 6502 ProcessorTests,
+// https://github.com/SingleStepTests/ProcessorTests/tree/main/6502/v1
+// there's a file per opcode with 10000 cases each: the registers and RAM
+// before and after the instruction, and the bus activity of every cycle.
+//
+// the data isn't part of the repo, point PROCESSOR_TESTS at the v1 directory:
+// PROCESSOR_TESTS=ProcessorTests/6502/v1 cargo test --features serde -- --ignored processor_tests
+
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::fs;
+use std::rc::Rc;
+
+use crate::bus::{FlatBus, RecordingBus};
+use crate::cpu::CPU;
+use crate::mapper::{BusAccess, BusEvent};
+
+// opcodes whose bus activity matches the hardware cycle for cycle
+const BUS_EXACT: [u8; 11] = [0xa9, 0xa5, 0xad, 0xbd, 0xb9, 0xb1, 0x85, 0x8d, 0x69, 0xe9, 0xc9];
+
+// the core skips some of the dummy accesses of these, so only the results are checked
+const STATE_ONLY: [u8; 9] = [0xb5, 0xa1, 0xe8, 0xca, 0xaa, 0x0a, 0x4a, 0x2a, 0x6a];
+
+// cases of a failing opcode that are printed before giving up on it
+const REPORTED_FAILURES: usize = 5;
+
+#[derive(Deserialize)]
+struct Registers {
+    pc: u16,
+    s:  u8,
+    a:  u8,
+    x:  u8,
+    y:  u8,
+    p:  u8,
+    ram: Vec<(u16, u8)>
+}
+
+#[derive(Deserialize)]
+struct Case {
+    name: String,
+    initial: Registers,
+    #[serde(rename = "final")]
+    expected: Registers,
+    // (address, value, "read" or "write")
+    cycles: Vec<(u16, u8, String)>
+}
+
+// B and the reserved bit don't exist in the register, so they aren't compared
+fn same_flags(a: u8, b: u8) -> bool {
+    return a | 0x30 == b | 0x30;
+}
+
+// every mismatch of the case, empty when it passed
+fn run_case(case: &Case, check_bus: bool) -> Vec<String> {
+    let mut flat = FlatBus::new();
+    for &(address, value) in &case.initial.ram {
+        flat.memory[address as usize] = value;
+    }
+
+    let bus = Rc::new(RefCell::new(RecordingBus::new(flat)));
+    let mut cpu = CPU::new(bus.clone());
+    cpu.pc = case.initial.pc;
+    cpu.sp = case.initial.s;
+    cpu.a  = case.initial.a;
+    cpu.x  = case.initial.x;
+    cpu.y  = case.initial.y;
+    cpu.set_flags(case.initial.p);
+
+    let cycles = match cpu.tick() {
+        Ok(cycles) => cycles,
+        Err(err) => return vec![err.to_string()]
+    };
+
+    let expected = &case.expected;
+    let mut errors = Vec::new();
+
+    let registers = [
+        ("PC", cpu.pc, expected.pc), ("S", cpu.sp as u16, expected.s as u16),
+        ("A", cpu.a as u16, expected.a as u16), ("X", cpu.x as u16, expected.x as u16), ("Y", cpu.y as u16, expected.y as u16)
+    ];
+    for (name, got, want) in registers {
+        if got != want {
+            errors.push(format!("{} is {:04X}, expected {:04X}", name, got, want));
+        }
+    }
+
+    if !same_flags(cpu.get_flags(), expected.p) {
+        errors.push(format!("P is {:02X}, expected {:02X}", cpu.get_flags(), expected.p));
+    }
+
+    for &(address, want) in &expected.ram {
+        let got = bus.borrow().inner.memory[address as usize];
+        if got != want {
+            errors.push(format!("{:04X} is {:02X}, expected {:02X}", address, got, want));
+        }
+    }
+
+    if cycles as usize != case.cycles.len() {
+        errors.push(format!("took {} cycles, expected {}", cycles, case.cycles.len()));
+    }
+
+    if check_bus {
+        let events = bus.borrow().take_events();
+        let wanted: Vec<BusEvent> = case.cycles.iter()
+            .map(|(address, value, kind)| BusEvent {
+                access: if kind == "read" { BusAccess::Read } else { BusAccess::Write },
+                address: *address,
+                value: *value
+            })
+            .collect();
+
+        if events != wanted {
+            errors.push(format!("bus activity was {:?}, expected {:?}", events, wanted));
+        }
+    }
+
+    return errors;
+}
+
+// the failures of every case in the file, as "name: mismatches"
+fn run_file(json: &str, check_bus: bool) -> Vec<String> {
+    let cases: Vec<Case> = serde_json::from_str(json).unwrap();
+
+    return cases.iter()
+        .filter_map(|case| {
+            let errors = run_case(case, check_bus);
+            if errors.is_empty() { None } else { Some(format!("{}: {}", case.name, errors.join(", "))) }
+        })
+        .collect();
+}
+
+// LDA #$42 at 0x0200, in the suite's format
+const SAMPLE: &str = r#"[{
+    "name": "a9 42 00",
+    "initial": { "pc": 512, "s": 253, "a": 0, "x": 0, "y": 0, "p": 38, "ram": [[512, 169], [513, 66]] },
+    "final":   { "pc": 514, "s": 253, "a": 66, "x": 0, "y": 0, "p": 36, "ram": [[512, 169], [513, 66]] },
+    "cycles":  [[512, 169, "read"], [513, 66, "read"]]
+}]"#;
+
+#[test]
+fn sample_case_passes() {
+    assert_eq!(run_file(SAMPLE, true), Vec::<String>::new());
+}
+
+#[test]
+fn wrong_expectations_are_reported() {
+    let failures = run_file(&SAMPLE.replace("\"a\": 66", "\"a\": 67"), true);
+
+    assert_eq!(failures, ["a9 42 00: A is 0042, expected 0043"]);
+}
+
+#[test]
+#[ignore]
+fn processor_tests() {
+    let dir = std::env::var("PROCESSOR_TESTS").expect("PROCESSOR_TESTS isn't set");
+    let opcodes = BUS_EXACT.iter().map(|&op| (op, true)).chain(STATE_ONLY.iter().map(|&op| (op, false)));
+
+    let mut failed = Vec::new();
+    for (opcode, check_bus) in opcodes {
+        let path = format!("{}/{:02x}.json", dir, opcode);
+        let json = fs::read_to_string(&path).unwrap_or_else(|err| panic!("couldn't read {}: {}", path, err));
+
+        let failures = run_file(&json, check_bus);
+        for failure in failures.iter().take(REPORTED_FAILURES) {
+            println!("{}", failure);
+        }
+
+        if !failures.is_empty() {
+            failed.push(format!("{:02X} ({} cases)", opcode, failures.len()));
+        }
+    }
+
+    assert!(failed.is_empty(), "failing opcodes: {}", failed.join(", "));
+}
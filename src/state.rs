@@ -0,0 +1,434 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+
+use crate::cpu::{RunState, CPU};
+use crate::interface_adapter::{Cursor, Sprite, Tone};
+use crate::mapper::Map;
+use crate::Emu;
+
+// save files start with the magic and a little endian format version,
+// bump the version whenever MachineState changes incompatibly
+const MAGIC:   [u8; 4] = *b"E65S";
+const VERSION: u16     = 1;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct CpuState {
+    pc: u16,
+    sp: u8,
+    a:  u8,
+    x:  u8,
+    y:  u8,
+    flags: u8,
+
+    cycles: u64,
+    nmi_pending: bool,
+    // stopped by STP or a KIL opcode
+    jammed: bool,
+    #[serde(default)]
+    waiting: bool
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct SpriteState {
+    x: u16,
+    y: u16,
+    glyph: u8,
+    attr: u8
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct AdapterState {
+    port_a: u8,
+    port_b: u8,
+    keyb: u8,
+    #[serde(default)]
+    joystick: u8,
+
+    mouse_x: u16,
+    mouse_y: u16,
+    click_x: u16,
+    click_y: u16,
+
+    rom_ptr: u32,
+    #[serde(default)]
+    rom_bank: u8,
+    ext_select: u8,
+    sprites: Vec<SpriteState>,
+    #[serde(default)]
+    cursor_x: u8,
+    #[serde(default)]
+    cursor_y: u8,
+    #[serde(default)]
+    cursor_ctrl: u8,
+    #[serde(default)]
+    video_mode: u8,
+    #[serde(default)]
+    tone_freq: u16,
+    #[serde(default)]
+    tone_gate: u8,
+
+    #[serde(default)]
+    mul_a: u8,
+    #[serde(default)]
+    mul_b: u8,
+
+    #[serde(default)]
+    dma_src: u16,
+    #[serde(default)]
+    dma_dst: u16,
+    #[serde(default)]
+    dma_len: u16,
+
+    #[serde(default)]
+    timer_latch: u16,
+    #[serde(default)]
+    timer_counter: u16,
+    #[serde(default)]
+    timer_ctrl: u8,
+
+    interrupt_id: u8,
+    irq_pending: bool,
+    // (interrupt id, scancode) of the events waiting behind the current one
+    #[serde(default)]
+    events: Vec<(u8, Option<u8>)>,
+    #[serde(default)]
+    key_unread: bool,
+    #[serde(default)]
+    timer_pending: bool
+}
+
+// the framebuffer lives in RAM and the sprites in the adapter,
+// so together they cover everything the PPU draws from
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct MachineState {
+    cpu: CpuState,
+    ram: Vec<u8>,
+    adapter: AdapterState
+}
+
+// the most recent states, oldest first. one is taken every `every`
+// frames and the oldest is dropped once there are `depth` of them
+pub struct Rewind {
+    every:  u32,
+    depth:  usize,
+    frames: u32,
+    states: VecDeque<MachineState>
+}
+
+impl Rewind {
+    pub fn new(every: u32, depth: usize) -> Self {
+        return Rewind { every, depth, frames: 0, states: VecDeque::with_capacity(depth) };
+    }
+
+    // called once per frame while running
+    fn tick(&mut self, cpu: &CPU, map: &Map) {
+        if self.every == 0 || self.depth == 0 {
+            return;
+        }
+
+        self.frames += 1;
+        if self.frames < self.every {
+            return;
+        }
+        self.frames = 0;
+
+        if self.states.len() == self.depth {
+            self.states.pop_front();
+        }
+
+        self.states.push_back(MachineState::capture(cpu, map));
+    }
+
+    // restores the latest state and drops it, so every call goes further back
+    fn step_back(&mut self, cpu: &mut CPU, map: &mut Map) -> bool {
+        return match self.states.pop_back() {
+            Some(state) => {
+                state.restore(cpu, map);
+                self.frames = 0;
+                true
+            }
+            None => false
+        };
+    }
+}
+
+impl MachineState {
+    // DMA runs as soon as it's started, so there's never one pending between instructions
+    fn capture(cpu: &CPU, map: &Map) -> Self {
+        let adapter = &map.int_adapter;
+        let (events, key_unread, timer_pending) = adapter.pending_events();
+
+        return MachineState {
+            cpu: CpuState {
+                pc: cpu.pc, sp: cpu.sp, a: cpu.a, x: cpu.x, y: cpu.y,
+                flags: cpu.get_flags(), cycles: cpu.cycles,
+                nmi_pending: cpu.nmi_pending,
+                jammed: cpu.run_state == RunState::Stopped,
+                waiting: cpu.run_state == RunState::WaitingForInterrupt
+            },
+            ram: map.ram.clone(),
+            adapter: AdapterState {
+                port_a: adapter.port_a, port_b: adapter.port_b, keyb: adapter.keyb, joystick: adapter.joystick,
+                mouse_x: adapter.mouse_x, mouse_y: adapter.mouse_y,
+                click_x: adapter.click_x, click_y: adapter.click_y,
+                rom_ptr: adapter.rom_ptr, rom_bank: adapter.rom_bank, ext_select: adapter.ext_select,
+                sprites: adapter.sprites.iter()
+                    .map(|sprite| SpriteState { x: sprite.x, y: sprite.y, glyph: sprite.glyph, attr: sprite.attr })
+                    .collect(),
+                cursor_x: adapter.cursor.x, cursor_y: adapter.cursor.y, cursor_ctrl: adapter.cursor.ctrl,
+                video_mode: adapter.video_mode,
+                tone_freq: adapter.tone.freq, tone_gate: adapter.tone.gate,
+                mul_a: adapter.mul_a, mul_b: adapter.mul_b,
+                dma_src: adapter.dma_src, dma_dst: adapter.dma_dst, dma_len: adapter.dma_len,
+                timer_latch: adapter.timer_latch, timer_counter: adapter.timer_counter, timer_ctrl: adapter.timer_ctrl,
+                interrupt_id: adapter.interrupt_id,
+                irq_pending: adapter.irq_asserted(),
+                events, key_unread, timer_pending
+            }
+        };
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend(serde_json::to_vec(self).unwrap());
+
+        return bytes;
+    }
+
+    fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let header = MAGIC.len() + 2;
+
+        if bytes.len() < header || bytes[.. MAGIC.len()] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a save state"));
+        }
+
+        let version = u16::from_le_bytes([bytes[MAGIC.len()], bytes[MAGIC.len() + 1]]);
+        if version != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("save state version {} isn't supported, expected {}", version, VERSION)
+            ));
+        }
+
+        return Ok(serde_json::from_slice(&bytes[header ..])?);
+    }
+
+    fn restore(self, cpu: &mut CPU, map: &mut Map) {
+        cpu.pc = self.cpu.pc;
+        cpu.sp = self.cpu.sp;
+        cpu.a  = self.cpu.a;
+        cpu.x  = self.cpu.x;
+        cpu.y  = self.cpu.y;
+        cpu.set_flags(self.cpu.flags);
+
+        cpu.cycles      = self.cpu.cycles;
+        cpu.nmi_pending = self.cpu.nmi_pending;
+        cpu.run_state   = if self.cpu.jammed {
+            RunState::Stopped
+        } else if self.cpu.waiting {
+            RunState::WaitingForInterrupt
+        } else {
+            RunState::Running
+        };
+
+        let ram_start = map.layout().ram.0;
+        map.load_image(&self.ram, ram_start);
+
+        let adapter = &mut map.int_adapter;
+        adapter.port_a = self.adapter.port_a;
+        adapter.port_b = self.adapter.port_b;
+        adapter.keyb   = self.adapter.keyb;
+        adapter.joystick = self.adapter.joystick;
+
+        adapter.mouse_x = self.adapter.mouse_x;
+        adapter.mouse_y = self.adapter.mouse_y;
+        adapter.click_x = self.adapter.click_x;
+        adapter.click_y = self.adapter.click_y;
+
+        adapter.rom_ptr    = self.adapter.rom_ptr;
+        adapter.rom_bank   = self.adapter.rom_bank;
+        adapter.ext_select = self.adapter.ext_select;
+
+        for (sprite, saved) in adapter.sprites.iter_mut().zip(self.adapter.sprites.iter()) {
+            *sprite = Sprite { x: saved.x, y: saved.y, glyph: saved.glyph, attr: saved.attr };
+        }
+        adapter.cursor = Cursor { x: self.adapter.cursor_x, y: self.adapter.cursor_y, ctrl: self.adapter.cursor_ctrl };
+        adapter.video_mode = self.adapter.video_mode;
+        adapter.tone = Tone { freq: self.adapter.tone_freq, gate: self.adapter.tone_gate };
+
+        adapter.mul_a = self.adapter.mul_a;
+        adapter.mul_b = self.adapter.mul_b;
+
+        adapter.dma_src = self.adapter.dma_src;
+        adapter.dma_dst = self.adapter.dma_dst;
+        adapter.dma_len = self.adapter.dma_len;
+
+        adapter.timer_latch   = self.adapter.timer_latch;
+        adapter.timer_counter = self.adapter.timer_counter;
+        adapter.timer_ctrl    = self.adapter.timer_ctrl;
+        adapter.display_changed = true;
+
+        adapter.set_irq_line(self.adapter.interrupt_id, self.adapter.irq_pending);
+        adapter.restore_pending_events(self.adapter.events, self.adapter.key_unread, self.adapter.timer_pending);
+    }
+}
+
+impl Emu {
+    fn machine_state(&self) -> MachineState {
+        return MachineState::capture(&self.cpu, &self.mapper.borrow());
+    }
+
+    fn restore(&mut self, state: MachineState) {
+        state.restore(&mut self.cpu, &mut self.mapper.borrow_mut());
+    }
+
+    #[allow(unused)]
+    pub fn export_state_json(&self) -> String {
+        return serde_json::to_string_pretty(&self.machine_state()).unwrap();
+    }
+
+    // ROM and cartridge contents aren't part of the state, they come from the command line
+    #[allow(unused)]
+    pub fn import_state_json(&mut self, json: &str) -> Result<(), serde_json::Error> {
+        let state: MachineState = serde_json::from_str(json)?;
+        self.restore(state);
+
+        return Ok(());
+    }
+
+    pub fn save_state(&self, path: &str) -> io::Result<()> {
+        return fs::write(path, self.machine_state().to_bytes());
+    }
+
+    // the machine is left untouched if the file can't be used
+    pub fn load_state(&mut self, path: &str) -> io::Result<()> {
+        let state = MachineState::from_bytes(&fs::read(path)?)?;
+        if state.ram.len() != self.mapper.borrow().ram.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "save state was made with a different RAM size"));
+        }
+
+        self.restore(state);
+        return Ok(());
+    }
+
+    pub fn rewind_tick(&mut self) {
+        self.rewind.tick(&self.cpu, &self.mapper.borrow());
+    }
+
+    pub fn rewind(&mut self) -> bool {
+        return self.rewind.step_back(&mut self.cpu, &mut self.mapper.borrow_mut());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::interface_adapter::KEYDOWN;
+    use crate::mapper::MemoryMap;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // LDX #$00; loop: INX; STX $10; JMP loop
+    fn counting_machine() -> (CPU, Rc<RefCell<Map>>) {
+        let mut rom = vec![0; 0x8000];
+        rom[.. 8].copy_from_slice(&[0xa2, 0x00, 0xe8, 0x86, 0x10, 0x4c, 0x02, 0x80]);
+        rom[0x7ffd] = 0x80;
+
+        let map = Rc::new(RefCell::new(Map::from_reader(&rom[..], MemoryMap::DEFAULT).unwrap()));
+        let mut cpu = CPU::new(map.clone());
+        cpu.reset();
+
+        return (cpu, map);
+    }
+
+    #[test]
+    fn save_reset_load_round_trip() {
+        let (mut cpu, map) = counting_machine();
+        cpu.run_until(|_| false, 50).unwrap();
+        (*map.borrow_mut()).int_adapter.queue_key(0x1e, KEYDOWN);
+        (*map.borrow_mut()).int_adapter.queue_key(0x30, KEYDOWN);
+
+        let saved = MachineState::capture(&cpu, &map.borrow());
+        let bytes = saved.to_bytes();
+
+        cpu.reset();
+        (*map.borrow_mut()).int_adapter.reset();
+        (*map.borrow_mut()).ram.fill(0);
+        assert_ne!(MachineState::capture(&cpu, &map.borrow()), saved);
+
+        MachineState::from_bytes(&bytes).unwrap().restore(&mut cpu, &mut map.borrow_mut());
+        assert_eq!(MachineState::capture(&cpu, &map.borrow()), saved);
+
+        // the queued keys came back too
+        let mut map = map.borrow_mut();
+        assert_eq!((map.read_byte(0x600f), map.read_byte(0x6002)), (KEYDOWN, 0x1e));
+        map.int_adapter.deliver_pending();
+        assert_eq!((map.read_byte(0x600f), map.read_byte(0x6002)), (KEYDOWN, 0x30));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(MachineState::from_bytes(b"{}").is_err());
+        assert!(MachineState::from_bytes(b"E65S\x02\x00{}").is_err());
+    }
+
+
+    #[test]
+    fn json_export_and_import_round_trip() {
+        let (mut cpu, map) = counting_machine();
+        cpu.run_until(|_| false, 30).unwrap();
+        (*map.borrow_mut()).int_adapter.write_ext(0x42, crate::interface_adapter::EXT_MUL_A);
+
+        // what export_state_json and import_state_json do with the emulator's machine
+        let saved = MachineState::capture(&cpu, &map.borrow());
+        let json  = serde_json::to_string_pretty(&saved).unwrap();
+        assert!(json.contains("\"ram\""));
+
+        let (mut other_cpu, other_map) = counting_machine();
+        let state: MachineState = serde_json::from_str(&json).unwrap();
+        state.restore(&mut other_cpu, &mut other_map.borrow_mut());
+
+        assert_eq!(MachineState::capture(&other_cpu, &other_map.borrow()), saved);
+        assert_eq!(other_map.borrow().ram, map.borrow().ram);
+    }
+
+
+    #[test]
+    fn rewinding_two_of_three_states_lands_on_the_second() {
+        let (mut cpu, map) = counting_machine();
+        let mut rewind = Rewind::new(1, 8);
+
+        let mut captured = Vec::new();
+        for _ in 0 .. 3 {
+            cpu.run_until(|_| false, 7).unwrap();
+            rewind.tick(&cpu, &map.borrow());
+            captured.push((cpu.pc, cpu.x));
+        }
+        cpu.run_until(|_| false, 7).unwrap();
+
+        assert!(rewind.step_back(&mut cpu, &mut map.borrow_mut()));
+        assert!(rewind.step_back(&mut cpu, &mut map.borrow_mut()));
+        assert_eq!((cpu.pc, cpu.x), captured[1]);
+    }
+
+    #[test]
+    fn rewind_keeps_only_the_newest_states() {
+        let (mut cpu, map) = counting_machine();
+        // a state every second frame, two at most
+        let mut rewind = Rewind::new(2, 2);
+
+        for _ in 0 .. 6 {
+            cpu.run_until(|_| false, 3).unwrap();
+            rewind.tick(&cpu, &map.borrow());
+        }
+
+        assert_eq!(rewind.states.len(), 2);
+        assert!(rewind.step_back(&mut cpu, &mut map.borrow_mut()));
+        assert!(rewind.step_back(&mut cpu, &mut map.borrow_mut()));
+        assert!(!rewind.step_back(&mut cpu, &mut map.borrow_mut()));
+    }
+}
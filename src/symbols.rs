@@ -0,0 +1,82 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+use crate::error::EmuError;
+use crate::parse_address;
+
+// labels by address, for annotating the debug panel
+#[derive(Default)]
+pub struct Symbols {
+    labels: BTreeMap<u16, String>
+}
+
+impl Symbols {
+    pub fn load(filename: &str) -> Result<Self, EmuError> {
+        return fs::read_to_string(filename)
+            .and_then(|text| Symbols::parse(&text))
+            .map_err(|err| EmuError::SymbolLoad(filename.to_string(), err));
+    }
+
+    // one `address = name` per line, addresses as on the command line.
+    // blank lines and lines starting with ; or # are skipped
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut labels = BTreeMap::new();
+
+        for (ix, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", ix + 1, msg));
+
+            let (address, name) = line.split_once('=')
+                .ok_or_else(|| invalid("expected ADDRESS = NAME".to_string()))?;
+            let (address, name) = (address.trim(), name.trim());
+
+            if name.is_empty() {
+                return Err(invalid("missing symbol name".to_string()));
+            }
+
+            labels.insert(parse_address(address).map_err(invalid)?, name.to_string());
+        }
+
+        return Ok(Symbols { labels });
+    }
+
+    // the closest label at or before the address, and how far past it the address is
+    pub fn lookup(&self, address: u16) -> Option<(&str, u16)> {
+        return self.labels.range(..= address).next_back()
+            .map(|(&start, name)| (name.as_str(), address - start));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = "; two labels\n$8000 = reset\n\n$8010 = irq\n";
+
+    #[test]
+    fn lookup_finds_the_nearest_preceding_label() {
+        let path = std::env::temp_dir().join(format!("emu6502-symbols-{}.sym", std::process::id()));
+        fs::write(&path, FILE).unwrap();
+        let symbols = Symbols::load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        let symbols = symbols.unwrap();
+
+        assert_eq!(symbols.lookup(0x8000), Some(("reset", 0)));
+        assert_eq!(symbols.lookup(0x800a), Some(("reset", 0x0a)));
+        assert_eq!(symbols.lookup(0x8010), Some(("irq", 0)));
+        assert_eq!(symbols.lookup(0x7fff), None);
+    }
+
+    #[test]
+    fn bad_lines_name_their_line_number() {
+        let err = Symbols::parse("$8000 = reset\nirq\n").err().unwrap();
+        assert!(err.to_string().starts_with("line 2:"));
+
+        assert!(Symbols::parse("$8000 =\n").is_err());
+    }
+}